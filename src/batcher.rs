@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::queue::QueueReceiver;
+
+/// When a batch is handed to the writer, whichever limit is hit first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchLimits {
+    pub max_count: usize,
+    pub max_bytes: usize,
+    /// how long the first message of a batch waits for more, zero takes only what has
+    /// already arrived
+    pub flush_interval: Duration,
+}
+
+/// Groups the messages of a queue into batches according to [BatchLimits]
+pub struct Batcher<T> {
+    rx: QueueReceiver<T>,
+    limits: BatchLimits,
+    size_of: fn(&T) -> usize,
+}
+
+impl<T> Batcher<T> {
+    pub fn new(rx: QueueReceiver<T>, limits: BatchLimits, size_of: fn(&T) -> usize) -> Self {
+        Self {
+            rx,
+            limits,
+            size_of,
+        }
+    }
+
+    /// Waits for a message and returns it with the ones that follow until a limit is hit.
+    /// `None` once the queue is closed and everything in it was returned.
+    pub async fn next_batch(&mut self) -> Option<Vec<T>> {
+        let first = self.rx.recv().await?;
+        let deadline = Instant::now() + self.limits.flush_interval;
+        let mut bytes = (self.size_of)(&first);
+        let mut batch = vec![first];
+
+        while batch.len() < self.limits.max_count && bytes < self.limits.max_bytes {
+            // messages that are already there are taken even if the deadline has passed
+            match tokio::time::timeout_at(deadline, self.rx.recv()).await {
+                Ok(Some(msg)) => {
+                    bytes += (self.size_of)(&msg);
+                    batch.push(msg);
+                }
+                // closed, the next call returns None
+                Ok(None) => break,
+                Err(_) => break,
+            }
+        }
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod next_batch {
+        use std::time::Duration;
+
+        use tokio::time::{sleep, Instant};
+
+        use crate::{
+            batcher::{BatchLimits, Batcher},
+            queue::{channel, OverflowPolicy, QueueSender},
+        };
+
+        const LIMITS: BatchLimits = BatchLimits {
+            max_count: 100,
+            max_bytes: 1000,
+            flush_interval: Duration::from_millis(100),
+        };
+
+        fn batcher(limits: BatchLimits) -> (QueueSender<String>, Batcher<String>) {
+            let (tx, rx) = channel(100, OverflowPolicy::Block);
+            (tx, Batcher::new(rx, limits, String::len))
+        }
+
+        async fn send_all(tx: &QueueSender<String>, msgs: &[&str]) {
+            for msg in msgs {
+                tx.send(msg.to_string()).await.unwrap();
+            }
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_count_limit() {
+            let (tx, mut batcher) = batcher(BatchLimits {
+                max_count: 3,
+                ..LIMITS
+            });
+            send_all(&tx, &["a", "b", "c", "d", "e"]).await;
+            drop(tx);
+
+            assert_eq!(batcher.next_batch().await.unwrap(), ["a", "b", "c"]);
+            assert_eq!(batcher.next_batch().await.unwrap(), ["d", "e"]);
+            assert_eq!(batcher.next_batch().await, None);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_byte_limit() {
+            let (tx, mut batcher) = batcher(BatchLimits {
+                max_bytes: 5,
+                ..LIMITS
+            });
+            send_all(&tx, &["aa", "bb", "cc", "dd"]).await;
+
+            // the message that crosses the limit is still part of the batch
+            assert_eq!(batcher.next_batch().await.unwrap(), ["aa", "bb", "cc"]);
+            let start = Instant::now();
+            assert_eq!(batcher.next_batch().await.unwrap(), ["dd"]);
+            assert_eq!(start.elapsed(), Duration::from_millis(100));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_waits_for_more_within_interval() {
+            let (tx, mut batcher) = batcher(LIMITS);
+            tokio::spawn(async move {
+                send_all(&tx, &["a"]).await;
+                sleep(Duration::from_millis(60)).await;
+                send_all(&tx, &["b"]).await;
+                sleep(Duration::from_millis(60)).await;
+                send_all(&tx, &["c"]).await;
+            });
+
+            let start = Instant::now();
+            assert_eq!(batcher.next_batch().await.unwrap(), ["a", "b"]);
+            assert_eq!(start.elapsed(), Duration::from_millis(100));
+            assert_eq!(batcher.next_batch().await.unwrap(), ["c"]);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_interval_bounds_a_steady_trickle() {
+            let (tx, mut batcher) = batcher(LIMITS);
+            tokio::spawn(async move {
+                for i in 0..10 {
+                    tx.send(i.to_string()).await.unwrap();
+                    sleep(Duration::from_millis(30)).await;
+                }
+            });
+
+            let start = Instant::now();
+            assert_eq!(batcher.next_batch().await.unwrap(), ["0", "1", "2", "3"]);
+            assert_eq!(start.elapsed(), Duration::from_millis(100));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_zero_interval_takes_what_is_there() {
+            let (tx, mut batcher) = batcher(BatchLimits {
+                flush_interval: Duration::ZERO,
+                ..LIMITS
+            });
+            send_all(&tx, &["a", "b"]).await;
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(10)).await;
+                send_all(&tx, &["c"]).await;
+            });
+
+            let start = Instant::now();
+            assert_eq!(batcher.next_batch().await.unwrap(), ["a", "b"]);
+            assert_eq!(start.elapsed(), Duration::ZERO);
+            assert_eq!(batcher.next_batch().await.unwrap(), ["c"]);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_closed_channel_flushes_partial_batch() {
+            let (tx, mut batcher) = batcher(LIMITS);
+            send_all(&tx, &["a"]).await;
+            drop(tx);
+
+            let start = Instant::now();
+            assert_eq!(batcher.next_batch().await.unwrap(), ["a"]);
+            assert_eq!(start.elapsed(), Duration::ZERO);
+            assert_eq!(batcher.next_batch().await, None);
+        }
+    }
+}
@@ -94,7 +94,7 @@ async fn do_main() -> anyhow::Result<()> {
         anyhow::Ok(())
     });
 
-    while let Ok(_) = eventloop.poll().await {}
+    while eventloop.poll().await.is_ok() {}
 
     Ok(())
 }
@@ -123,7 +123,7 @@ impl Pusher {
     }
 
     pub async fn exec_at_rate(&self, rate: u64) -> anyhow::Result<()> {
-        let nanoseconds = (1000_000_000.0 / rate as f64).floor() as u64;
+        let nanoseconds = (1_000_000_000.0 / rate as f64).floor() as u64;
         let interval = Duration::from_nanos(nanoseconds);
         println!("Duration {:?}", interval);
         let start_time = Instant::now();
@@ -1,38 +1,87 @@
-use sqlx::{postgres::PgPoolOptions, query::Query};
-use std::collections::{BTreeMap, HashMap};
+use bigdecimal::BigDecimal;
+use bytes::{BufMut, BytesMut};
+use itertools::Itertools;
+use serde::Deserialize;
+use sqlx::{
+    mysql::MySqlPoolOptions,
+    postgres::{PgArgumentBuffer, PgPoolOptions},
+    query::Query,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Row,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::Mutex,
+};
 
-use crate::utils::get_wildcard_string;
+use crate::{
+    dead_letter::DeadLetter,
+    parquet_driver::ParquetDriver,
+    utils::{
+        get_rows_per_statement, get_wildcard_string, sanitize_identifier, to_identifier,
+        with_hash_suffix, PreDefinedColumn,
+    },
+};
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Cell<Tz: chrono::TimeZone = chrono::Utc> {
     // have to think about this
     JsonObject(serde_json::Value),
     Number(i64),
+    Float(f64),
+    // integers beyond i64 and fractions a f64 can't represent exactly
+    Decimal(BigDecimal),
     String(String),
+    Uuid(uuid::Uuid),
+    Inet(std::net::IpAddr),
     Bool(bool),
     DateTime(chrono::NaiveDateTime),
     DateTimeTz(chrono::DateTime<Tz>),
     Null,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct DataRow {
     pub cells: BTreeMap<String, Cell>,
+    /// rows for the child tables of this row keyed by the field they came from,
+    /// see [MQTable::child]
+    pub children: BTreeMap<String, Vec<DataRow>>,
+    /// payload key of every cell whose column name differs from it
+    pub original_keys: BTreeMap<String, String>,
 }
 
-#[derive(Clone, Hash, PartialEq, Eq)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct MQTable {
     pub name: String,
 }
 
+/// Records which table each topic is written to, see [Manager::resolve_table](crate::manager::Manager::resolve_table)
+pub const TABLE_REGISTRY: &str = "_topic_tables";
+
+/// Holds the messages that could not be mapped, see [DeadLetter]
+pub const DEAD_LETTER_TABLE: &str = "_dead_letters";
+
 impl MQTable {
+    /// Deterministic table name for `topic`, distinct topics may still end up on the same
+    /// name, e.g. `a/b` and `a_b`
     pub fn from_topic(topic: &str) -> Self {
-        // clean the topic to make it a valid table name
-        let name = topic.replace("/", "_").replace("-", "_").replace(".", "_");
         MQTable {
-            name: name.to_string(),
+            name: to_identifier(topic),
+        }
+    }
+
+    /// Name for `topic` when the one from [Self::from_topic] is taken
+    pub fn from_topic_hashed(topic: &str) -> Self {
+        MQTable {
+            name: with_hash_suffix(&sanitize_identifier(topic), topic),
         }
     }
+
+    /// Table for the exploded array `field` of rows in this table
+    pub fn child(&self, field: &str) -> Self {
+        Self::from_topic(&format!("{}__{}", self.name, field))
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Default, Clone, Hash)]
@@ -40,16 +89,25 @@ pub enum Modifier {
     NotNull,
     PrimaryKey,
     Unique,
+    /// foreign key to the `pkey` of the named table
+    References(String),
     #[default]
     None,
 }
 
 impl Modifier {
-    fn to_db_string(&self) -> String {
+    fn to_db_string(&self, quote: fn(&str) -> String) -> String {
         match self {
             Modifier::NotNull => "NOT NULL".to_string(),
             Modifier::PrimaryKey => "PRIMARY KEY".to_string(),
             Modifier::Unique => "UNIQUE".to_string(),
+            Modifier::References(table) => {
+                format!(
+                    "REFERENCES {} ({})",
+                    quote(table),
+                    quote(&PreDefinedColumn::PKey.to_string())
+                )
+            }
             Modifier::None => "".to_string(),
         }
     }
@@ -88,6 +146,9 @@ pub struct MQTableColumnInfo {
     pub modifier: Modifier,
     #[sqlx(skip)]
     pub default_value: Option<DefaultValue>,
+    /// payload key the column name was normalized from, kept as a column comment
+    #[sqlx(skip)]
+    pub original_name: Option<String>,
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
@@ -97,7 +158,7 @@ pub struct MQTableInfo {
 
 impl MQTableInfo {
     pub fn exists(&self) -> bool {
-        self.columns.len() > 0
+        !self.columns.is_empty()
     }
 
     pub fn has_column(&self, column_name: &str) -> bool {
@@ -120,6 +181,126 @@ impl From<Vec<MQTableColumnInfo>> for MQTableInfo {
     }
 }
 
+/// Double quoted identifier, the syntax Postgres and SQLite share
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Single quoted string literal, for statements that take no bind parameters
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Lowercase type name without its `(...)` length, precision or scale, e.g. `varchar` for
+/// `VARCHAR(45)`, so column types read back from the database and types this crate created
+/// compare equal
+fn base_db_type(data_type: &str) -> String {
+    let mut base = String::with_capacity(data_type.len());
+    let mut depth = 0;
+    for c in data_type.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if depth == 0 => base.extend(c.to_lowercase()),
+            _ => {}
+        }
+    }
+    base.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// [base_db_type] with the aliases Postgres accepts replaced by the names it reports in
+/// `information_schema.columns`
+fn pg_base_db_type(data_type: &str) -> String {
+    let base = base_db_type(data_type);
+    match base.as_str() {
+        "timestamptz" => "timestamp with time zone",
+        "timestamp" => "timestamp without time zone",
+        "int8" => "bigint",
+        "int" | "int4" => "integer",
+        "int2" => "smallint",
+        "float8" => "double precision",
+        "float4" => "real",
+        "decimal" => "numeric",
+        _ => return base,
+    }
+    .to_string()
+}
+
+/// [base_db_type] with the aliases MySQL accepts replaced by the names it reports in
+/// `information_schema.columns`
+fn mysql_base_db_type(data_type: &str) -> String {
+    let base = base_db_type(data_type);
+    match base.as_str() {
+        "integer" => "int",
+        "numeric" => "decimal",
+        _ => return base,
+    }
+    .to_string()
+}
+
+impl DataRow {
+    pub fn columns(&self) -> Vec<String> {
+        self.cells.keys().cloned().collect()
+    }
+
+    pub fn has_children(&self) -> bool {
+        self.children.values().any(|rows| !rows.is_empty())
+    }
+
+    /// Copy of the row without children, written with the given `pkey`
+    fn with_pkey(&self, pkey: i64) -> DataRow {
+        let mut cells = self.cells.clone();
+        cells.insert(PreDefinedColumn::PKey.to_string(), Cell::Number(pkey));
+        DataRow {
+            cells,
+            ..Default::default()
+        }
+    }
+}
+
+/// Rows for the child tables of `table`, each pointing at the `pkey` its parent was written with
+pub(crate) fn child_rows<'a>(
+    table: &MQTable,
+    parents: impl IntoIterator<Item = (i64, &'a DataRow)>,
+) -> Vec<(MQTable, Vec<DataRow>)> {
+    let mut tables: BTreeMap<&str, Vec<DataRow>> = BTreeMap::new();
+    for (pkey, parent) in parents {
+        for (field, children) in &parent.children {
+            tables
+                .entry(field)
+                .or_default()
+                .extend(children.iter().map(|child| {
+                    let mut child = child.clone();
+                    child
+                        .cells
+                        .insert(PreDefinedColumn::ParentPkey.to_string(), Cell::Number(pkey));
+                    child
+                }));
+        }
+    }
+    tables
+        .into_iter()
+        .filter(|(_, rows)| !rows.is_empty())
+        .map(|(field, rows)| (table.child(field), rows))
+        .collect()
+}
+
+/// Splits a batch into groups of rows sharing the same column set, so each group can be
+/// bound positionally. Groups keep the order in which their column set first appears.
+fn group_by_columns<'a>(
+    items: impl IntoIterator<Item = &'a DataRow>,
+) -> Vec<(Vec<String>, Vec<&'a DataRow>)> {
+    let mut groups: Vec<(Vec<String>, Vec<&'a DataRow>)> = vec![];
+    for item in items {
+        let columns = item.columns();
+        match groups.iter_mut().find(|(c, _)| *c == columns) {
+            Some((_, rows)) => rows.push(item),
+            None => groups.push((columns, vec![item])),
+        }
+    }
+    groups
+}
+
 pub trait DBDriver {
     #[allow(async_fn_in_trait)]
     async fn connect(connection_string: &str) -> anyhow::Result<impl DBDriver>;
@@ -129,12 +310,35 @@ pub trait DBDriver {
     #[allow(async_fn_in_trait)]
     async fn insert_one(&self, item: DataRow, table: &MQTable) -> anyhow::Result<()>;
 
+    /// Writes `items` and their children in one transaction where the backend has them
     #[allow(async_fn_in_trait)]
     async fn insert_many(&self, items: &[DataRow], table: &MQTable) -> anyhow::Result<()>;
 
     #[allow(async_fn_in_trait)]
     async fn get_table_info(&self, table: &MQTable) -> anyhow::Result<MQTableInfo>;
 
+    /// Every `topic -> table` mapping recorded in [TABLE_REGISTRY]
+    #[allow(async_fn_in_trait)]
+    async fn get_table_registry(&self) -> anyhow::Result<HashMap<String, MQTable>>;
+
+    /// Records `topic -> table`, fails if either side is already recorded
+    #[allow(async_fn_in_trait)]
+    async fn register_table(&self, topic: &str, table: &MQTable) -> anyhow::Result<()>;
+
+    #[allow(async_fn_in_trait)]
+    async fn create_dead_letter_table(&self) -> anyhow::Result<()>;
+
+    /// Stores `letters` in [DEAD_LETTER_TABLE], their `id` is assigned by the backend
+    #[allow(async_fn_in_trait)]
+    async fn insert_dead_letters(&self, letters: &[DeadLetter]) -> anyhow::Result<()>;
+
+    /// Everything in [DEAD_LETTER_TABLE], oldest first
+    #[allow(async_fn_in_trait)]
+    async fn get_dead_letters(&self) -> anyhow::Result<Vec<DeadLetter>>;
+
+    #[allow(async_fn_in_trait)]
+    async fn delete_dead_letter(&self, id: i64) -> anyhow::Result<()>;
+
     #[allow(async_fn_in_trait)]
     async fn add_column_to_table(
         &self,
@@ -149,11 +353,198 @@ pub trait DBDriver {
         info: &MQTableInfo,
     ) -> anyhow::Result<()>;
 
+    #[allow(async_fn_in_trait)]
+    async fn alter_column_type(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()>;
+
     fn convert_to_db_type_string(&self, cell: &Cell) -> String;
+
+    /// Wider type for a column of `data_type` that has to hold `cell`, `None` if it already fits
+    fn widen_db_type(&self, data_type: &str, cell: &Cell) -> Option<String>;
+
+    /// Columns every table is created with before any payload columns are added
+    fn default_table_info(&self) -> MQTableInfo;
+}
+
+/// How [PostgresDriver] writes a batch of rows
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PgWriteMode {
+    /// Multi-row `INSERT ... VALUES`
+    Insert,
+    /// `COPY ... FROM STDIN` in binary format
+    Copy,
+    /// `COPY` once a batch reaches the copy threshold, `INSERT` below it
+    #[default]
+    Auto,
 }
+
 pub struct PostgresDriver {
     pool: sqlx::Pool<sqlx::Postgres>,
+    write_mode: PgWriteMode,
+    copy_threshold: usize,
+    // binary COPY needs the exact column types
+    column_types: Mutex<HashMap<MQTable, MQTableInfo>>,
+}
+
+impl PostgresDriver {
+    pub fn set_write_mode(&mut self, write_mode: PgWriteMode, copy_threshold: usize) {
+        self.write_mode = write_mode;
+        self.copy_threshold = copy_threshold;
+    }
+
+    fn uses_copy(&self, batch_len: usize) -> bool {
+        match self.write_mode {
+            PgWriteMode::Insert => false,
+            PgWriteMode::Copy => true,
+            PgWriteMode::Auto => batch_len >= self.copy_threshold,
+        }
+    }
+
+    async fn column_types(&self, table: &MQTable) -> anyhow::Result<MQTableInfo> {
+        if let Some(info) = self.column_types.lock().unwrap().get(table) {
+            return Ok(info.clone());
+        }
+        let info = self.get_table_info(table).await?;
+        self.column_types
+            .lock()
+            .unwrap()
+            .insert(table.clone(), info.clone());
+        Ok(info)
+    }
+
+    /// Unlike the `INSERT` path there is no `ON CONFLICT DO NOTHING`, a conflicting row fails
+    /// the whole batch. Only `pkey` is unique and it is always generated, so that never happens
+    /// for tables created by the [Manager](crate::manager::Manager).
+    async fn copy_rows(
+        &self,
+        conn: &mut sqlx::PgConnection,
+        table: &MQTable,
+        columns: &[String],
+        rows: &[&DataRow],
+    ) -> anyhow::Result<()> {
+        let info = self.column_types(table).await?;
+
+        let types = columns
+            .iter()
+            .map(|c| {
+                info.columns
+                    .get(c)
+                    .map(|i| i.data_type.as_str())
+                    .ok_or_else(|| anyhow::anyhow!("Column {} missing in table {}", c, table.name))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let statement = format!(
+            "COPY {} ({}) FROM STDIN (FORMAT BINARY)",
+            quote_identifier(&table.name),
+            columns.iter().map(|c| quote_identifier(c)).join(", ")
+        );
+
+        let mut copy = conn.copy_in_raw(&statement).await?;
+
+        let mut buffer = BytesMut::new();
+        put_copy_header(&mut buffer);
+        for item in rows {
+            let encoded = put_copy_row(&mut buffer, columns, &types, item);
+            let sent = match encoded {
+                Ok(()) if buffer.len() >= COPY_CHUNK_SIZE => copy
+                    .send(buffer.split())
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.into()),
+                other => other,
+            };
+            if let Err(e) = sent {
+                copy.abort(e.to_string()).await?;
+                return Err(e);
+            }
+        }
+        put_copy_trailer(&mut buffer);
+        copy.send(buffer).await?;
+        copy.finish().await?;
+
+        Ok(())
+    }
+
+    /// Draws `count` values from the sequence behind the `pkey` of `table`
+    async fn allocate_pkeys(
+        &self,
+        conn: &mut sqlx::PgConnection,
+        table: &MQTable,
+        count: usize,
+    ) -> anyhow::Result<Vec<i64>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        sqlx::query_scalar::<_, i64>(
+            "SELECT nextval(pg_get_serial_sequence($1, $2)) FROM generate_series(1, $3)",
+        )
+        .bind(table.name.as_str())
+        .bind(PreDefinedColumn::PKey.to_string())
+        .bind(count as i64)
+        .fetch_all(conn)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    async fn write_rows<'a>(
+        &self,
+        conn: &mut sqlx::PgConnection,
+        table: &MQTable,
+        rows: impl IntoIterator<Item = &'a DataRow>,
+        use_copy: bool,
+    ) -> anyhow::Result<()> {
+        for (columns, rows) in group_by_columns(rows) {
+            if use_copy {
+                self.copy_rows(conn, table, &columns, &rows).await?;
+            } else {
+                self.insert_rows(conn, table, &columns, &rows).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn insert_rows(
+        &self,
+        conn: &mut sqlx::PgConnection,
+        table: &MQTable,
+        columns: &[String],
+        rows: &[&DataRow],
+    ) -> anyhow::Result<()> {
+        // wide batches would go past the bind parameter limit of a single statement
+        let chunk_size = get_rows_per_statement(columns.len(), PG_MAX_BIND_PARAMS);
+
+        for chunk in rows.chunks(chunk_size) {
+            let placeholder_string = get_wildcard_string(columns.len(), chunk.len());
+
+            let query_string = format!(
+                "INSERT INTO {} ({}) VALUES {} ON CONFLICT DO NOTHING",
+                quote_identifier(&table.name),
+                columns.iter().map(|c| quote_identifier(c)).join(", "),
+                placeholder_string
+            );
+
+            let mut intermediate_query: Query<'_, _, _> = sqlx::query(&query_string);
+
+            for item in chunk {
+                for cell in item.cells.values() {
+                    intermediate_query = bind_to_query(intermediate_query, cell);
+                }
+            }
+
+            intermediate_query.execute(&mut *conn).await?;
+        }
+
+        Ok(())
+    }
 }
+
 impl DBDriver for PostgresDriver {
     #[allow(refining_impl_trait)]
     async fn connect(connection_string: &str) -> anyhow::Result<PostgresDriver> {
@@ -163,7 +554,12 @@ impl DBDriver for PostgresDriver {
             .connect(connection_string)
             .await?;
         println!("Connected to Postgres with {}", connection_string);
-        anyhow::Ok(PostgresDriver { pool })
+        anyhow::Ok(PostgresDriver {
+            pool,
+            write_mode: PgWriteMode::default(),
+            copy_threshold: DEFAULT_COPY_THRESHOLD,
+            column_types: Mutex::new(HashMap::new()),
+        })
     }
 
     async fn execute_query(&self, _: &str) -> anyhow::Result<String> {
@@ -179,26 +575,33 @@ impl DBDriver for PostgresDriver {
             return Ok(());
         }
 
-        let columns: Vec<_> = items[0].cells.keys().cloned().collect();
+        let use_copy = self.uses_copy(items.len());
 
-        let placeholder_string = get_wildcard_string(columns.len(), items.len());
+        let mut transaction = self.pool.begin().await?;
 
-        let query_string = format!(
-            "INSERT INTO {} ({}) VALUES {} ON CONFLICT DO NOTHING",
-            table.name,
-            columns.join(", "),
-            placeholder_string
-        );
+        // parents get their pkey up front so their children can point at it, COPY can't
+        // hand generated keys back
+        let parents: Vec<&DataRow> = items.iter().filter(|r| r.has_children()).collect();
+        let pkeys = self
+            .allocate_pkeys(&mut transaction, table, parents.len())
+            .await?;
+        let keyed: Vec<DataRow> = parents
+            .iter()
+            .zip(&pkeys)
+            .map(|(row, pkey)| row.with_pkey(*pkey))
+            .collect();
 
-        let mut intermediate_query: Query<'_, _, _> = sqlx::query(&query_string);
+        let rows = items.iter().filter(|r| !r.has_children()).chain(&keyed);
+        self.write_rows(&mut transaction, table, rows, use_copy)
+            .await?;
 
-        for item in items {
-            for cell in item.cells.values() {
-                intermediate_query = bind_to_query(intermediate_query, cell);
-            }
+        for (child, rows) in child_rows(table, pkeys.into_iter().zip(parents)) {
+            let use_copy = self.uses_copy(rows.len());
+            self.write_rows(&mut transaction, &child, &rows, use_copy)
+                .await?;
         }
 
-        intermediate_query.execute(&self.pool).await?;
+        transaction.commit().await?;
 
         Ok(())
     }
@@ -214,14 +617,141 @@ impl DBDriver for PostgresDriver {
         .map_err(|e| e.into())
     }
 
+    async fn get_table_registry(&self) -> anyhow::Result<HashMap<String, MQTable>> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} \
+             (topic TEXT PRIMARY KEY, table_name TEXT NOT NULL UNIQUE)",
+            quote_identifier(TABLE_REGISTRY)
+        ))
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query_as::<_, (String, String)>(&format!(
+            "SELECT topic, table_name FROM {}",
+            quote_identifier(TABLE_REGISTRY)
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|(topic, name)| (topic, MQTable { name }))
+                .collect()
+        })
+        .map_err(|e| e.into())
+    }
+
+    async fn register_table(&self, topic: &str, table: &MQTable) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (topic, table_name) VALUES ($1, $2)",
+            quote_identifier(TABLE_REGISTRY)
+        ))
+        .bind(topic)
+        .bind(table.name.as_str())
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+
+    async fn create_dead_letter_table(&self) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} \
+             (id BIGSERIAL PRIMARY KEY, topic TEXT NOT NULL, payload BYTEA NOT NULL, \
+             error_kind TEXT NOT NULL, error_message TEXT NOT NULL, \
+             received_ts TIMESTAMPTZ NOT NULL)",
+            quote_identifier(DEAD_LETTER_TABLE)
+        ))
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+
+    async fn insert_dead_letters(&self, letters: &[DeadLetter]) -> anyhow::Result<()> {
+        let query_string = format!(
+            "INSERT INTO {} (topic, payload, error_kind, error_message, received_ts) \
+             VALUES ($1, $2, $3, $4, $5)",
+            quote_identifier(DEAD_LETTER_TABLE)
+        );
+        let mut transaction = self.pool.begin().await?;
+        for letter in letters {
+            sqlx::query(&query_string)
+                .bind(letter.topic.as_str())
+                .bind(letter.payload.as_slice())
+                .bind(letter.error_kind.as_str())
+                .bind(letter.error_message.as_str())
+                .bind(letter.received_ts)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_dead_letters(&self) -> anyhow::Result<Vec<DeadLetter>> {
+        sqlx::query_as::<_, DeadLetter>(&format!(
+            "SELECT id, topic, payload, error_kind, error_message, received_ts \
+             FROM {} ORDER BY id",
+            quote_identifier(DEAD_LETTER_TABLE)
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    async fn delete_dead_letter(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE id = $1",
+            quote_identifier(DEAD_LETTER_TABLE)
+        ))
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+
     async fn add_column_to_table(
         &self,
         table: &MQTable,
         column: &MQTableColumnInfo,
     ) -> anyhow::Result<()> {
+        self.column_types.lock().unwrap().remove(table);
+
         let query_string = format!(
             "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}",
-            table.name, column.column_name, column.data_type
+            quote_identifier(&table.name),
+            quote_identifier(&column.column_name),
+            column.data_type
+        );
+
+        sqlx::query(&query_string).execute(&self.pool).await?;
+
+        if let Some(original_name) = &column.original_name {
+            let comment = format!(
+                "COMMENT ON COLUMN {}.{} IS {}",
+                quote_identifier(&table.name),
+                quote_identifier(&column.column_name),
+                quote_literal(original_name)
+            );
+            sqlx::query(&comment).execute(&self.pool).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn alter_column_type(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()> {
+        self.column_types.lock().unwrap().remove(table);
+
+        let query_string = format!(
+            "ALTER TABLE {} ALTER COLUMN {} TYPE {}",
+            quote_identifier(&table.name),
+            quote_identifier(&column.column_name),
+            column.data_type
         );
 
         sqlx::query(&query_string)
@@ -242,9 +772,9 @@ impl DBDriver for PostgresDriver {
             .map(|col| {
                 format!(
                     "{} {} {} {}",
-                    col.column_name,
+                    quote_identifier(&col.column_name),
                     col.data_type,
-                    col.modifier.to_db_string(),
+                    col.modifier.to_db_string(quote_identifier),
                     col.default_value
                         .as_ref()
                         .map(|f| f.to_db_string())
@@ -254,7 +784,10 @@ impl DBDriver for PostgresDriver {
             .collect::<Vec<_>>()
             .join(", ");
 
-        let query_string = format!("CREATE TABLE IF NOT EXISTS {} ({col_string})", table.name);
+        let query_string = format!(
+            "CREATE TABLE IF NOT EXISTS {} ({col_string})",
+            quote_identifier(&table.name)
+        );
 
         sqlx::query(&query_string)
             .execute(&self.pool)
@@ -266,7 +799,11 @@ impl DBDriver for PostgresDriver {
     fn convert_to_db_type_string(&self, cell: &Cell) -> String {
         match cell {
             Cell::Number(_) => "BIGINT".to_string(),
+            Cell::Float(_) => "DOUBLE PRECISION".to_string(),
+            Cell::Decimal(_) => "NUMERIC".to_string(),
             Cell::String(_) => "TEXT".to_string(),
+            Cell::Uuid(_) => "UUID".to_string(),
+            Cell::Inet(_) => "INET".to_string(),
             Cell::Bool(_) => "BOOLEAN".to_string(),
             Cell::Null => "TEXT".to_string(), // Default to TEXT for NULLs
             Cell::JsonObject(_) => "JSONB".to_string(),
@@ -274,6 +811,55 @@ impl DBDriver for PostgresDriver {
             Cell::DateTimeTz(_) => "TIMESTAMPTZ".to_string(),
         }
     }
+
+    fn widen_db_type(&self, data_type: &str, cell: &Cell) -> Option<String> {
+        match (pg_base_db_type(data_type).as_str(), cell) {
+            ("bigint" | "integer" | "smallint", Cell::Float(_)) => {
+                Some("DOUBLE PRECISION".to_string())
+            }
+            ("bigint" | "integer" | "smallint" | "double precision" | "real", Cell::Decimal(_)) => {
+                Some("NUMERIC".to_string())
+            }
+            // an inferred column getting a value that didn't pass inference
+            (
+                "uuid"
+                | "inet"
+                | "numeric"
+                | "timestamp with time zone"
+                | "timestamp without time zone",
+                Cell::String(_),
+            ) => Some("TEXT".to_string()),
+            _ => None,
+        }
+    }
+
+    fn default_table_info(&self) -> MQTableInfo {
+        vec![
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::PKey.to_string(),
+                data_type: "SERIAL".to_string(),
+                modifier: Modifier::PrimaryKey,
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::Raw.to_string(),
+                data_type: "TEXT".to_string(),
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::InsertTs.to_string(),
+                data_type: "TIMESTAMP".to_string(),
+                default_value: Some("CURRENT_TIMESTAMP AT TIME ZONE 'UTC'".into()),
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::ReceivedTs.to_string(),
+                data_type: "TIMESTAMP".to_string(),
+                ..Default::default()
+            },
+        ]
+        .into()
+    }
 }
 
 fn bind_to_query<'a>(
@@ -284,6 +870,18 @@ fn bind_to_query<'a>(
         Cell::Number(n) => {
             intermediate_query = intermediate_query.bind(n);
         }
+        Cell::Float(f) => {
+            intermediate_query = intermediate_query.bind(f);
+        }
+        Cell::Decimal(d) => {
+            intermediate_query = intermediate_query.bind(d);
+        }
+        Cell::Uuid(u) => {
+            intermediate_query = intermediate_query.bind(u);
+        }
+        Cell::Inet(ip) => {
+            intermediate_query = intermediate_query.bind(ip);
+        }
         Cell::String(s) => {
             intermediate_query = intermediate_query.bind(s);
         }
@@ -303,5 +901,3020 @@ fn bind_to_query<'a>(
             intermediate_query = intermediate_query.bind(dt);
         }
     }
-    return intermediate_query;
+    intermediate_query
+}
+
+pub const DEFAULT_COPY_THRESHOLD: usize = 500;
+
+/// Postgres' limit on bind parameters in one statement
+const PG_MAX_BIND_PARAMS: usize = 65535;
+
+/// `SQLITE_MAX_VARIABLE_NUMBER` of the bundled sqlite
+const SQLITE_MAX_BIND_PARAMS: usize = 32766;
+
+/// Placeholders the MySQL protocol can address in one prepared statement
+const MYSQL_MAX_BIND_PARAMS: usize = 65535;
+
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+// postgres binary timestamps count microseconds from 2000-01-01
+const PG_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+fn put_copy_header(buffer: &mut BytesMut) {
+    buffer.put_slice(b"PGCOPY\n\xff\r\n\0");
+    // flags
+    buffer.put_i32(0);
+    // header extension length
+    buffer.put_i32(0);
+}
+
+fn put_copy_trailer(buffer: &mut BytesMut) {
+    buffer.put_i16(-1);
+}
+
+/// Encodes one row in COPY binary format, `types` are the information_schema data types
+/// of `columns`. Values are encoded for the column type, the same way postgres would
+/// assignment cast them on `INSERT`.
+fn put_copy_row(
+    buffer: &mut BytesMut,
+    columns: &[String],
+    types: &[&str],
+    row: &DataRow,
+) -> anyhow::Result<()> {
+    buffer.put_i16(columns.len().try_into()?);
+    for (column, data_type) in columns.iter().zip(types) {
+        let cell = row.cells.get(column).unwrap_or(&Cell::Null);
+        if let Cell::Null = cell {
+            buffer.put_i32(-1);
+            continue;
+        }
+
+        let mismatch = || {
+            anyhow::anyhow!(
+                "Cannot copy {:?} into {} column {}",
+                cell,
+                data_type,
+                column
+            )
+        };
+
+        let value: Vec<u8> = match (*data_type, cell) {
+            ("bigint", Cell::Number(n)) => n.to_be_bytes().to_vec(),
+            ("double precision", Cell::Float(f)) => f.to_be_bytes().to_vec(),
+            ("double precision", Cell::Number(n)) => (*n as f64).to_be_bytes().to_vec(),
+            ("numeric", Cell::Decimal(_) | Cell::Float(_) | Cell::Number(_)) => {
+                let decimal = match cell {
+                    Cell::Decimal(d) => d.clone(),
+                    Cell::Float(f) => BigDecimal::from_str(&f.to_string())?,
+                    _ => BigDecimal::from_str(&copy_text(cell))?,
+                };
+                // numeric's binary format is involved, let sqlx encode it
+                let mut buffer = PgArgumentBuffer::default();
+                // a decimal is never NULL
+                let _ = sqlx::Encode::<sqlx::Postgres>::encode_by_ref(&decimal, &mut buffer)
+                    .map_err(|e| anyhow::anyhow!(e))?;
+                buffer.to_vec()
+            }
+            ("integer", Cell::Number(n)) => i32::try_from(*n)?.to_be_bytes().to_vec(),
+            ("boolean", Cell::Bool(b)) => vec![*b as u8],
+            ("uuid", Cell::Uuid(u)) => u.as_bytes().to_vec(),
+            ("inet", Cell::Inet(ip)) => {
+                let mut buffer = PgArgumentBuffer::default();
+                // an address is never NULL
+                let _ = sqlx::Encode::<sqlx::Postgres>::encode_by_ref(ip, &mut buffer)
+                    .map_err(|e| anyhow::anyhow!(e))?;
+                buffer.to_vec()
+            }
+            ("text" | "character varying", _) => copy_text(cell).into_bytes(),
+            ("json", _) => jsonb_text(&copy_json(cell)).into_bytes(),
+            ("jsonb", _) => {
+                // jsonb binary format version
+                let mut value = vec![1];
+                value.extend(copy_json(cell).to_string().into_bytes());
+                value
+            }
+            ("timestamp without time zone", Cell::DateTime(dt)) => {
+                (dt.and_utc().timestamp_micros() - PG_EPOCH_MICROS)
+                    .to_be_bytes()
+                    .to_vec()
+            }
+            ("timestamp without time zone" | "timestamp with time zone", Cell::DateTimeTz(dt)) => {
+                (dt.timestamp_micros() - PG_EPOCH_MICROS)
+                    .to_be_bytes()
+                    .to_vec()
+            }
+            ("timestamp with time zone", Cell::DateTime(dt)) => (dt.and_utc().timestamp_micros()
+                - PG_EPOCH_MICROS)
+                .to_be_bytes()
+                .to_vec(),
+            _ => return Err(mismatch()),
+        };
+        buffer.put_i32(value.len().try_into()?);
+        buffer.put_slice(&value);
+    }
+    Ok(())
+}
+
+fn copy_text(cell: &Cell) -> String {
+    match cell {
+        Cell::String(s) => s.clone(),
+        Cell::JsonObject(v) => jsonb_text(v),
+        Cell::Number(n) => n.to_string(),
+        Cell::Float(f) => f.to_string(),
+        Cell::Decimal(d) => d.to_string(),
+        Cell::Uuid(u) => u.to_string(),
+        // inet's cast to text keeps the netmask
+        Cell::Inet(ip @ std::net::IpAddr::V4(_)) => format!("{}/32", ip),
+        Cell::Inet(ip @ std::net::IpAddr::V6(_)) => format!("{}/128", ip),
+        Cell::Bool(b) => b.to_string(),
+        Cell::DateTime(dt) => pg_timestamp_text(dt),
+        Cell::DateTimeTz(dt) => format!("{}+00", pg_timestamp_text(&dt.naive_utc())),
+        Cell::Null => String::new(),
+    }
+}
+
+/// Renders a timestamp the way postgres prints one in ISO style, the `INSERT` path stores
+/// that text when a timestamp lands in a text column. Sessions are in UTC, fractional
+/// seconds are printed without trailing zeros.
+fn pg_timestamp_text(dt: &chrono::NaiveDateTime) -> String {
+    let mut text = dt.format("%Y-%m-%d %H:%M:%S").to_string();
+    let micros = dt.and_utc().timestamp_subsec_micros();
+    if micros > 0 {
+        text.push_str(format!(".{:06}", micros).trim_end_matches('0'));
+    }
+    text
+}
+
+/// Renders json the way postgres prints a jsonb value, which is what the `INSERT` path
+/// stores when a json cell lands in a text column
+fn jsonb_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Array(items) => {
+            format!("[{}]", items.iter().map(jsonb_text).join(", "))
+        }
+        serde_json::Value::Object(map) => {
+            // jsonb orders keys by length first, then bytewise
+            let entries = map
+                .iter()
+                .sorted_by(|a, b| a.0.len().cmp(&b.0.len()).then_with(|| a.0.cmp(b.0)))
+                .map(|(k, v)| format!("{}: {}", serde_json::Value::from(k.as_str()), jsonb_text(v)))
+                .join(", ");
+            format!("{{{}}}", entries)
+        }
+        scalar => scalar.to_string(),
+    }
+}
+
+fn copy_json(cell: &Cell) -> serde_json::Value {
+    match cell {
+        Cell::JsonObject(v) => v.clone(),
+        Cell::String(s) => serde_json::Value::String(s.clone()),
+        Cell::Number(n) => (*n).into(),
+        Cell::Float(_) | Cell::Decimal(_) => {
+            serde_json::from_str(&copy_text(cell)).unwrap_or(serde_json::Value::Null)
+        }
+        Cell::Bool(b) => (*b).into(),
+        Cell::Uuid(u) => serde_json::Value::String(u.to_string()),
+        Cell::Inet(ip) => serde_json::Value::String(ip.to_string()),
+        Cell::DateTime(dt) => serde_json::Value::String(dt.to_string()),
+        Cell::DateTimeTz(dt) => serde_json::Value::String(dt.to_rfc3339()),
+        Cell::Null => serde_json::Value::Null,
+    }
+}
+
+pub struct SqliteDriver {
+    pool: sqlx::Pool<sqlx::Sqlite>,
+}
+
+impl SqliteDriver {
+    async fn insert_rows<'a>(
+        &self,
+        conn: &mut sqlx::SqliteConnection,
+        table: &MQTable,
+        rows: impl IntoIterator<Item = &'a DataRow>,
+    ) -> anyhow::Result<()> {
+        for (columns, rows) in group_by_columns(rows) {
+            let chunk_size = get_rows_per_statement(columns.len(), SQLITE_MAX_BIND_PARAMS);
+
+            for chunk in rows.chunks(chunk_size) {
+                let placeholder_string = get_wildcard_string(columns.len(), chunk.len());
+
+                let query_string = format!(
+                    "INSERT OR IGNORE INTO {} ({}) VALUES {}",
+                    quote_identifier(&table.name),
+                    columns.iter().map(|c| quote_identifier(c)).join(", "),
+                    placeholder_string
+                );
+
+                let mut intermediate_query: Query<'_, _, _> = sqlx::query(&query_string);
+
+                for item in chunk {
+                    for cell in item.cells.values() {
+                        intermediate_query = bind_to_sqlite_query(intermediate_query, cell);
+                    }
+                }
+
+                intermediate_query.execute(&mut *conn).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `None` when the row was ignored, its children are dropped with it
+    async fn insert_returning_pkey(
+        &self,
+        conn: &mut sqlx::SqliteConnection,
+        table: &MQTable,
+        row: &DataRow,
+    ) -> anyhow::Result<Option<i64>> {
+        let columns = row.columns();
+        let query_string = format!(
+            "INSERT OR IGNORE INTO {} ({}) VALUES {} RETURNING {}",
+            quote_identifier(&table.name),
+            columns.iter().map(|c| quote_identifier(c)).join(", "),
+            get_wildcard_string(columns.len(), 1),
+            quote_identifier(&PreDefinedColumn::PKey.to_string())
+        );
+
+        let mut intermediate_query: Query<'_, _, _> = sqlx::query(&query_string);
+        for cell in row.cells.values() {
+            intermediate_query = bind_to_sqlite_query(intermediate_query, cell);
+        }
+
+        Ok(intermediate_query
+            .fetch_optional(&mut *conn)
+            .await?
+            .map(|r| r.get::<i64, _>(0)))
+    }
+}
+
+impl DBDriver for SqliteDriver {
+    #[allow(refining_impl_trait)]
+    async fn connect(connection_string: &str) -> anyhow::Result<SqliteDriver> {
+        let options = SqliteConnectOptions::from_str(connection_string)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await?;
+        println!("Connected to Sqlite with {}", connection_string);
+        anyhow::Ok(SqliteDriver { pool })
+    }
+
+    async fn execute_query(&self, query: &str) -> anyhow::Result<String> {
+        let result = sqlx::query(query).execute(&self.pool).await?;
+        Ok(result.rows_affected().to_string())
+    }
+
+    async fn insert_one(&self, row: DataRow, table: &MQTable) -> anyhow::Result<()> {
+        self.insert_many(&[row], table).await
+    }
+
+    async fn insert_many(&self, items: &[DataRow], table: &MQTable) -> anyhow::Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let mut transaction = self.pool.begin().await?;
+
+        self.insert_rows(
+            &mut transaction,
+            table,
+            items.iter().filter(|r| !r.has_children()),
+        )
+        .await?;
+
+        let mut parents = vec![];
+        for row in items.iter().filter(|r| r.has_children()) {
+            if let Some(pkey) = self
+                .insert_returning_pkey(&mut transaction, table, row)
+                .await?
+            {
+                parents.push((pkey, row));
+            }
+        }
+
+        for (child, rows) in child_rows(table, parents) {
+            self.insert_rows(&mut transaction, &child, &rows).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_table_info(&self, table: &MQTable) -> anyhow::Result<MQTableInfo> {
+        sqlx::query_as::<_, MQTableColumnInfo>(
+            "SELECT name AS column_name, type AS data_type FROM pragma_table_info($1)",
+        )
+        .bind(table.name.as_str())
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| rows.into())
+        .map_err(|e| e.into())
+    }
+
+    async fn get_table_registry(&self) -> anyhow::Result<HashMap<String, MQTable>> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} \
+             (topic TEXT PRIMARY KEY, table_name TEXT NOT NULL UNIQUE)",
+            quote_identifier(TABLE_REGISTRY)
+        ))
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query_as::<_, (String, String)>(&format!(
+            "SELECT topic, table_name FROM {}",
+            quote_identifier(TABLE_REGISTRY)
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|(topic, name)| (topic, MQTable { name }))
+                .collect()
+        })
+        .map_err(|e| e.into())
+    }
+
+    async fn register_table(&self, topic: &str, table: &MQTable) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (topic, table_name) VALUES ($1, $2)",
+            quote_identifier(TABLE_REGISTRY)
+        ))
+        .bind(topic)
+        .bind(table.name.as_str())
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+
+    async fn create_dead_letter_table(&self) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} \
+             (id INTEGER PRIMARY KEY AUTOINCREMENT, topic TEXT NOT NULL, payload BLOB NOT NULL, \
+             error_kind TEXT NOT NULL, error_message TEXT NOT NULL, received_ts TEXT NOT NULL)",
+            quote_identifier(DEAD_LETTER_TABLE)
+        ))
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+
+    async fn insert_dead_letters(&self, letters: &[DeadLetter]) -> anyhow::Result<()> {
+        let query_string = format!(
+            "INSERT INTO {} (topic, payload, error_kind, error_message, received_ts) \
+             VALUES ($1, $2, $3, $4, $5)",
+            quote_identifier(DEAD_LETTER_TABLE)
+        );
+        let mut transaction = self.pool.begin().await?;
+        for letter in letters {
+            sqlx::query(&query_string)
+                .bind(letter.topic.as_str())
+                .bind(letter.payload.as_slice())
+                .bind(letter.error_kind.as_str())
+                .bind(letter.error_message.as_str())
+                .bind(letter.received_ts)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_dead_letters(&self) -> anyhow::Result<Vec<DeadLetter>> {
+        sqlx::query_as::<_, DeadLetter>(&format!(
+            "SELECT id, topic, payload, error_kind, error_message, received_ts \
+             FROM {} ORDER BY id",
+            quote_identifier(DEAD_LETTER_TABLE)
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    async fn delete_dead_letter(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE id = $1",
+            quote_identifier(DEAD_LETTER_TABLE)
+        ))
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+
+    async fn add_column_to_table(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()> {
+        // sqlite has no ADD COLUMN IF NOT EXISTS
+        if self
+            .get_table_info(table)
+            .await?
+            .has_column(&column.column_name)
+        {
+            return Ok(());
+        }
+
+        // sqlite has no column comments, the original key of a normalized column is not kept
+        let query_string = format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            quote_identifier(&table.name),
+            quote_identifier(&column.column_name),
+            column.data_type
+        );
+
+        sqlx::query(&query_string)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    async fn alter_column_type(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!(
+            "Sqlite cannot change the type of column {} in {}",
+            column.column_name,
+            table.name
+        )
+    }
+
+    async fn create_table_if_not_exists(
+        &self,
+        table: &MQTable,
+        info: &MQTableInfo,
+    ) -> anyhow::Result<()> {
+        let col_string = info
+            .columns()
+            .iter()
+            .map(|col| {
+                format!(
+                    "{} {} {} {}",
+                    quote_identifier(&col.column_name),
+                    col.data_type,
+                    col.modifier.to_db_string(quote_identifier),
+                    col.default_value
+                        .as_ref()
+                        .map(|f| f.to_db_string())
+                        .unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let query_string = format!(
+            "CREATE TABLE IF NOT EXISTS {} ({col_string})",
+            quote_identifier(&table.name)
+        );
+
+        sqlx::query(&query_string)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    fn convert_to_db_type_string(&self, cell: &Cell) -> String {
+        match cell {
+            Cell::Number(_) => "INTEGER".to_string(),
+            Cell::Float(_) => "REAL".to_string(),
+            // NUMERIC affinity would turn digits beyond i64 into a lossy REAL
+            Cell::Decimal(_) => "TEXT".to_string(),
+            Cell::String(_) => "TEXT".to_string(),
+            Cell::Uuid(_) => "TEXT".to_string(),
+            Cell::Inet(_) => "TEXT".to_string(),
+            Cell::Bool(_) => "BOOLEAN".to_string(),
+            Cell::Null => "TEXT".to_string(), // Default to TEXT for NULLs
+            Cell::JsonObject(_) => "JSON".to_string(),
+            Cell::DateTime(_) => "DATETIME".to_string(),
+            Cell::DateTimeTz(_) => "DATETIME".to_string(),
+        }
+    }
+
+    fn widen_db_type(&self, _: &str, _: &Cell) -> Option<String> {
+        // sqlite columns only have an affinity, any column can store any value
+        None
+    }
+
+    fn default_table_info(&self) -> MQTableInfo {
+        vec![
+            MQTableColumnInfo {
+                // INTEGER PRIMARY KEY aliases the rowid, which autoincrements
+                column_name: PreDefinedColumn::PKey.to_string(),
+                data_type: "INTEGER".to_string(),
+                modifier: Modifier::PrimaryKey,
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::Raw.to_string(),
+                data_type: "TEXT".to_string(),
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::InsertTs.to_string(),
+                data_type: "DATETIME".to_string(),
+                default_value: Some("CURRENT_TIMESTAMP".into()),
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::ReceivedTs.to_string(),
+                data_type: "DATETIME".to_string(),
+                ..Default::default()
+            },
+        ]
+        .into()
+    }
+}
+
+fn bind_to_sqlite_query<'a>(
+    mut intermediate_query: Query<'a, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'a>>,
+    cell: &'a Cell,
+) -> Query<'a, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'a>> {
+    match cell {
+        Cell::Number(n) => {
+            intermediate_query = intermediate_query.bind(n);
+        }
+        Cell::Float(f) => {
+            intermediate_query = intermediate_query.bind(f);
+        }
+        Cell::Decimal(d) => {
+            // sqlx has no sqlite decimal type
+            intermediate_query = intermediate_query.bind(d.to_string());
+        }
+        Cell::Uuid(u) => {
+            // sqlx encodes uuids as 16 byte blobs here
+            intermediate_query = intermediate_query.bind(u.hyphenated().to_string());
+        }
+        Cell::Inet(ip) => {
+            intermediate_query = intermediate_query.bind(ip.to_string());
+        }
+        Cell::String(s) => {
+            intermediate_query = intermediate_query.bind(s);
+        }
+        Cell::Bool(b) => {
+            intermediate_query = intermediate_query.bind(b);
+        }
+        Cell::Null => {
+            intermediate_query = intermediate_query.bind(None::<String>);
+        }
+        Cell::JsonObject(obj) => {
+            intermediate_query = intermediate_query.bind(obj);
+        }
+        Cell::DateTime(dt) => {
+            intermediate_query = intermediate_query.bind(dt);
+        }
+        Cell::DateTimeTz(dt) => {
+            intermediate_query = intermediate_query.bind(dt);
+        }
+    }
+    intermediate_query
+}
+
+pub struct MySqlDriver {
+    pool: sqlx::Pool<sqlx::MySql>,
+}
+
+impl MySqlDriver {
+    async fn insert_rows<'a>(
+        &self,
+        conn: &mut sqlx::MySqlConnection,
+        table: &MQTable,
+        rows: impl IntoIterator<Item = &'a DataRow>,
+    ) -> anyhow::Result<()> {
+        for (columns, rows) in group_by_columns(rows) {
+            let columns: Vec<_> = columns.iter().map(|c| quote_mysql_identifier(c)).collect();
+            let chunk_size = get_rows_per_statement(columns.len(), MYSQL_MAX_BIND_PARAMS);
+
+            for chunk in rows.chunks(chunk_size) {
+                let placeholder_string = get_mysql_wildcard_string(columns.len(), chunk.len());
+
+                let query_string = format!(
+                    "INSERT IGNORE INTO {} ({}) VALUES {}",
+                    quote_mysql_identifier(&table.name),
+                    columns.join(", "),
+                    placeholder_string
+                );
+
+                let mut intermediate_query: Query<'_, _, _> = sqlx::query(&query_string);
+
+                for item in chunk {
+                    for cell in item.cells.values() {
+                        intermediate_query = bind_to_mysql_query(intermediate_query, cell);
+                    }
+                }
+
+                intermediate_query.execute(&mut *conn).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `None` when the row was ignored, its children are dropped with it
+    async fn insert_returning_pkey(
+        &self,
+        conn: &mut sqlx::MySqlConnection,
+        table: &MQTable,
+        row: &DataRow,
+    ) -> anyhow::Result<Option<i64>> {
+        let columns: Vec<_> = row
+            .columns()
+            .iter()
+            .map(|c| quote_mysql_identifier(c))
+            .collect();
+        let query_string = format!(
+            "INSERT IGNORE INTO {} ({}) VALUES {}",
+            quote_mysql_identifier(&table.name),
+            columns.join(", "),
+            get_mysql_wildcard_string(columns.len(), 1)
+        );
+
+        let mut intermediate_query: Query<'_, _, _> = sqlx::query(&query_string);
+        for cell in row.cells.values() {
+            intermediate_query = bind_to_mysql_query(intermediate_query, cell);
+        }
+
+        let result = intermediate_query.execute(&mut *conn).await?;
+        Ok((result.rows_affected() > 0).then(|| result.last_insert_id() as i64))
+    }
+}
+
+impl DBDriver for MySqlDriver {
+    #[allow(refining_impl_trait)]
+    async fn connect(connection_string: &str) -> anyhow::Result<MySqlDriver> {
+        let pool = MySqlPoolOptions::new()
+            .max_connections(5)
+            .connect(connection_string)
+            .await?;
+        println!("Connected to MySql with {}", connection_string);
+        anyhow::Ok(MySqlDriver { pool })
+    }
+
+    async fn execute_query(&self, query: &str) -> anyhow::Result<String> {
+        let result = sqlx::query(query).execute(&self.pool).await?;
+        Ok(result.rows_affected().to_string())
+    }
+
+    async fn insert_one(&self, row: DataRow, table: &MQTable) -> anyhow::Result<()> {
+        self.insert_many(&[row], table).await
+    }
+
+    async fn insert_many(&self, items: &[DataRow], table: &MQTable) -> anyhow::Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let mut transaction = self.pool.begin().await?;
+
+        self.insert_rows(
+            &mut transaction,
+            table,
+            items.iter().filter(|r| !r.has_children()),
+        )
+        .await?;
+
+        let mut parents = vec![];
+        for row in items.iter().filter(|r| r.has_children()) {
+            if let Some(pkey) = self
+                .insert_returning_pkey(&mut transaction, table, row)
+                .await?
+            {
+                parents.push((pkey, row));
+            }
+        }
+
+        for (child, rows) in child_rows(table, parents) {
+            self.insert_rows(&mut transaction, &child, &rows).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_table_info(&self, table: &MQTable) -> anyhow::Result<MQTableInfo> {
+        // information_schema columns are not plain VARCHAR on every server version
+        sqlx::query_as::<_, MQTableColumnInfo>(
+            "SELECT CAST(COLUMN_NAME AS CHAR) AS column_name, CAST(DATA_TYPE AS CHAR) AS data_type \
+             FROM INFORMATION_SCHEMA.COLUMNS WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+        )
+        .bind(table.name.as_str())
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| rows.into())
+        .map_err(|e| e.into())
+    }
+
+    async fn get_table_registry(&self) -> anyhow::Result<HashMap<String, MQTable>> {
+        // longest key InnoDB can index with utf8mb4
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} \
+             (topic VARCHAR(768) PRIMARY KEY, table_name VARCHAR(64) NOT NULL UNIQUE)",
+            quote_mysql_identifier(TABLE_REGISTRY)
+        ))
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query_as::<_, (String, String)>(&format!(
+            "SELECT topic, table_name FROM {}",
+            quote_mysql_identifier(TABLE_REGISTRY)
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|(topic, name)| (topic, MQTable { name }))
+                .collect()
+        })
+        .map_err(|e| e.into())
+    }
+
+    async fn register_table(&self, topic: &str, table: &MQTable) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (topic, table_name) VALUES (?, ?)",
+            quote_mysql_identifier(TABLE_REGISTRY)
+        ))
+        .bind(topic)
+        .bind(table.name.as_str())
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+
+    async fn create_dead_letter_table(&self) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} \
+             (id BIGINT AUTO_INCREMENT PRIMARY KEY, topic TEXT NOT NULL, \
+             payload LONGBLOB NOT NULL, error_kind VARCHAR(64) NOT NULL, \
+             error_message TEXT NOT NULL, received_ts DATETIME(6) NOT NULL)",
+            quote_mysql_identifier(DEAD_LETTER_TABLE)
+        ))
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+
+    async fn insert_dead_letters(&self, letters: &[DeadLetter]) -> anyhow::Result<()> {
+        let query_string = format!(
+            "INSERT INTO {} (topic, payload, error_kind, error_message, received_ts) \
+             VALUES (?, ?, ?, ?, ?)",
+            quote_mysql_identifier(DEAD_LETTER_TABLE)
+        );
+        let mut transaction = self.pool.begin().await?;
+        for letter in letters {
+            sqlx::query(&query_string)
+                .bind(letter.topic.as_str())
+                .bind(letter.payload.as_slice())
+                .bind(letter.error_kind.as_str())
+                .bind(letter.error_message.as_str())
+                .bind(letter.received_ts)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_dead_letters(&self) -> anyhow::Result<Vec<DeadLetter>> {
+        sqlx::query_as::<_, DeadLetter>(&format!(
+            "SELECT id, topic, payload, error_kind, error_message, received_ts \
+             FROM {} ORDER BY id",
+            quote_mysql_identifier(DEAD_LETTER_TABLE)
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.into())
+    }
+
+    async fn delete_dead_letter(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE id = ?",
+            quote_mysql_identifier(DEAD_LETTER_TABLE)
+        ))
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+
+    async fn add_column_to_table(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()> {
+        // mysql has no ADD COLUMN IF NOT EXISTS (only mariadb does)
+        if self
+            .get_table_info(table)
+            .await?
+            .has_column(&column.column_name)
+        {
+            return Ok(());
+        }
+
+        let query_string = format!(
+            "ALTER TABLE {} ADD COLUMN {} {} {}",
+            quote_mysql_identifier(&table.name),
+            quote_mysql_identifier(&column.column_name),
+            column.data_type,
+            column
+                .original_name
+                .as_ref()
+                .map(|name| format!("COMMENT {}", quote_mysql_literal(name)))
+                .unwrap_or_default()
+        );
+
+        sqlx::query(&query_string)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    async fn alter_column_type(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()> {
+        let query_string = format!(
+            "ALTER TABLE {} MODIFY COLUMN {} {}",
+            quote_mysql_identifier(&table.name),
+            quote_mysql_identifier(&column.column_name),
+            column.data_type
+        );
+
+        sqlx::query(&query_string)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    async fn create_table_if_not_exists(
+        &self,
+        table: &MQTable,
+        info: &MQTableInfo,
+    ) -> anyhow::Result<()> {
+        sqlx::query(&mysql_create_table_sql(table, info))
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    fn convert_to_db_type_string(&self, cell: &Cell) -> String {
+        match cell {
+            Cell::Number(_) => "BIGINT".to_string(),
+            Cell::Float(_) => "DOUBLE".to_string(),
+            Cell::Decimal(_) => "DECIMAL(65,30)".to_string(),
+            Cell::String(_) => "TEXT".to_string(),
+            Cell::Uuid(_) => "CHAR(36)".to_string(),
+            Cell::Inet(_) => "VARCHAR(45)".to_string(),
+            Cell::Bool(_) => "TINYINT(1)".to_string(),
+            Cell::Null => "TEXT".to_string(), // Default to TEXT for NULLs
+            Cell::JsonObject(_) => "JSON".to_string(),
+            Cell::DateTime(_) => "DATETIME(6)".to_string(),
+            // mysql has no zoned timestamp type, values are stored as UTC
+            Cell::DateTimeTz(_) => "DATETIME(6)".to_string(),
+        }
+    }
+
+    fn widen_db_type(&self, data_type: &str, cell: &Cell) -> Option<String> {
+        match (mysql_base_db_type(data_type).as_str(), cell) {
+            ("bigint" | "int" | "mediumint" | "smallint", Cell::Float(_)) => {
+                Some("DOUBLE".to_string())
+            }
+            (
+                "bigint" | "int" | "mediumint" | "smallint" | "double" | "float",
+                Cell::Decimal(_),
+            ) => Some("DECIMAL(65,30)".to_string()),
+            ("char" | "varchar" | "decimal" | "datetime", Cell::String(_)) => {
+                Some("TEXT".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    fn default_table_info(&self) -> MQTableInfo {
+        vec![
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::PKey.to_string(),
+                data_type: "BIGINT AUTO_INCREMENT".to_string(),
+                modifier: Modifier::PrimaryKey,
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::Raw.to_string(),
+                data_type: "TEXT".to_string(),
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::InsertTs.to_string(),
+                data_type: "DATETIME(6)".to_string(),
+                default_value: Some("UTC_TIMESTAMP(6)".into()),
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::ReceivedTs.to_string(),
+                data_type: "DATETIME(6)".to_string(),
+                ..Default::default()
+            },
+        ]
+        .into()
+    }
+}
+
+fn quote_mysql_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+/// Backslashes escape in MySQL string literals unless NO_BACKSLASH_ESCAPES is set
+fn quote_mysql_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+/// Foreign keys go in a table-level `FOREIGN KEY` clause, MySQL parses an inline
+/// `REFERENCES` on a column and then ignores it
+fn mysql_create_table_sql(table: &MQTable, info: &MQTableInfo) -> String {
+    let columns = info.columns();
+    let col_defs = columns.iter().map(|col| {
+        let modifier = match &col.modifier {
+            Modifier::References(_) => String::new(),
+            modifier => modifier.to_db_string(quote_mysql_identifier),
+        };
+        format!(
+            "{} {} {} {}",
+            quote_mysql_identifier(&col.column_name),
+            col.data_type,
+            modifier,
+            col.default_value
+                .as_ref()
+                .map(|f| f.to_db_string())
+                .unwrap_or_default()
+        )
+    });
+    let foreign_keys = columns.iter().filter_map(|col| match &col.modifier {
+        modifier @ Modifier::References(_) => Some(format!(
+            "FOREIGN KEY ({}) {}",
+            quote_mysql_identifier(&col.column_name),
+            modifier.to_db_string(quote_mysql_identifier)
+        )),
+        _ => None,
+    });
+
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} ({})",
+        quote_mysql_identifier(&table.name),
+        col_defs.chain(foreign_keys).join(", ")
+    )
+}
+
+fn get_mysql_wildcard_string(column_len: usize, items_len: usize) -> String {
+    let row = format!("({})", vec!["?"; column_len].join(", "));
+    vec![row; items_len].join(", ")
+}
+
+fn bind_to_mysql_query<'a>(
+    mut intermediate_query: Query<'a, sqlx::MySql, sqlx::mysql::MySqlArguments>,
+    cell: &'a Cell,
+) -> Query<'a, sqlx::MySql, sqlx::mysql::MySqlArguments> {
+    match cell {
+        Cell::Number(n) => {
+            intermediate_query = intermediate_query.bind(n);
+        }
+        Cell::Float(f) => {
+            intermediate_query = intermediate_query.bind(f);
+        }
+        Cell::Decimal(d) => {
+            intermediate_query = intermediate_query.bind(d);
+        }
+        Cell::Uuid(u) => {
+            // sqlx encodes uuids as 16 byte blobs here
+            intermediate_query = intermediate_query.bind(u.hyphenated().to_string());
+        }
+        Cell::Inet(ip) => {
+            intermediate_query = intermediate_query.bind(ip.to_string());
+        }
+        Cell::String(s) => {
+            intermediate_query = intermediate_query.bind(s);
+        }
+        Cell::Bool(b) => {
+            intermediate_query = intermediate_query.bind(b);
+        }
+        Cell::Null => {
+            intermediate_query = intermediate_query.bind(None::<String>);
+        }
+        Cell::JsonObject(obj) => {
+            intermediate_query = intermediate_query.bind(obj);
+        }
+        Cell::DateTime(dt) => {
+            intermediate_query = intermediate_query.bind(dt);
+        }
+        Cell::DateTimeTz(dt) => {
+            intermediate_query = intermediate_query.bind(dt);
+        }
+    }
+    intermediate_query
+}
+
+/// Driver picked at runtime from the scheme of the connection string
+pub enum AnyDriver {
+    Postgres(PostgresDriver),
+    Sqlite(SqliteDriver),
+    MySql(MySqlDriver),
+    Parquet(ParquetDriver),
+}
+
+impl DBDriver for AnyDriver {
+    #[allow(refining_impl_trait)]
+    async fn connect(connection_string: &str) -> anyhow::Result<AnyDriver> {
+        let scheme = connection_string
+            .split_once(':')
+            .map(|(scheme, _)| scheme)
+            .unwrap_or_default();
+        match scheme {
+            "postgres" | "postgresql" => Ok(AnyDriver::Postgres(
+                PostgresDriver::connect(connection_string).await?,
+            )),
+            "sqlite" => Ok(AnyDriver::Sqlite(
+                SqliteDriver::connect(connection_string).await?,
+            )),
+            "mysql" | "mariadb" => Ok(AnyDriver::MySql(
+                MySqlDriver::connect(connection_string).await?,
+            )),
+            "parquet" => Ok(AnyDriver::Parquet(
+                ParquetDriver::connect(connection_string).await?,
+            )),
+            _ => anyhow::bail!("Unsupported database url scheme: {}", scheme),
+        }
+    }
+
+    async fn execute_query(&self, query: &str) -> anyhow::Result<String> {
+        match self {
+            AnyDriver::Postgres(d) => d.execute_query(query).await,
+            AnyDriver::Sqlite(d) => d.execute_query(query).await,
+            AnyDriver::MySql(d) => d.execute_query(query).await,
+            AnyDriver::Parquet(d) => d.execute_query(query).await,
+        }
+    }
+
+    async fn insert_one(&self, item: DataRow, table: &MQTable) -> anyhow::Result<()> {
+        match self {
+            AnyDriver::Postgres(d) => d.insert_one(item, table).await,
+            AnyDriver::Sqlite(d) => d.insert_one(item, table).await,
+            AnyDriver::MySql(d) => d.insert_one(item, table).await,
+            AnyDriver::Parquet(d) => d.insert_one(item, table).await,
+        }
+    }
+
+    async fn insert_many(&self, items: &[DataRow], table: &MQTable) -> anyhow::Result<()> {
+        match self {
+            AnyDriver::Postgres(d) => d.insert_many(items, table).await,
+            AnyDriver::Sqlite(d) => d.insert_many(items, table).await,
+            AnyDriver::MySql(d) => d.insert_many(items, table).await,
+            AnyDriver::Parquet(d) => d.insert_many(items, table).await,
+        }
+    }
+
+    async fn get_table_info(&self, table: &MQTable) -> anyhow::Result<MQTableInfo> {
+        match self {
+            AnyDriver::Postgres(d) => d.get_table_info(table).await,
+            AnyDriver::Sqlite(d) => d.get_table_info(table).await,
+            AnyDriver::MySql(d) => d.get_table_info(table).await,
+            AnyDriver::Parquet(d) => d.get_table_info(table).await,
+        }
+    }
+
+    async fn get_table_registry(&self) -> anyhow::Result<HashMap<String, MQTable>> {
+        match self {
+            AnyDriver::Postgres(d) => d.get_table_registry().await,
+            AnyDriver::Sqlite(d) => d.get_table_registry().await,
+            AnyDriver::MySql(d) => d.get_table_registry().await,
+            AnyDriver::Parquet(d) => d.get_table_registry().await,
+        }
+    }
+
+    async fn register_table(&self, topic: &str, table: &MQTable) -> anyhow::Result<()> {
+        match self {
+            AnyDriver::Postgres(d) => d.register_table(topic, table).await,
+            AnyDriver::Sqlite(d) => d.register_table(topic, table).await,
+            AnyDriver::MySql(d) => d.register_table(topic, table).await,
+            AnyDriver::Parquet(d) => d.register_table(topic, table).await,
+        }
+    }
+
+    async fn create_dead_letter_table(&self) -> anyhow::Result<()> {
+        match self {
+            AnyDriver::Postgres(d) => d.create_dead_letter_table().await,
+            AnyDriver::Sqlite(d) => d.create_dead_letter_table().await,
+            AnyDriver::MySql(d) => d.create_dead_letter_table().await,
+            AnyDriver::Parquet(d) => d.create_dead_letter_table().await,
+        }
+    }
+
+    async fn insert_dead_letters(&self, letters: &[DeadLetter]) -> anyhow::Result<()> {
+        match self {
+            AnyDriver::Postgres(d) => d.insert_dead_letters(letters).await,
+            AnyDriver::Sqlite(d) => d.insert_dead_letters(letters).await,
+            AnyDriver::MySql(d) => d.insert_dead_letters(letters).await,
+            AnyDriver::Parquet(d) => d.insert_dead_letters(letters).await,
+        }
+    }
+
+    async fn get_dead_letters(&self) -> anyhow::Result<Vec<DeadLetter>> {
+        match self {
+            AnyDriver::Postgres(d) => d.get_dead_letters().await,
+            AnyDriver::Sqlite(d) => d.get_dead_letters().await,
+            AnyDriver::MySql(d) => d.get_dead_letters().await,
+            AnyDriver::Parquet(d) => d.get_dead_letters().await,
+        }
+    }
+
+    async fn delete_dead_letter(&self, id: i64) -> anyhow::Result<()> {
+        match self {
+            AnyDriver::Postgres(d) => d.delete_dead_letter(id).await,
+            AnyDriver::Sqlite(d) => d.delete_dead_letter(id).await,
+            AnyDriver::MySql(d) => d.delete_dead_letter(id).await,
+            AnyDriver::Parquet(d) => d.delete_dead_letter(id).await,
+        }
+    }
+
+    async fn add_column_to_table(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()> {
+        match self {
+            AnyDriver::Postgres(d) => d.add_column_to_table(table, column).await,
+            AnyDriver::Sqlite(d) => d.add_column_to_table(table, column).await,
+            AnyDriver::MySql(d) => d.add_column_to_table(table, column).await,
+            AnyDriver::Parquet(d) => d.add_column_to_table(table, column).await,
+        }
+    }
+
+    async fn create_table_if_not_exists(
+        &self,
+        table: &MQTable,
+        info: &MQTableInfo,
+    ) -> anyhow::Result<()> {
+        match self {
+            AnyDriver::Postgres(d) => d.create_table_if_not_exists(table, info).await,
+            AnyDriver::Sqlite(d) => d.create_table_if_not_exists(table, info).await,
+            AnyDriver::MySql(d) => d.create_table_if_not_exists(table, info).await,
+            AnyDriver::Parquet(d) => d.create_table_if_not_exists(table, info).await,
+        }
+    }
+
+    async fn alter_column_type(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()> {
+        match self {
+            AnyDriver::Postgres(d) => d.alter_column_type(table, column).await,
+            AnyDriver::Sqlite(d) => d.alter_column_type(table, column).await,
+            AnyDriver::MySql(d) => d.alter_column_type(table, column).await,
+            AnyDriver::Parquet(d) => d.alter_column_type(table, column).await,
+        }
+    }
+
+    fn convert_to_db_type_string(&self, cell: &Cell) -> String {
+        match self {
+            AnyDriver::Postgres(d) => d.convert_to_db_type_string(cell),
+            AnyDriver::Sqlite(d) => d.convert_to_db_type_string(cell),
+            AnyDriver::MySql(d) => d.convert_to_db_type_string(cell),
+            AnyDriver::Parquet(d) => d.convert_to_db_type_string(cell),
+        }
+    }
+
+    fn widen_db_type(&self, data_type: &str, cell: &Cell) -> Option<String> {
+        match self {
+            AnyDriver::Postgres(d) => d.widen_db_type(data_type, cell),
+            AnyDriver::Sqlite(d) => d.widen_db_type(data_type, cell),
+            AnyDriver::MySql(d) => d.widen_db_type(data_type, cell),
+            AnyDriver::Parquet(d) => d.widen_db_type(data_type, cell),
+        }
+    }
+
+    fn default_table_info(&self) -> MQTableInfo {
+        match self {
+            AnyDriver::Postgres(d) => d.default_table_info(),
+            AnyDriver::Sqlite(d) => d.default_table_info(),
+            AnyDriver::MySql(d) => d.default_table_info(),
+            AnyDriver::Parquet(d) => d.default_table_info(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod group_by_columns {
+        use std::collections::BTreeMap;
+
+        use crate::db::{group_by_columns, Cell, DataRow};
+
+        fn row(columns: &[&str]) -> DataRow {
+            DataRow {
+                cells: columns
+                    .iter()
+                    .map(|c| (c.to_string(), Cell::Null))
+                    .collect::<BTreeMap<_, _>>(),
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn test_uniform_batch_is_one_group() {
+            let items = vec![row(&["a", "b"]), row(&["a", "b"])];
+            let groups = group_by_columns(&items);
+            assert_eq!(groups.len(), 1);
+            assert_eq!(groups[0].0, vec!["a", "b"]);
+            assert_eq!(groups[0].1.len(), 2);
+        }
+
+        #[test]
+        fn test_mixed_batch_groups_by_column_set() {
+            let items = vec![
+                row(&["a", "b"]),
+                row(&["a"]),
+                row(&["a", "b"]),
+                row(&["b", "c"]),
+                row(&["a"]),
+            ];
+            let groups = group_by_columns(&items);
+            let shapes: Vec<_> = groups.iter().map(|(c, r)| (c.clone(), r.len())).collect();
+            assert_eq!(
+                shapes,
+                vec![
+                    (vec!["a".to_string(), "b".to_string()], 2),
+                    (vec!["a".to_string()], 2),
+                    (vec!["b".to_string(), "c".to_string()], 1),
+                ]
+            );
+        }
+
+        #[test]
+        fn test_empty_batch_has_no_groups() {
+            assert!(group_by_columns(&[]).is_empty());
+        }
+    }
+
+    mod child_rows {
+        use std::collections::BTreeMap;
+
+        use crate::db::{child_rows, Cell, DataRow, MQTable};
+
+        fn row(cells: Vec<(&str, Cell)>, children: Vec<(&str, Vec<DataRow>)>) -> DataRow {
+            DataRow {
+                cells: cells
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect::<BTreeMap<_, _>>(),
+                children: children
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn test_child_table_name() {
+            let table = MQTable::from_topic("devices/1");
+            assert_eq!(table.child("items").name, "devices_1__items");
+            assert_eq!(table.child("order.items").name, "devices_1__order_items");
+        }
+
+        #[test]
+        fn test_children_point_at_parent() {
+            let table = MQTable::from_topic("orders");
+            let element = |i| row(vec![("element_index", Cell::Number(i))], vec![]);
+            let first = row(vec![], vec![("items", vec![element(0), element(1)])]);
+            let second = row(
+                vec![],
+                vec![("items", vec![element(0)]), ("tags", vec![element(0)])],
+            );
+
+            let tables = child_rows(&table, [(10, &first), (11, &second)]);
+            let names: Vec<_> = tables.iter().map(|(t, _)| t.name.as_str()).collect();
+            assert_eq!(names, ["orders__items", "orders__tags"]);
+
+            let parents: Vec<_> = tables[0]
+                .1
+                .iter()
+                .map(|r| r.cells["parent_pkey"].clone())
+                .collect();
+            assert_eq!(
+                parents,
+                [Cell::Number(10), Cell::Number(10), Cell::Number(11)]
+            );
+            assert_eq!(tables[1].1[0].cells["parent_pkey"], Cell::Number(11));
+        }
+
+        #[test]
+        fn test_with_pkey_drops_children() {
+            let parent = row(vec![("a", Cell::Number(1))], vec![("items", vec![])]);
+            let keyed = parent.with_pkey(5);
+            assert_eq!(keyed.cells["pkey"], Cell::Number(5));
+            assert!(keyed.children.is_empty());
+        }
+    }
+
+    mod quoting {
+        use crate::db::{
+            quote_identifier, quote_literal, quote_mysql_identifier, quote_mysql_literal, Modifier,
+        };
+
+        #[test]
+        fn test_identifier_escapes_quotes() {
+            assert_eq!(quote_identifier("temp"), r#""temp""#);
+            assert_eq!(
+                quote_identifier(r#"a"; DROP TABLE y; --"#),
+                r#""a""; DROP TABLE y; --""#
+            );
+            assert_eq!(quote_mysql_identifier("a`b"), "`a``b`");
+        }
+
+        #[test]
+        fn test_literal_escapes_quotes() {
+            assert_eq!(quote_literal("it's"), "'it''s'");
+            assert_eq!(quote_mysql_literal(r"it's \"), r"'it''s \\'");
+        }
+
+        #[test]
+        fn test_references_are_quoted() {
+            let modifier = Modifier::References("orders".to_string());
+            assert_eq!(
+                modifier.to_db_string(quote_identifier),
+                r#"REFERENCES "orders" ("pkey")"#
+            );
+            assert_eq!(
+                modifier.to_db_string(quote_mysql_identifier),
+                "REFERENCES `orders` (`pkey`)"
+            );
+        }
+    }
+
+    mod sqlite_driver {
+        use std::collections::BTreeMap;
+
+        use chrono::Utc;
+        use sqlx::Row;
+        use tempfile::TempDir;
+
+        use crate::{
+            db::{
+                AnyDriver, Cell, DBDriver, DataRow, MQTable, MQTableColumnInfo, SqliteDriver,
+                DEAD_LETTER_TABLE,
+            },
+            dead_letter::{to_data_row, DeadLetter},
+            manager::Manager,
+            mapper::{
+                json_to_data_row, json_to_data_row_with, FlattenOptions, InferenceRules,
+                MappingOptions,
+            },
+            router::Router,
+        };
+
+        async fn connect() -> (TempDir, SqliteDriver) {
+            let dir = tempfile::tempdir().unwrap();
+            let url = format!("sqlite://{}", dir.path().join("test.db").display());
+            let driver = SqliteDriver::connect(&url).await.unwrap();
+            (dir, driver)
+        }
+
+        fn row(cells: Vec<(&str, Cell)>) -> DataRow {
+            DataRow {
+                cells: cells
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect::<BTreeMap<_, _>>(),
+                ..Default::default()
+            }
+        }
+
+        #[tokio::test]
+        async fn test_connect_creates_file() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("created.db");
+            SqliteDriver::connect(&format!("sqlite://{}", path.display()))
+                .await
+                .unwrap();
+            assert!(path.exists());
+        }
+
+        #[tokio::test]
+        async fn test_any_driver_selects_sqlite() {
+            let dir = tempfile::tempdir().unwrap();
+            let url = format!("sqlite://{}", dir.path().join("any.db").display());
+            let driver = AnyDriver::connect(&url).await.unwrap();
+            assert!(matches!(driver, AnyDriver::Sqlite(_)));
+        }
+
+        #[tokio::test]
+        async fn test_any_driver_rejects_unknown_scheme() {
+            assert!(AnyDriver::connect("oracle://localhost").await.is_err());
+        }
+
+        #[tokio::test]
+        async fn test_missing_table_has_no_info() {
+            let (_dir, driver) = connect().await;
+            let info = driver
+                .get_table_info(&MQTable::from_topic("missing"))
+                .await
+                .unwrap();
+            assert!(!info.exists());
+        }
+
+        #[tokio::test]
+        async fn test_create_table_and_introspect() {
+            let (_dir, driver) = connect().await;
+            let table = MQTable::from_topic("sensors/temp");
+            driver
+                .create_table_if_not_exists(&table, &driver.default_table_info())
+                .await
+                .unwrap();
+
+            let info = driver.get_table_info(&table).await.unwrap();
+            assert!(info.has_column("pkey"));
+            assert!(info.has_column("raw"));
+            assert!(info.has_column("insert_ts"));
+            assert!(info.has_column("received_ts"));
+            assert_eq!(info.columns.get("pkey").unwrap().data_type, "INTEGER");
+        }
+
+        #[tokio::test]
+        async fn test_add_column_is_idempotent() {
+            let (_dir, driver) = connect().await;
+            let table = MQTable::from_topic("add_column");
+            driver
+                .create_table_if_not_exists(&table, &driver.default_table_info())
+                .await
+                .unwrap();
+
+            let column = MQTableColumnInfo {
+                column_name: "reading".to_string(),
+                data_type: "INTEGER".to_string(),
+                ..Default::default()
+            };
+            driver.add_column_to_table(&table, &column).await.unwrap();
+            driver.add_column_to_table(&table, &column).await.unwrap();
+
+            let info = driver.get_table_info(&table).await.unwrap();
+            assert_eq!(info.columns.get("reading").unwrap().data_type, "INTEGER");
+        }
+
+        #[tokio::test]
+        async fn test_insert_many_rows() {
+            let (_dir, driver) = connect().await;
+            let table = MQTable::from_topic("insert_many");
+            driver
+                .create_table_if_not_exists(&table, &driver.default_table_info())
+                .await
+                .unwrap();
+            for (name, cell) in [("id", Cell::Number(0)), ("flag", Cell::Bool(false))] {
+                let column = MQTableColumnInfo {
+                    column_name: name.to_string(),
+                    data_type: driver.convert_to_db_type_string(&cell),
+                    ..Default::default()
+                };
+                driver.add_column_to_table(&table, &column).await.unwrap();
+            }
+
+            let rows = vec![
+                row(vec![("id", Cell::Number(1)), ("flag", Cell::Bool(true))]),
+                row(vec![("id", Cell::Number(2)), ("flag", Cell::Bool(false))]),
+                row(vec![("id", Cell::Number(3)), ("flag", Cell::Null)]),
+            ];
+            driver.insert_many(&rows, &table).await.unwrap();
+
+            let fetched = sqlx::query("SELECT id, flag FROM insert_many ORDER BY pkey")
+                .fetch_all(&driver.pool)
+                .await
+                .unwrap();
+            assert_eq!(fetched.len(), 3);
+            assert_eq!(fetched[0].get::<i64, _>("id"), 1);
+            assert_eq!(fetched[1].get::<Option<bool>, _>("flag"), Some(false));
+            assert_eq!(fetched[2].get::<Option<bool>, _>("flag"), None);
+        }
+
+        #[tokio::test]
+        async fn test_wide_batch_is_chunked() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let table = MQTable::from_topic("wide");
+
+            // 50 columns of 1000 rows are more bind parameters than one statement takes
+            let rows: Vec<DataRow> = (0..1000)
+                .map(|i| DataRow {
+                    cells: (0..50)
+                        .map(|c| (format!("c{c}"), Cell::Number(i)))
+                        .collect(),
+                    ..Default::default()
+                })
+                .collect();
+            manager.insert_many(&table, &rows).await.unwrap();
+
+            let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM wide")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(count, 1000);
+        }
+
+        #[tokio::test]
+        async fn test_insert_sparse_and_dense_rows() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let table = MQTable::from_topic("mixed");
+
+            let rows = vec![
+                json_to_data_row(r#"{"a": 1, "b": "dense", "c": true}"#, Utc::now()).unwrap(),
+                json_to_data_row(r#"{"c": false}"#, Utc::now()).unwrap(),
+                json_to_data_row(r#"{"b": "sparse"}"#, Utc::now()).unwrap(),
+                json_to_data_row(r#"{"a": 4, "b": "dense", "c": true}"#, Utc::now()).unwrap(),
+            ];
+            manager.insert_many(&table, &rows).await.unwrap();
+
+            let fetched = sqlx::query("SELECT a, b, c, insert_ts FROM mixed ORDER BY a, b")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            let values: Vec<_> = fetched
+                .iter()
+                .map(|r| {
+                    (
+                        r.get::<Option<i64>, _>("a"),
+                        r.get::<Option<String>, _>("b"),
+                        r.get::<Option<bool>, _>("c"),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                values,
+                vec![
+                    (None, None, Some(false)),
+                    (None, Some("sparse".to_string()), None),
+                    (Some(1), Some("dense".to_string()), Some(true)),
+                    (Some(4), Some("dense".to_string()), Some(true)),
+                ]
+            );
+            // missing columns fall back to defaults, not NULL
+            assert!(fetched.iter().all(|r| r
+                .get::<Option<chrono::NaiveDateTime>, _>("insert_ts")
+                .is_some()));
+        }
+
+        #[tokio::test]
+        async fn test_floats_and_decimals() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let table = MQTable::from_topic("numbers");
+
+            let rows = vec![
+                json_to_data_row(r#"{"temp": 21, "big": 18446744073709551615}"#, Utc::now())
+                    .unwrap(),
+                json_to_data_row(r#"{"temp": 21.7, "big": 1}"#, Utc::now()).unwrap(),
+            ];
+            manager.insert_many(&table, &rows).await.unwrap();
+
+            let fetched =
+                sqlx::query("SELECT temp, CAST(big AS TEXT) AS big FROM numbers ORDER BY pkey")
+                    .fetch_all(&pool)
+                    .await
+                    .unwrap();
+            // INTEGER affinity keeps whole numbers as integers
+            assert_eq!(fetched[0].get::<i64, _>("temp"), 21);
+            assert_eq!(fetched[1].get::<f64, _>("temp"), 21.7);
+            assert_eq!(fetched[0].get::<String, _>("big"), "18446744073709551615");
+        }
+
+        #[tokio::test]
+        async fn test_inferred_cells() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let table = MQTable::from_topic("inferred");
+            let options = MappingOptions {
+                infer: InferenceRules {
+                    timestamps: true,
+                    uuids: true,
+                    ip_addresses: true,
+                    numeric_strings: true,
+                    epoch_millis_fields: vec!["*_ms".to_string()],
+                },
+                ..Default::default()
+            };
+
+            let rows = vec![json_to_data_row_with(
+                r#"{"at": "2024-01-02T03:04:05Z", "id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+                    "ip": "10.0.0.1", "price": "12.50", "seen_ms": 1700000000000}"#,
+                Utc::now(),
+                &options,
+            )
+            .unwrap()];
+            manager.insert_many(&table, &rows).await.unwrap();
+
+            let types = sqlx::query(
+                "SELECT name, type FROM pragma_table_info('inferred') \
+                 WHERE name IN ('at', 'id', 'ip', 'price', 'seen_ms') ORDER BY name",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| (r.get::<String, _>("name"), r.get::<String, _>("type")))
+            .collect::<Vec<_>>();
+            assert_eq!(
+                types,
+                [
+                    ("at", "DATETIME"),
+                    ("id", "TEXT"),
+                    ("ip", "TEXT"),
+                    ("price", "TEXT"),
+                    ("seen_ms", "DATETIME"),
+                ]
+                .map(|(n, t)| (n.to_string(), t.to_string()))
+            );
+
+            let fetched = sqlx::query("SELECT at, id, ip, price FROM inferred")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(
+                fetched.get::<chrono::DateTime<Utc>, _>("at").to_rfc3339(),
+                "2024-01-02T03:04:05+00:00"
+            );
+            assert_eq!(
+                fetched.get::<String, _>("id"),
+                "67e55044-10b1-426f-9247-bb680e5fe0c8"
+            );
+            assert_eq!(fetched.get::<String, _>("ip"), "10.0.0.1");
+            assert_eq!(fetched.get::<String, _>("price"), "12.50");
+        }
+
+        #[tokio::test]
+        async fn test_flattened_columns_are_typed() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let table = MQTable::from_topic("flat");
+            let options = MappingOptions {
+                flatten: Some(FlattenOptions::default()),
+                ..Default::default()
+            };
+
+            let rows = vec![
+                json_to_data_row_with(
+                    r#"{"complex": {"id": 1, "name": "x"}}"#,
+                    Utc::now(),
+                    &options,
+                )
+                .unwrap(),
+                json_to_data_row_with(
+                    r#"{"complex": {"id": 2, "pos": {"lat": 1.5}}}"#,
+                    Utc::now(),
+                    &options,
+                )
+                .unwrap(),
+            ];
+            manager.insert_many(&table, &rows).await.unwrap();
+
+            let types = sqlx::query(
+                "SELECT name, type FROM pragma_table_info('flat') \
+                 WHERE name LIKE 'complex%' ORDER BY name",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| (r.get::<String, _>("name"), r.get::<String, _>("type")))
+            .collect::<Vec<_>>();
+            assert_eq!(
+                types,
+                [
+                    ("complex_id", "INTEGER"),
+                    ("complex_name", "TEXT"),
+                    ("complex_pos_lat", "REAL"),
+                ]
+                .map(|(n, t)| (n.to_string(), t.to_string()))
+            );
+
+            let fetched = sqlx::query("SELECT complex_id FROM flat ORDER BY pkey")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            assert_eq!(fetched[1].get::<i64, _>("complex_id"), 2);
+        }
+
+        #[tokio::test]
+        async fn test_exploded_arrays_go_to_child_tables() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let table = MQTable::from_topic("orders");
+            let options = MappingOptions {
+                explode: vec!["items".to_string()],
+                ..Default::default()
+            };
+
+            let rows = [
+                r#"{"id": 1, "items": [{"sku": "a", "qty": 2}, {"sku": "b", "qty": 1}]}"#,
+                r#"{"id": 2}"#,
+                r#"{"id": 3, "items": [{"sku": "c", "qty": 5}]}"#,
+            ]
+            .map(|json| json_to_data_row_with(json, Utc::now(), &options).unwrap());
+            manager.insert_many(&table, &rows).await.unwrap();
+
+            let fetched = sqlx::query(
+                "SELECT o.id, i.sku, i.qty, i.element_index FROM orders o \
+                 JOIN orders__items i ON i.parent_pkey = o.pkey ORDER BY o.id, i.element_index",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| {
+                (
+                    r.get::<i64, _>("id"),
+                    r.get::<String, _>("sku"),
+                    r.get::<i64, _>("qty"),
+                    r.get::<i64, _>("element_index"),
+                )
+            })
+            .collect::<Vec<_>>();
+            assert_eq!(
+                fetched,
+                [
+                    (1, "a".to_string(), 2, 0),
+                    (1, "b".to_string(), 1, 1),
+                    (3, "c".to_string(), 5, 0),
+                ]
+            );
+
+            let parents: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM orders")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(parents, 3);
+        }
+
+        #[tokio::test]
+        async fn test_child_table_references_parent() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let table = MQTable::from_topic("orders");
+            let options = MappingOptions {
+                explode: vec!["items".to_string()],
+                ..Default::default()
+            };
+
+            let rows =
+                vec![json_to_data_row_with(r#"{"items": [1]}"#, Utc::now(), &options).unwrap()];
+            manager.insert_many(&table, &rows).await.unwrap();
+
+            let references: String = sqlx::query_scalar(
+                "SELECT \"table\" FROM pragma_foreign_key_list('orders__items') \
+                 WHERE \"from\" = 'parent_pkey'",
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            assert_eq!(references, "orders");
+
+            // sqlx turns foreign keys on, a dangling child is rejected
+            let dangling = sqlx::query(
+                "INSERT INTO orders__items (parent_pkey, element_index) VALUES (99, 0)",
+            )
+            .execute(&pool)
+            .await;
+            assert!(dangling.is_err());
+        }
+
+        #[tokio::test]
+        async fn test_child_table_is_reserved() {
+            let (_dir, driver) = connect().await;
+            let mut manager = Manager::new(driver);
+            let options = MappingOptions {
+                explode: vec!["items".to_string()],
+                ..Default::default()
+            };
+            let rows =
+                vec![json_to_data_row_with(r#"{"items": [1]}"#, Utc::now(), &options).unwrap()];
+            manager
+                .insert_many(&MQTable::from_topic("orders"), &rows)
+                .await
+                .unwrap();
+
+            // neither a topic nor a routing rule can take the name
+            let table = manager.resolve_table("orders__items").await.unwrap();
+            assert_ne!(table.name, "orders__items");
+            assert!(manager.resolve_routed_table("orders__items").await.is_err());
+        }
+
+        #[tokio::test]
+        async fn test_child_table_taken_by_topic() {
+            let (_dir, driver) = connect().await;
+            let mut manager = Manager::new(driver);
+            manager.resolve_table("orders__items").await.unwrap();
+            let options = MappingOptions {
+                explode: vec!["items".to_string()],
+                ..Default::default()
+            };
+            let rows =
+                vec![json_to_data_row_with(r#"{"items": [1]}"#, Utc::now(), &options).unwrap()];
+            let err = manager
+                .insert_many(&MQTable::from_topic("orders"), &rows)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Child table orders__items is already used by orders__items"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_colliding_topics_get_distinct_tables() {
+            let (_dir, driver) = connect().await;
+            let mut manager = Manager::new(driver);
+
+            let first = manager.resolve_table("a/b").await.unwrap();
+            let second = manager.resolve_table("a_b").await.unwrap();
+            assert_eq!(first.name, "a_b");
+            assert_eq!(second, MQTable::from_topic_hashed("a_b"));
+            assert_eq!(manager.resolve_table("a/b").await.unwrap(), first);
+        }
+
+        #[tokio::test]
+        async fn test_registry_survives_reconnect() {
+            let dir = tempfile::tempdir().unwrap();
+            let url = format!("sqlite://{}", dir.path().join("test.db").display());
+
+            let mut manager = Manager::new(SqliteDriver::connect(&url).await.unwrap());
+            manager.resolve_table("a/b").await.unwrap();
+            let second = manager.resolve_table("a_b").await.unwrap();
+            drop(manager);
+
+            // resolved in the opposite order, the registry still decides
+            let mut manager = Manager::new(SqliteDriver::connect(&url).await.unwrap());
+            assert_eq!(manager.resolve_table("a_b").await.unwrap(), second);
+            assert_eq!(manager.resolve_table("a/b").await.unwrap().name, "a_b");
+        }
+
+        #[tokio::test]
+        async fn test_registry_table_name_is_reserved() {
+            let (_dir, driver) = connect().await;
+            let mut manager = Manager::new(driver);
+            let table = manager.resolve_table("_topic_tables").await.unwrap();
+            assert_ne!(table.name, "_topic_tables");
+        }
+
+        #[tokio::test]
+        async fn test_register_rejects_taken_table() {
+            let (_dir, driver) = connect().await;
+            driver.get_table_registry().await.unwrap();
+            let table = MQTable::from_topic("a/b");
+            driver.register_table("a/b", &table).await.unwrap();
+            assert!(driver.register_table("a_b", &table).await.is_err());
+        }
+
+        #[tokio::test]
+        async fn test_wildcard_filter_creates_no_table() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let known = manager.resolve_table("sensors/a").await.unwrap();
+            manager.resolve_table("other/b").await.unwrap();
+
+            manager.initialize_matching("sensors/#").await.unwrap();
+
+            let tables: Vec<String> = sqlx::query_scalar(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+            assert_eq!(tables, ["_topic_tables", known.name.as_str()]);
+        }
+
+        #[tokio::test]
+        async fn test_routed_topics_share_a_table() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let router = Router::new(
+                toml::from_str(
+                    r#"
+                    [[routes]]
+                    pattern = "sites/{site}/devices/{device_id}/telemetry"
+                    table = "telemetry"
+                    "#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+            for topic in [
+                "sites/berlin/devices/d1/telemetry",
+                "sites/paris/devices/d2/telemetry",
+            ] {
+                let route = router.route(topic);
+                let table = manager
+                    .resolve_routed_table(route.table.unwrap())
+                    .await
+                    .unwrap();
+                let mut row = json_to_data_row(r#"{"temp": 21}"#, Utc::now()).unwrap();
+                route.merge_into(&mut row);
+                manager.insert(&table, row).await.unwrap();
+            }
+
+            let fetched = sqlx::query("SELECT site, device_id, temp FROM telemetry ORDER BY pkey")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            assert_eq!(fetched.len(), 2);
+            assert_eq!(fetched[0].get::<String, _>("site"), "berlin");
+            assert_eq!(fetched[1].get::<String, _>("device_id"), "d2");
+            assert_eq!(fetched[1].get::<i64, _>("temp"), 21);
+        }
+
+        #[tokio::test]
+        async fn test_routed_table_is_reserved() {
+            let (_dir, driver) = connect().await;
+            let mut manager = Manager::new(driver);
+
+            let routed = manager.resolve_routed_table("telemetry").await.unwrap();
+            assert_eq!(routed.name, "telemetry");
+            assert_eq!(
+                manager.resolve_routed_table("telemetry").await.unwrap(),
+                routed
+            );
+            // a topic without a rule can't take the name
+            let table = manager.resolve_table("telemetry").await.unwrap();
+            assert_ne!(table.name, "telemetry");
+        }
+
+        #[tokio::test]
+        async fn test_dead_letter_table_is_reserved() {
+            let (_dir, driver) = connect().await;
+            let mut manager = Manager::new(driver);
+            let table = manager.resolve_table(DEAD_LETTER_TABLE).await.unwrap();
+            assert_ne!(table.name, DEAD_LETTER_TABLE);
+            assert!(manager
+                .resolve_routed_table(DEAD_LETTER_TABLE)
+                .await
+                .is_err());
+        }
+
+        /// With the error `payload` fails with, `invalid_payload` for one that maps fine
+        fn dead_letter(topic: &str, payload: &[u8]) -> DeadLetter {
+            let error = to_data_row(payload, Utc::now(), &MappingOptions::default())
+                .err()
+                .unwrap_or_else(|| {
+                    to_data_row(b"[]", Utc::now(), &MappingOptions::default()).unwrap_err()
+                });
+            DeadLetter::new(topic.to_string(), payload.to_vec(), Utc::now(), &error)
+        }
+
+        #[tokio::test]
+        async fn test_dead_letters_round_trip() {
+            let (_dir, driver) = connect().await;
+            let mut manager = Manager::new(driver);
+            let letters = [dead_letter("a/b", b"\xff\x00raw"), dead_letter("c", b"[1]")];
+            manager.dead_letter(&letters).await.unwrap();
+
+            let stored = manager.dead_letters().await.unwrap();
+            assert_eq!(stored.len(), 2);
+            for (stored, letter) in stored.iter().zip(&letters) {
+                assert_eq!(
+                    DeadLetter {
+                        id: letter.id,
+                        ..stored.clone()
+                    },
+                    *letter
+                );
+            }
+            assert_eq!(stored[0].error_kind, "invalid_utf8");
+            assert_eq!(stored[1].error_kind, "invalid_payload");
+
+            manager.remove_dead_letter(stored[0].id).await.unwrap();
+            let left = manager.dead_letters().await.unwrap();
+            assert_eq!(left, stored[1..]);
+        }
+
+        #[tokio::test]
+        async fn test_routed_table_taken_by_topic() {
+            let (_dir, driver) = connect().await;
+            let mut manager = Manager::new(driver);
+            manager.resolve_table("telemetry").await.unwrap();
+            assert!(manager.resolve_routed_table("telemetry").await.is_err());
+        }
+
+        #[tokio::test]
+        async fn test_hostile_identifiers_are_quoted() {
+            let (_dir, driver) = connect().await;
+            let table = MQTable {
+                name: r#"t"; DROP TABLE victim; --"#.to_string(),
+            };
+            let column = r#"c" TEXT); DROP TABLE victim; --"#;
+            driver
+                .execute_query("CREATE TABLE victim (id INTEGER)")
+                .await
+                .unwrap();
+
+            driver
+                .create_table_if_not_exists(&table, &driver.default_table_info())
+                .await
+                .unwrap();
+            driver
+                .add_column_to_table(
+                    &table,
+                    &MQTableColumnInfo {
+                        column_name: column.to_string(),
+                        data_type: "TEXT".to_string(),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            driver
+                .insert_many(
+                    &[row(vec![(column, Cell::String("x".to_string()))])],
+                    &table,
+                )
+                .await
+                .unwrap();
+
+            let info = driver.get_table_info(&table).await.unwrap();
+            assert!(info.has_column(column));
+            let victim = driver
+                .get_table_info(&MQTable::from_topic("victim"))
+                .await
+                .unwrap();
+            assert!(victim.exists());
+        }
+
+        #[tokio::test]
+        async fn test_hostile_payload_keys() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let table = manager.resolve_table("Site A/\"Temp\"").await.unwrap();
+
+            let rows =
+                vec![
+                    json_to_data_row(r#"{"x; DROP TABLE y": 1, "\"Temp C\"": 21.5}"#, Utc::now())
+                        .unwrap(),
+                ];
+            manager.insert_many(&table, &rows).await.unwrap();
+
+            let fetched = sqlx::query(&format!(
+                "SELECT x__drop_table_y, _temp_c_ FROM {}",
+                table.name
+            ))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            assert_eq!(table.name, "site_a__temp_");
+            assert_eq!(fetched.get::<i64, _>(0), 1);
+            assert_eq!(fetched.get::<f64, _>(1), 21.5);
+        }
+
+        #[tokio::test]
+        async fn test_insert_empty_is_noop() {
+            let (_dir, driver) = connect().await;
+            driver
+                .insert_many(&[], &MQTable::from_topic("never_created"))
+                .await
+                .unwrap();
+        }
+
+        #[tokio::test]
+        async fn test_manager_round_trip() {
+            let (_dir, driver) = connect().await;
+            let pool = driver.pool.clone();
+            let mut manager = Manager::new(driver);
+            let table = MQTable::from_topic("devices/1");
+
+            let rows = vec![
+                json_to_data_row(r#"{"temp": 21, "name": "a"}"#, Utc::now()).unwrap(),
+                json_to_data_row(r#"{"temp": 22, "name": "b"}"#, Utc::now()).unwrap(),
+            ];
+            manager.insert_many(&table, &rows).await.unwrap();
+
+            let fetched = sqlx::query("SELECT temp, name, insert_ts FROM devices_1 ORDER BY pkey")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            assert_eq!(fetched.len(), 2);
+            assert_eq!(fetched[1].get::<i64, _>("temp"), 22);
+            assert_eq!(fetched[1].get::<String, _>("name"), "b");
+            assert!(fetched[0]
+                .get::<Option<chrono::NaiveDateTime>, _>("insert_ts")
+                .is_some());
+        }
+
+        #[tokio::test]
+        async fn test_convert_to_db_type_string() {
+            let driver = SqliteDriver {
+                pool: sqlx::Pool::connect_lazy("sqlite::memory:").unwrap(),
+            };
+            let cases = vec![
+                (Cell::Number(1), "INTEGER"),
+                (Cell::Float(1.5), "REAL"),
+                (Cell::Decimal(1.into()), "TEXT"),
+                (Cell::String("a".to_string()), "TEXT"),
+                (Cell::Bool(true), "BOOLEAN"),
+                (Cell::Null, "TEXT"),
+                (Cell::JsonObject(serde_json::json!({})), "JSON"),
+                (Cell::DateTime(Utc::now().naive_utc()), "DATETIME"),
+                (Cell::DateTimeTz(Utc::now()), "DATETIME"),
+            ];
+            for (cell, expected) in cases {
+                assert_eq!(driver.convert_to_db_type_string(&cell), expected);
+            }
+        }
+    }
+
+    mod mysql_driver {
+        use chrono::Utc;
+
+        use crate::{
+            db::{
+                get_mysql_wildcard_string, mysql_create_table_sql, quote_mysql_identifier, Cell,
+                DBDriver, MQTable, MQTableColumnInfo, Modifier, MySqlDriver,
+            },
+            manager::Manager,
+            mapper::{json_to_data_row_with, MappingOptions},
+        };
+
+        fn lazy_driver() -> MySqlDriver {
+            MySqlDriver {
+                pool: sqlx::Pool::connect_lazy("mysql://root@localhost/test").unwrap(),
+            }
+        }
+
+        #[test]
+        fn test_quote_identifier() {
+            assert_eq!(quote_mysql_identifier("sensors_temp"), "`sensors_temp`");
+        }
+
+        #[test]
+        fn test_quote_identifier_escapes_backticks() {
+            assert_eq!(quote_mysql_identifier("a`b"), "`a``b`");
+        }
+
+        #[test]
+        fn test_wildcard_string() {
+            assert_eq!(get_mysql_wildcard_string(2, 3), "(?, ?), (?, ?), (?, ?)");
+            assert_eq!(get_mysql_wildcard_string(1, 1), "(?)");
+        }
+
+        #[tokio::test]
+        async fn test_convert_to_db_type_string() {
+            let driver = lazy_driver();
+            let cases = vec![
+                (Cell::Number(1), "BIGINT"),
+                (Cell::Float(1.5), "DOUBLE"),
+                (Cell::Decimal(1.into()), "DECIMAL(65,30)"),
+                (Cell::String("a".to_string()), "TEXT"),
+                (Cell::Bool(true), "TINYINT(1)"),
+                (Cell::Null, "TEXT"),
+                (Cell::JsonObject(serde_json::json!({})), "JSON"),
+                (Cell::DateTime(Utc::now().naive_utc()), "DATETIME(6)"),
+                (Cell::DateTimeTz(Utc::now()), "DATETIME(6)"),
+            ];
+            for (cell, expected) in cases {
+                assert_eq!(driver.convert_to_db_type_string(&cell), expected);
+            }
+        }
+
+        #[tokio::test]
+        async fn test_widen_db_type() {
+            let driver = lazy_driver();
+            assert_eq!(
+                driver.widen_db_type("bigint", &Cell::Float(1.5)),
+                Some("DOUBLE".to_string())
+            );
+            assert_eq!(
+                driver.widen_db_type("double", &Cell::Decimal(1.into())),
+                Some("DECIMAL(65,30)".to_string())
+            );
+            assert_eq!(driver.widen_db_type("double", &Cell::Float(1.5)), None);
+            assert_eq!(driver.widen_db_type("bigint", &Cell::Number(1)), None);
+        }
+
+        #[tokio::test]
+        async fn test_foreign_key_is_a_table_constraint() {
+            let mut info = lazy_driver().default_table_info();
+            info.columns.insert(
+                "parent_pkey".to_string(),
+                MQTableColumnInfo {
+                    column_name: "parent_pkey".to_string(),
+                    data_type: "BIGINT".to_string(),
+                    modifier: Modifier::References("orders".to_string()),
+                    ..Default::default()
+                },
+            );
+            let sql = mysql_create_table_sql(&MQTable::from_topic("orders__items"), &info);
+            assert_eq!(sql.matches("REFERENCES").count(), 1, "{sql}");
+            assert!(
+                sql.ends_with(", FOREIGN KEY (`parent_pkey`) REFERENCES `orders` (`pkey`))"),
+                "{sql}"
+            );
+        }
+
+        /// Runs against the server at `MYSQL_TEST_URL` and passes without one
+        #[tokio::test]
+        async fn test_child_table_references_parent() {
+            let Ok(url) = std::env::var("MYSQL_TEST_URL") else {
+                return;
+            };
+            let driver = MySqlDriver::connect(&url).await.unwrap();
+            let pool = driver.pool.clone();
+            sqlx::query("DROP TABLE IF EXISTS fk_orders__items, fk_orders")
+                .execute(&pool)
+                .await
+                .unwrap();
+
+            let mut manager = Manager::new(driver);
+            let options = MappingOptions {
+                explode: vec!["items".to_string()],
+                ..Default::default()
+            };
+            let rows =
+                vec![json_to_data_row_with(r#"{"items": [1]}"#, Utc::now(), &options).unwrap()];
+            manager
+                .insert_many(&MQTable::from_topic("fk_orders"), &rows)
+                .await
+                .unwrap();
+
+            let references: String = sqlx::query_scalar(
+                "SELECT CAST(REFERENCED_TABLE_NAME AS CHAR) FROM INFORMATION_SCHEMA.KEY_COLUMN_USAGE \
+                 WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'fk_orders__items' \
+                 AND COLUMN_NAME = 'parent_pkey'",
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            assert_eq!(references, "fk_orders");
+
+            let dangling = sqlx::query(
+                "INSERT INTO fk_orders__items (parent_pkey, element_index) VALUES (99, 0)",
+            )
+            .execute(&pool)
+            .await;
+            assert!(dangling.is_err());
+        }
+
+        #[tokio::test]
+        async fn test_default_table_info_has_auto_increment_key() {
+            let info = lazy_driver().default_table_info();
+            assert_eq!(
+                info.columns.get("pkey").unwrap().data_type,
+                "BIGINT AUTO_INCREMENT"
+            );
+        }
+    }
+
+    mod postgres_driver {
+        use std::collections::BTreeMap;
+
+        use itertools::Itertools;
+
+        use crate::db::{
+            Cell, DBDriver, DataRow, MQTable, PgWriteMode, PostgresDriver, DEFAULT_COPY_THRESHOLD,
+        };
+
+        /// A driver writing with `INSERT` to `name`, recreated with `columns` as BIGINT
+        async fn insert_driver(url: &str, name: &str, columns: &[String]) -> PostgresDriver {
+            let mut driver = PostgresDriver::connect(url).await.unwrap();
+            driver.set_write_mode(PgWriteMode::Insert, 0);
+            sqlx::query(&format!("DROP TABLE IF EXISTS {}", name))
+                .execute(&driver.pool)
+                .await
+                .unwrap();
+            sqlx::query(&format!(
+                "CREATE TABLE {} ({})",
+                name,
+                columns.iter().map(|c| format!("{} BIGINT", c)).join(", ")
+            ))
+            .execute(&driver.pool)
+            .await
+            .unwrap();
+            driver
+        }
+
+        fn lazy_driver() -> PostgresDriver {
+            PostgresDriver {
+                pool: sqlx::Pool::connect_lazy("postgres://localhost/test").unwrap(),
+                write_mode: PgWriteMode::default(),
+                copy_threshold: DEFAULT_COPY_THRESHOLD,
+                column_types: Default::default(),
+            }
+        }
+
+        #[tokio::test]
+        async fn test_widen_db_type() {
+            let driver = lazy_driver();
+            assert_eq!(
+                driver.widen_db_type("bigint", &Cell::Float(1.5)),
+                Some("DOUBLE PRECISION".to_string())
+            );
+            assert_eq!(
+                driver.widen_db_type("double precision", &Cell::Decimal(1.into())),
+                Some("NUMERIC".to_string())
+            );
+            assert_eq!(driver.widen_db_type("numeric", &Cell::Float(1.5)), None);
+            assert_eq!(driver.widen_db_type("text", &Cell::Float(1.5)), None);
+        }
+
+        fn row(cells: &[(&str, i64)]) -> DataRow {
+            DataRow {
+                cells: cells
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), Cell::Number(*v)))
+                    .collect::<BTreeMap<_, _>>(),
+                ..Default::default()
+            }
+        }
+
+        /// Runs against the server at `PG_TEST_URL` and passes without one
+        #[tokio::test]
+        async fn test_batch_past_the_bind_parameter_limit() {
+            let Ok(url) = std::env::var("PG_TEST_URL") else {
+                return;
+            };
+            let columns: Vec<String> = (0..10).map(|i| format!("c{}", i)).collect();
+            let driver = insert_driver(&url, "wide_batch", &columns).await;
+
+            // 70,000 binds
+            let rows: Vec<DataRow> = (0..7_000)
+                .map(|i| {
+                    let cells: Vec<(&str, i64)> = columns
+                        .iter()
+                        .enumerate()
+                        .map(|(c, name)| (name.as_str(), i * 10 + c as i64))
+                        .collect();
+                    row(&cells)
+                })
+                .collect();
+            driver
+                .insert_many(&rows, &MQTable::from_topic("wide_batch"))
+                .await
+                .unwrap();
+
+            let (count, sum): (i64, i64) =
+                sqlx::query_as("SELECT COUNT(*), SUM(c0 + c9)::BIGINT FROM wide_batch")
+                    .fetch_one(&driver.pool)
+                    .await
+                    .unwrap();
+            assert_eq!(count, 7_000);
+            // c0 + c9 is 20i + 9
+            assert_eq!(sum, 20 * (6_999 * 7_000 / 2) + 9 * 7_000);
+            let last: i64 = sqlx::query_scalar("SELECT c9 FROM wide_batch WHERE c0 = 69990")
+                .fetch_one(&driver.pool)
+                .await
+                .unwrap();
+            assert_eq!(last, 69_999);
+        }
+
+        /// Runs against the server at `PG_TEST_URL` and passes without one
+        #[tokio::test]
+        async fn test_batch_with_mixed_column_sets() {
+            let Ok(url) = std::env::var("PG_TEST_URL") else {
+                return;
+            };
+            let columns: Vec<String> = ["id", "a", "b", "c"].map(String::from).to_vec();
+            let driver = insert_driver(&url, "mixed_batch", &columns).await;
+
+            let rows = vec![
+                row(&[("id", 1), ("a", 10), ("b", 20), ("c", 30)]),
+                row(&[("id", 2), ("c", 31)]),
+                row(&[("id", 3), ("a", 12), ("b", 22), ("c", 32)]),
+                row(&[("id", 4), ("b", 23)]),
+                row(&[("id", 5), ("c", 34)]),
+            ];
+            driver
+                .insert_many(&rows, &MQTable::from_topic("mixed_batch"))
+                .await
+                .unwrap();
+
+            type Stored = (i64, Option<i64>, Option<i64>, Option<i64>);
+            let stored: Vec<Stored> =
+                sqlx::query_as("SELECT id, a, b, c FROM mixed_batch ORDER BY id")
+                    .fetch_all(&driver.pool)
+                    .await
+                    .unwrap();
+            assert_eq!(
+                stored,
+                vec![
+                    (1, Some(10), Some(20), Some(30)),
+                    (2, None, None, Some(31)),
+                    (3, Some(12), Some(22), Some(32)),
+                    (4, None, Some(23), None),
+                    (5, None, None, Some(34)),
+                ]
+            );
+        }
+    }
+
+    mod widen_in_manager {
+        use std::{
+            collections::{BTreeMap, HashMap},
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc, Mutex,
+            },
+        };
+
+        use chrono::Utc;
+
+        use crate::{
+            db::{
+                Cell, DBDriver, DataRow, MQTable, MQTableColumnInfo, MQTableInfo, MySqlDriver,
+                PgWriteMode, PostgresDriver, DEFAULT_COPY_THRESHOLD,
+            },
+            dead_letter::DeadLetter,
+            manager::Manager,
+        };
+
+        /// Keeps tables in memory and records every type change, the column types come from
+        /// `inner` so the manager sees exactly what it would against that backend
+        struct Recording<D> {
+            inner: D,
+            tables: Mutex<HashMap<MQTable, MQTableInfo>>,
+            altered: Arc<Mutex<Vec<(String, String)>>>,
+            /// How many of the next type changes fail
+            failing_alters: Arc<AtomicUsize>,
+        }
+
+        impl<D: DBDriver + Send + Sync> DBDriver for Recording<D> {
+            async fn connect(_: &str) -> anyhow::Result<impl DBDriver> {
+                Err::<Self, _>(anyhow::anyhow!("Recording drivers are built with new"))
+            }
+
+            async fn execute_query(&self, _: &str) -> anyhow::Result<String> {
+                Ok(String::new())
+            }
+
+            async fn insert_one(&self, _: DataRow, _: &MQTable) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            async fn insert_many(&self, _: &[DataRow], _: &MQTable) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            async fn get_table_info(&self, table: &MQTable) -> anyhow::Result<MQTableInfo> {
+                let tables = self.tables.lock().unwrap();
+                Ok(tables.get(table).cloned().unwrap_or_default())
+            }
+
+            async fn get_table_registry(&self) -> anyhow::Result<HashMap<String, MQTable>> {
+                Ok(HashMap::new())
+            }
+
+            async fn register_table(&self, _: &str, _: &MQTable) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            async fn create_dead_letter_table(&self) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            async fn insert_dead_letters(&self, _: &[DeadLetter]) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            async fn get_dead_letters(&self) -> anyhow::Result<Vec<DeadLetter>> {
+                Ok(vec![])
+            }
+
+            async fn delete_dead_letter(&self, _: i64) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            async fn add_column_to_table(
+                &self,
+                table: &MQTable,
+                column: &MQTableColumnInfo,
+            ) -> anyhow::Result<()> {
+                let mut tables = self.tables.lock().unwrap();
+                let info = tables.entry(table.clone()).or_default();
+                info.columns
+                    .insert(column.column_name.clone(), column.clone());
+                Ok(())
+            }
+
+            async fn create_table_if_not_exists(
+                &self,
+                table: &MQTable,
+                info: &MQTableInfo,
+            ) -> anyhow::Result<()> {
+                let mut tables = self.tables.lock().unwrap();
+                tables.entry(table.clone()).or_insert_with(|| info.clone());
+                Ok(())
+            }
+
+            async fn alter_column_type(
+                &self,
+                table: &MQTable,
+                column: &MQTableColumnInfo,
+            ) -> anyhow::Result<()> {
+                self.altered
+                    .lock()
+                    .unwrap()
+                    .push((column.column_name.clone(), column.data_type.clone()));
+                let failing = self.failing_alters.load(Ordering::SeqCst);
+                if failing > 0 {
+                    self.failing_alters.store(failing - 1, Ordering::SeqCst);
+                    anyhow::bail!("lock timeout");
+                }
+                self.add_column_to_table(table, column).await
+            }
+
+            fn convert_to_db_type_string(&self, cell: &Cell) -> String {
+                self.inner.convert_to_db_type_string(cell)
+            }
+
+            fn widen_db_type(&self, data_type: &str, cell: &Cell) -> Option<String> {
+                self.inner.widen_db_type(data_type, cell)
+            }
+
+            fn default_table_info(&self) -> MQTableInfo {
+                self.inner.default_table_info()
+            }
+        }
+
+        fn row(cells: &[(&str, Cell)]) -> DataRow {
+            DataRow {
+                cells: cells
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect::<BTreeMap<_, _>>(),
+                ..Default::default()
+            }
+        }
+
+        /// Creates every column from one row, then sends a row that doesn't fit them
+        async fn altered_after<D: DBDriver + Send + Sync>(
+            inner: D,
+            created: &[(&str, Cell)],
+            widened: &[(&str, Cell)],
+        ) -> Vec<(String, String)> {
+            let altered = Arc::new(Mutex::new(vec![]));
+            let mut manager = Manager::new(Recording {
+                inner,
+                tables: Default::default(),
+                altered: altered.clone(),
+                failing_alters: Default::default(),
+            });
+            let table = MQTable::from_topic("widen");
+            manager.insert_many(&table, &[row(created)]).await.unwrap();
+            manager.insert_many(&table, &[row(widened)]).await.unwrap();
+            let mut altered = altered.lock().unwrap().clone();
+            altered.sort();
+            altered
+        }
+
+        fn text(s: &str) -> Cell {
+            Cell::String(s.to_string())
+        }
+
+        fn altered(columns: &[(&str, &str)]) -> Vec<(String, String)> {
+            columns
+                .iter()
+                .map(|(c, t)| (c.to_string(), t.to_string()))
+                .collect()
+        }
+
+        #[tokio::test]
+        async fn test_failed_alter_is_retried() {
+            let recorded = Arc::new(Mutex::new(vec![]));
+            let failing_alters = Arc::new(AtomicUsize::new(0));
+            let mut manager = Manager::new(Recording {
+                inner: PostgresDriver {
+                    pool: sqlx::Pool::connect_lazy("postgres://localhost/test").unwrap(),
+                    write_mode: PgWriteMode::default(),
+                    copy_threshold: DEFAULT_COPY_THRESHOLD,
+                    column_types: Default::default(),
+                },
+                tables: Default::default(),
+                altered: recorded.clone(),
+                failing_alters: failing_alters.clone(),
+            });
+            let table = MQTable::from_topic("widen");
+            manager
+                .insert_many(&table, &[row(&[("n", Cell::Number(1))])])
+                .await
+                .unwrap();
+
+            failing_alters.store(1, Ordering::SeqCst);
+            let float = [row(&[("n", Cell::Float(1.5))])];
+            assert!(manager.insert_many(&table, &float).await.is_err());
+            manager.insert_many(&table, &float).await.unwrap();
+            assert_eq!(
+                *recorded.lock().unwrap(),
+                altered(&[("n", "DOUBLE PRECISION"), ("n", "DOUBLE PRECISION")])
+            );
+        }
+
+        #[tokio::test]
+        async fn test_postgres_widens_columns_it_created() {
+            let driver = PostgresDriver {
+                pool: sqlx::Pool::connect_lazy("postgres://localhost/test").unwrap(),
+                write_mode: PgWriteMode::default(),
+                copy_threshold: DEFAULT_COPY_THRESHOLD,
+                column_types: Default::default(),
+            };
+            let now = Utc::now();
+            let created = [
+                ("n", Cell::Number(1)),
+                ("f", Cell::Float(1.5)),
+                ("ts", Cell::DateTime(now.naive_utc())),
+                ("tstz", Cell::DateTimeTz(now)),
+                ("d", Cell::Decimal(1.into())),
+            ];
+            let widened = [
+                ("n", Cell::Float(1.5)),
+                ("f", Cell::Decimal(1.into())),
+                ("ts", text("yesterday")),
+                ("tstz", text("yesterday")),
+                ("d", text("NaN-ish")),
+            ];
+            assert_eq!(
+                altered_after(driver, &created, &widened).await,
+                altered(&[
+                    ("d", "TEXT"),
+                    ("f", "NUMERIC"),
+                    ("n", "DOUBLE PRECISION"),
+                    ("ts", "TEXT"),
+                    ("tstz", "TEXT"),
+                ])
+            );
+        }
+
+        #[tokio::test]
+        async fn test_mysql_widens_columns_it_created() {
+            let driver = MySqlDriver {
+                pool: sqlx::Pool::connect_lazy("mysql://root@localhost/test").unwrap(),
+            };
+            let now = Utc::now();
+            let created = [
+                ("n", Cell::Number(1)),
+                ("f", Cell::Float(1.5)),
+                ("ts", Cell::DateTimeTz(now)),
+                ("d", Cell::Decimal(1.into())),
+                ("id", Cell::Uuid(uuid::Uuid::nil())),
+            ];
+            let widened = [
+                ("n", Cell::Float(1.5)),
+                ("f", Cell::Decimal(1.into())),
+                ("ts", text("yesterday")),
+                ("d", text("NaN-ish")),
+                ("id", text("not a uuid")),
+            ];
+            assert_eq!(
+                altered_after(driver, &created, &widened).await,
+                altered(&[
+                    ("d", "TEXT"),
+                    ("f", "DECIMAL(65,30)"),
+                    ("id", "TEXT"),
+                    ("n", "DOUBLE"),
+                    ("ts", "TEXT"),
+                ])
+            );
+        }
+
+        #[tokio::test]
+        async fn test_type_names_are_normalised() {
+            let pg = PostgresDriver {
+                pool: sqlx::Pool::connect_lazy("postgres://localhost/test").unwrap(),
+                write_mode: PgWriteMode::default(),
+                copy_threshold: DEFAULT_COPY_THRESHOLD,
+                column_types: Default::default(),
+            };
+            for data_type in [
+                "TIMESTAMPTZ",
+                "timestamp(3) with time zone",
+                "TIMESTAMP",
+                "timestamp without time zone",
+                "NUMERIC(10,2)",
+            ] {
+                assert_eq!(
+                    pg.widen_db_type(data_type, &text("x")),
+                    Some("TEXT".to_string()),
+                    "{data_type}"
+                );
+            }
+            assert_eq!(
+                pg.widen_db_type("INT8", &Cell::Float(1.5)),
+                Some("DOUBLE PRECISION".to_string())
+            );
+
+            let mysql = MySqlDriver {
+                pool: sqlx::Pool::connect_lazy("mysql://root@localhost/test").unwrap(),
+            };
+            for data_type in ["DATETIME(6)", "CHAR(36)", "VARCHAR(45)", "DECIMAL(65,30)"] {
+                assert_eq!(
+                    mysql.widen_db_type(data_type, &text("x")),
+                    Some("TEXT".to_string()),
+                    "{data_type}"
+                );
+            }
+            assert_eq!(mysql.widen_db_type("TEXT", &text("x")), None);
+        }
+    }
+
+    mod postgres_copy {
+        use std::collections::BTreeMap;
+
+        use bytes::BytesMut;
+        use chrono::{NaiveDate, TimeZone, Timelike, Utc};
+
+        use crate::db::{
+            copy_text, jsonb_text, put_copy_header, put_copy_row, put_copy_trailer, Cell, DBDriver,
+            DataRow, MQTable, PgWriteMode, PostgresDriver, DEFAULT_COPY_THRESHOLD,
+        };
+
+        fn row(cells: Vec<(&str, Cell)>) -> DataRow {
+            DataRow {
+                cells: cells
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect::<BTreeMap<_, _>>(),
+                ..Default::default()
+            }
+        }
+
+        fn encode(columns: &[&str], types: &[&str], row: &DataRow) -> anyhow::Result<Vec<u8>> {
+            let columns: Vec<String> = columns.iter().map(|c| c.to_string()).collect();
+            let mut buffer = BytesMut::new();
+            put_copy_row(&mut buffer, &columns, types, row)?;
+            Ok(buffer.to_vec())
+        }
+
+        #[test]
+        fn test_header_and_trailer() {
+            let mut buffer = BytesMut::new();
+            put_copy_header(&mut buffer);
+            put_copy_trailer(&mut buffer);
+            assert_eq!(
+                buffer.to_vec(),
+                b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0\xff\xff".to_vec()
+            );
+        }
+
+        #[test]
+        fn test_bigint_and_bool() {
+            let encoded = encode(
+                &["a", "b"],
+                &["bigint", "boolean"],
+                &row(vec![("a", Cell::Number(258)), ("b", Cell::Bool(true))]),
+            )
+            .unwrap();
+            assert_eq!(
+                encoded,
+                vec![0, 2, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 1, 1]
+            );
+        }
+
+        #[test]
+        fn test_null_and_missing_cells() {
+            let encoded = encode(
+                &["a", "b"],
+                &["bigint", "text"],
+                &row(vec![("a", Cell::Null)]),
+            )
+            .unwrap();
+            assert_eq!(encoded, vec![0, 2, 255, 255, 255, 255, 255, 255, 255, 255]);
+        }
+
+        #[test]
+        fn test_json_into_text_and_jsonb() {
+            let value = serde_json::json!({"a": 1});
+            let encoded = encode(
+                &["raw", "obj"],
+                &["text", "jsonb"],
+                &row(vec![
+                    ("raw", Cell::JsonObject(value.clone())),
+                    ("obj", Cell::JsonObject(value)),
+                ]),
+            )
+            .unwrap();
+            let mut expected = vec![0, 2, 0, 0, 0, 8];
+            expected.extend(br#"{"a": 1}"#);
+            expected.extend([0, 0, 0, 8, 1]);
+            expected.extend(br#"{"a":1}"#);
+            assert_eq!(encoded, expected);
+        }
+
+        #[test]
+        fn test_timestamps_from_pg_epoch() {
+            let naive = NaiveDate::from_ymd_opt(2000, 1, 1)
+                .unwrap()
+                .and_hms_micro_opt(0, 0, 0, 1)
+                .unwrap();
+            let zoned = Utc.from_utc_datetime(&naive);
+            let encoded = encode(
+                &["a", "b"],
+                &["timestamp without time zone", "timestamp with time zone"],
+                &row(vec![
+                    ("a", Cell::DateTime(naive)),
+                    ("b", Cell::DateTimeTz(zoned)),
+                ]),
+            )
+            .unwrap();
+            assert_eq!(
+                encoded,
+                vec![0, 2, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 1]
+            );
+        }
+
+        #[test]
+        fn test_timestamps_into_text() {
+            let naive = NaiveDate::from_ymd_opt(2024, 3, 1)
+                .unwrap()
+                .and_hms_micro_opt(12, 30, 5, 500_000)
+                .unwrap();
+            let whole = naive.with_nanosecond(0).unwrap();
+            assert_eq!(copy_text(&Cell::DateTime(naive)), "2024-03-01 12:30:05.5");
+            assert_eq!(
+                copy_text(&Cell::DateTimeTz(Utc.from_utc_datetime(&whole))),
+                "2024-03-01 12:30:05+00"
+            );
+        }
+
+        /// Runs against the server at `PG_TEST_URL` and passes without one
+        #[tokio::test]
+        async fn test_copy_stores_what_insert_stores() {
+            let Ok(url) = std::env::var("PG_TEST_URL") else {
+                return;
+            };
+            let mut driver = PostgresDriver::connect(&url).await.unwrap();
+            let pool = driver.pool.clone();
+            for name in ["copy_vs_insert_i", "copy_vs_insert_c"] {
+                sqlx::query(&format!("DROP TABLE IF EXISTS {}", name))
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+                sqlx::query(&format!(
+                    "CREATE TABLE {} (id BIGINT, ts TEXT, tstz TEXT, f TEXT, b TEXT, j TEXT, \
+                     d TEXT, u TEXT, ip TEXT)",
+                    name
+                ))
+                .execute(&pool)
+                .await
+                .unwrap();
+            }
+
+            let naive = NaiveDate::from_ymd_opt(2024, 3, 1)
+                .unwrap()
+                .and_hms_micro_opt(12, 30, 5, 123_400)
+                .unwrap();
+            let rows: Vec<DataRow> = [naive, naive.with_nanosecond(0).unwrap()]
+                .into_iter()
+                .enumerate()
+                .map(|(i, ts)| {
+                    row(vec![
+                        ("id", Cell::Number(i as i64)),
+                        ("ts", Cell::DateTime(ts)),
+                        ("tstz", Cell::DateTimeTz(Utc.from_utc_datetime(&ts))),
+                        ("f", Cell::Float(21.5)),
+                        ("b", Cell::Bool(i == 0)),
+                        (
+                            "j",
+                            Cell::JsonObject(serde_json::json!({"bb": [1], "a": "x"})),
+                        ),
+                        ("d", Cell::Decimal("12345.678".parse().unwrap())),
+                        ("u", Cell::Uuid(uuid::Uuid::from_u128(i as u128))),
+                        ("ip", Cell::Inet("192.168.0.1".parse().unwrap())),
+                    ])
+                })
+                .collect();
+
+            driver.set_write_mode(PgWriteMode::Insert, DEFAULT_COPY_THRESHOLD);
+            driver
+                .insert_many(&rows, &MQTable::from_topic("copy_vs_insert_i"))
+                .await
+                .unwrap();
+            driver.set_write_mode(PgWriteMode::Copy, DEFAULT_COPY_THRESHOLD);
+            driver
+                .insert_many(&rows, &MQTable::from_topic("copy_vs_insert_c"))
+                .await
+                .unwrap();
+
+            let mut tables = Vec::new();
+            for name in ["copy_vs_insert_i", "copy_vs_insert_c"] {
+                let rows: Vec<Vec<String>> = sqlx::query_scalar(&format!(
+                    "SELECT ARRAY[ts, tstz, f, b, j, d, u, ip] FROM {} ORDER BY id",
+                    name
+                ))
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+                tables.push(rows);
+            }
+            assert_eq!(tables[0][0][0], "2024-03-01 12:30:05.1234");
+            assert_eq!(tables[0][1][1], "2024-03-01 12:30:05+00");
+            assert_eq!(tables[0], tables[1]);
+        }
+
+        #[test]
+        fn test_jsonb_text_key_order() {
+            let value = serde_json::json!({"bb": [1, {"z": "\"q\""}], "a": null, "ab": true});
+            assert_eq!(
+                jsonb_text(&value),
+                r#"{"a": null, "ab": true, "bb": [1, {"z": "\"q\""}]}"#
+            );
+        }
+
+        #[test]
+        fn test_double_precision() {
+            let encoded = encode(
+                &["a", "b"],
+                &["double precision", "double precision"],
+                &row(vec![("a", Cell::Float(1.5)), ("b", Cell::Number(2))]),
+            )
+            .unwrap();
+            let mut expected = vec![0, 2, 0, 0, 0, 8];
+            expected.extend(1.5f64.to_be_bytes());
+            expected.extend([0, 0, 0, 8]);
+            expected.extend(2f64.to_be_bytes());
+            assert_eq!(encoded, expected);
+        }
+
+        #[test]
+        fn test_numeric() {
+            let encoded = encode(
+                &["a"],
+                &["numeric"],
+                &row(vec![("a", Cell::Decimal("12345.678".parse().unwrap()))]),
+            )
+            .unwrap();
+            // ndigits 3, weight 1, positive, dscale 3, digits 1 2345 6780
+            assert_eq!(
+                encoded,
+                vec![0, 1, 0, 0, 0, 14, 0, 3, 0, 1, 0, 0, 0, 3, 0, 1, 9, 41, 26, 124]
+            );
+        }
+
+        #[test]
+        fn test_type_mismatch_is_an_error() {
+            let result = encode(
+                &["a"],
+                &["bigint"],
+                &row(vec![("a", Cell::String("x".to_string()))]),
+            );
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn test_integer_overflow_is_an_error() {
+            let result = encode(
+                &["a"],
+                &["integer"],
+                &row(vec![("a", Cell::Number(i64::MAX))]),
+            );
+            assert!(result.is_err());
+        }
+
+        #[tokio::test]
+        async fn test_write_mode_selection() {
+            let mut driver = PostgresDriver {
+                pool: sqlx::Pool::connect_lazy("postgres://localhost/test").unwrap(),
+                write_mode: PgWriteMode::default(),
+                copy_threshold: DEFAULT_COPY_THRESHOLD,
+                column_types: Default::default(),
+            };
+            assert!(!driver.uses_copy(499));
+            assert!(driver.uses_copy(500));
+
+            driver.set_write_mode(PgWriteMode::Insert, 500);
+            assert!(!driver.uses_copy(10_000));
+
+            driver.set_write_mode(PgWriteMode::Copy, 500);
+            assert!(driver.uses_copy(1));
+
+            driver.set_write_mode(PgWriteMode::Auto, 10);
+            assert!(driver.uses_copy(10));
+            assert_eq!(driver.convert_to_db_type_string(&Cell::Number(1)), "BIGINT");
+        }
+    }
 }
@@ -0,0 +1,379 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    db::{DBDriver, DataRow},
+    manager::Manager,
+    mapper::{json_to_data_row_with, MapperConfig, MappingOptions},
+    router::Router,
+    supervisor::is_transient,
+    utils::topic_matches_filter,
+};
+
+/// Why a message could not become a row, it is the message's fault and retrying it as it is
+/// won't help
+#[derive(Debug)]
+pub enum MessageError {
+    InvalidUtf8(std::string::FromUtf8Error),
+    /// not JSON or not an object
+    InvalidPayload(anyhow::Error),
+    /// the database refused its row, e.g. a value out of range for its column
+    Rejected(anyhow::Error),
+}
+
+impl MessageError {
+    /// Stored in the `error_kind` column
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MessageError::InvalidUtf8(_) => "invalid_utf8",
+            MessageError::InvalidPayload(_) => "invalid_payload",
+            MessageError::Rejected(_) => "rejected",
+        }
+    }
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::InvalidUtf8(e) => write!(f, "{}", e),
+            MessageError::InvalidPayload(e) | MessageError::Rejected(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+/// Row of a payload, without the MQTT properties and the captures of its route
+pub fn to_data_row(
+    payload: &[u8],
+    timestamp: DateTime<Utc>,
+    options: &MappingOptions,
+) -> Result<DataRow, MessageError> {
+    let json = String::from_utf8(payload.to_vec()).map_err(MessageError::InvalidUtf8)?;
+    json_to_data_row_with(&json, timestamp, options).map_err(MessageError::InvalidPayload)
+}
+
+/// Message kept in [DEAD_LETTER_TABLE](crate::db::DEAD_LETTER_TABLE) because it could not be
+/// mapped, until it is re-driven with [redrive]
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct DeadLetter {
+    /// assigned by the backend, ignored on insert
+    pub id: i64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub error_kind: String,
+    pub error_message: String,
+    pub received_ts: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    pub fn new(
+        topic: String,
+        payload: Vec<u8>,
+        received_ts: DateTime<Utc>,
+        error: &MessageError,
+    ) -> Self {
+        Self {
+            id: 0,
+            topic,
+            payload,
+            error_kind: error.kind().to_string(),
+            error_message: error.to_string(),
+            received_ts,
+        }
+    }
+}
+
+impl std::fmt::Display for DeadLetter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}: {} {:?}",
+            self.id,
+            self.received_ts.to_rfc3339(),
+            self.topic,
+            self.error_kind,
+            self.error_message,
+            String::from_utf8_lossy(&self.payload)
+        )
+    }
+}
+
+/// `mqtt-sql-connector dead-letters list|redrive [FILTER]`, `FILTER` is an MQTT topic filter
+/// and selects every dead letter when left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeadLetterCommand {
+    List { filter: String },
+    Redrive { filter: String },
+}
+
+impl DeadLetterCommand {
+    /// `None` when there are no arguments and the connector should run
+    pub fn from_args(args: &[String]) -> anyhow::Result<Option<Self>> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let (command, filter) = match args.as_slice() {
+            [] => return Ok(None),
+            ["dead-letters", command] => (*command, "#"),
+            ["dead-letters", command, filter] => (*command, *filter),
+            _ => anyhow::bail!("Usage: mqtt-sql-connector [dead-letters list|redrive [FILTER]]"),
+        };
+        if !rumqttc::valid_filter(filter) {
+            anyhow::bail!("Invalid topic filter {:?}", filter);
+        }
+        let filter = filter.to_string();
+        match command {
+            "list" => Ok(Some(Self::List { filter })),
+            "redrive" => Ok(Some(Self::Redrive { filter })),
+            _ => anyhow::bail!(
+                "Unknown dead-letters command {}, use list or redrive",
+                command
+            ),
+        }
+    }
+}
+
+/// Dead letters of the topics `filter` matches, oldest first
+pub async fn list<T: DBDriver + Send + Sync>(
+    manager: &mut Manager<T>,
+    filter: &str,
+) -> anyhow::Result<Vec<DeadLetter>> {
+    Ok(manager
+        .dead_letters()
+        .await?
+        .into_iter()
+        .filter(|letter| topic_matches_filter(filter, &letter.topic))
+        .collect())
+}
+
+/// Outcome of [redrive]
+#[derive(Debug, Default)]
+pub struct Redriven {
+    /// dead letters stored in their tables and removed
+    pub written: usize,
+    /// dead letters that still fail and stay, with why
+    pub failed: Vec<(DeadLetter, MessageError)>,
+}
+
+/// Maps the dead letters `filter` matches again with the current config and writes them to
+/// their tables, each one is removed once its row is stored. A dead letter that can't be
+/// mapped or whose row is rejected stays. Transient database errors stop the redrive.
+///
+/// MQTT 5 properties are not kept with a dead letter, so their columns stay empty.
+pub async fn redrive<T: DBDriver + Send + Sync>(
+    manager: &mut Manager<T>,
+    router: &Router,
+    mapper_config: &MapperConfig,
+    filter: &str,
+) -> anyhow::Result<Redriven> {
+    let mut redriven = Redriven::default();
+    for letter in list(manager, filter).await? {
+        let options = mapper_config.options_for(&letter.topic);
+        let mut row = match to_data_row(&letter.payload, letter.received_ts, options) {
+            Ok(row) => row,
+            Err(e) => {
+                redriven.failed.push((letter, e));
+                continue;
+            }
+        };
+        let route = router.route(&letter.topic);
+        let table = match route.table {
+            Some(name) => manager.resolve_routed_table(name).await?,
+            None => manager.resolve_table(&letter.topic).await?,
+        };
+        route.merge_into(&mut row);
+        match manager.insert(&table, row).await {
+            Ok(()) => {}
+            Err(e) if is_transient(&e) => return Err(e),
+            Err(e) => {
+                // the failed statement may have left the cache ahead of the database
+                manager.clear_cache();
+                redriven.failed.push((letter, MessageError::Rejected(e)));
+                continue;
+            }
+        }
+        // not one transaction, a crash in between leaves the row and its dead letter
+        manager.remove_dead_letter(letter.id).await?;
+        redriven.written += 1;
+    }
+    Ok(redriven)
+}
+
+#[cfg(test)]
+mod tests {
+    mod to_data_row {
+        use chrono::Utc;
+
+        use crate::{dead_letter::to_data_row, mapper::MappingOptions};
+
+        fn kind(payload: &[u8]) -> &'static str {
+            to_data_row(payload, Utc::now(), &MappingOptions::default())
+                .unwrap_err()
+                .kind()
+        }
+
+        #[test]
+        fn test_error_kinds() {
+            assert_eq!(kind(b"\xff\xfe"), "invalid_utf8");
+            assert_eq!(kind(b"not json"), "invalid_payload");
+            assert_eq!(kind(b"[1, 2]"), "invalid_payload");
+            assert!(to_data_row(b"{\"a\": 1}", Utc::now(), &MappingOptions::default()).is_ok());
+        }
+    }
+
+    mod redrive {
+        use chrono::Utc;
+        use sqlx::Row;
+
+        use crate::{
+            db::{DBDriver, SqliteDriver},
+            dead_letter::{list, redrive, to_data_row, DeadLetter},
+            manager::Manager,
+            mapper::{MapperConfig, MappingOptions},
+            router::Router,
+        };
+
+        fn dead_letter(topic: &str, payload: &[u8]) -> DeadLetter {
+            let error = to_data_row(b"[]", Utc::now(), &MappingOptions::default()).unwrap_err();
+            DeadLetter::new(topic.to_string(), payload.to_vec(), Utc::now(), &error)
+        }
+
+        async fn manager_with(
+            letters: &[DeadLetter],
+        ) -> (tempfile::TempDir, sqlx::SqlitePool, Manager<SqliteDriver>) {
+            let dir = tempfile::tempdir().unwrap();
+            let url = format!("sqlite://{}", dir.path().join("test.db").display());
+            let driver = SqliteDriver::connect(&url).await.unwrap();
+            let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+            let mut manager = Manager::new(driver);
+            manager.dead_letter(letters).await.unwrap();
+            (dir, pool, manager)
+        }
+
+        fn topics(letters: &[DeadLetter]) -> Vec<&str> {
+            letters.iter().map(|l| l.topic.as_str()).collect()
+        }
+
+        #[tokio::test]
+        async fn test_list_filters_by_topic() {
+            let (_dir, _pool, mut manager) = manager_with(&[
+                dead_letter("a/b", b"x"),
+                dead_letter("c", b"y"),
+                dead_letter("a/d", b"z"),
+            ])
+            .await;
+            assert_eq!(
+                topics(&list(&mut manager, "a/+").await.unwrap()),
+                ["a/b", "a/d"]
+            );
+            assert_eq!(
+                topics(&list(&mut manager, "#").await.unwrap()),
+                ["a/b", "c", "a/d"]
+            );
+            assert!(list(&mut manager, "e").await.unwrap().is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_writes_fixed_and_keeps_failing() {
+            let (_dir, pool, mut manager) = manager_with(&[
+                dead_letter("a/b", br#"{"x": 1}"#),
+                dead_letter("a/b", b"\xff"),
+                dead_letter("c", br#"{"x": 2}"#),
+            ])
+            .await;
+
+            let redriven = redrive(
+                &mut manager,
+                &Router::default(),
+                &MapperConfig::default(),
+                "a/#",
+            )
+            .await
+            .unwrap();
+            assert_eq!(redriven.written, 1);
+            let failed: Vec<_> = redriven
+                .failed
+                .iter()
+                .map(|(letter, e)| (letter.payload.as_slice(), e.kind()))
+                .collect();
+            assert_eq!(failed, [(&b"\xff"[..], "invalid_utf8")]);
+
+            let table = manager.resolve_table("a/b").await.unwrap();
+            let fetched = sqlx::query(&format!("SELECT x FROM \"{}\"", table.name))
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            let values: Vec<i64> = fetched.iter().map(|r| r.get("x")).collect();
+            assert_eq!(values, [1]);
+
+            assert_eq!(
+                topics(&list(&mut manager, "#").await.unwrap()),
+                ["a/b", "c"]
+            );
+        }
+
+        #[tokio::test]
+        async fn test_rejected_row_stays() {
+            let (_dir, pool, mut manager) = manager_with(&[dead_letter("a", br#"{"x": 1}"#)]).await;
+            let table = manager.resolve_table("a").await.unwrap();
+            manager.initialize(&table).await.unwrap();
+            sqlx::query(&format!(
+                "CREATE TRIGGER reject BEFORE INSERT ON \"{}\" BEGIN SELECT RAISE(ABORT, 'no'); END",
+                table.name
+            ))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+            let redriven = redrive(
+                &mut manager,
+                &Router::default(),
+                &MapperConfig::default(),
+                "#",
+            )
+            .await
+            .unwrap();
+            assert_eq!(redriven.written, 0);
+            assert_eq!(redriven.failed.len(), 1);
+            assert_eq!(redriven.failed[0].1.kind(), "rejected");
+            assert_eq!(list(&mut manager, "#").await.unwrap().len(), 1);
+        }
+    }
+
+    mod dead_letter_command {
+        use crate::dead_letter::DeadLetterCommand;
+
+        fn parse(args: &[&str]) -> anyhow::Result<Option<DeadLetterCommand>> {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            DeadLetterCommand::from_args(&args)
+        }
+
+        #[test]
+        fn test_commands() {
+            assert_eq!(parse(&[]).unwrap(), None);
+            assert_eq!(
+                parse(&["dead-letters", "list"]).unwrap(),
+                Some(DeadLetterCommand::List {
+                    filter: "#".to_string()
+                })
+            );
+            assert_eq!(
+                parse(&["dead-letters", "redrive", "sites/+/telemetry"]).unwrap(),
+                Some(DeadLetterCommand::Redrive {
+                    filter: "sites/+/telemetry".to_string()
+                })
+            );
+        }
+
+        #[test]
+        fn test_rejects_bad_args() {
+            for args in [
+                &["list"][..],
+                &["dead-letters"],
+                &["dead-letters", "purge"],
+                &["dead-letters", "list", "a/#/b"],
+                &["dead-letters", "list", "a", "b"],
+            ] {
+                assert!(parse(args).is_err(), "{args:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+/// How long a health check may take to send its request before the connection is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State of the writer as last reported by [crate::supervisor::supervise]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriterHealth {
+    Starting,
+    Running {
+        /// after transient errors since startup
+        restarts: u32,
+    },
+    /// waiting to start again after a transient error
+    Restarting {
+        restarts: u32,
+        error: String,
+    },
+    Failed {
+        error: String,
+    },
+    /// the queue was closed and everything in it stored
+    Stopped,
+}
+
+impl WriterHealth {
+    /// Whether messages are being stored, a restarting writer is not
+    pub fn is_healthy(&self) -> bool {
+        matches!(
+            self,
+            WriterHealth::Starting | WriterHealth::Running { .. } | WriterHealth::Stopped
+        )
+    }
+}
+
+impl std::fmt::Display for WriterHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriterHealth::Starting => write!(f, "writer starting"),
+            WriterHealth::Running { restarts } => {
+                write!(f, "writer running, {} restarts", restarts)
+            }
+            WriterHealth::Restarting { restarts, error } => {
+                write!(f, "writer restarting, {} restarts: {}", restarts, error)
+            }
+            WriterHealth::Failed { error } => write!(f, "writer failed: {}", error),
+            WriterHealth::Stopped => write!(f, "writer stopped"),
+        }
+    }
+}
+
+/// Answers every connection with the writer health as a plain HTTP response, 200 while it is
+/// healthy and 503 otherwise, for liveness probes and load balancer checks at `HEALTH_ADDR`
+pub async fn serve(listener: TcpListener, health: watch::Receiver<WriterHealth>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let health = health.borrow().clone();
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, &health).await {
+                        println!("Health check response failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => println!("Accepting a health check failed: {}", e),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, health: &WriterHealth) -> std::io::Result<()> {
+    // the request doesn't matter, it is read so the client doesn't see a reset
+    let mut request = [0; 1024];
+    let _ = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut request))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    let status = if health.is_healthy() {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let body = format!("{}\n", health);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    mod serve {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+            sync::watch,
+        };
+
+        use crate::health::{serve, WriterHealth};
+
+        async fn get(addr: std::net::SocketAddr) -> String {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /health HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+
+        #[tokio::test]
+        async fn test_reports_current_health() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (health, health_rx) = watch::channel(WriterHealth::Running { restarts: 0 });
+            tokio::spawn(serve(listener, health_rx));
+
+            let response = get(addr).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+            assert!(response.ends_with("\r\n\r\nwriter running, 0 restarts\n"));
+
+            health.send_replace(WriterHealth::Restarting {
+                restarts: 1,
+                error: "pool timed out".to_string(),
+            });
+            let response = get(addr).await;
+            assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+            assert!(response.ends_with("writer restarting, 1 restarts: pool timed out\n"));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_silent_client_is_dropped() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (_health, health_rx) = watch::channel(WriterHealth::Running { restarts: 0 });
+            tokio::spawn(serve(listener, health_rx));
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert_eq!(response, "");
+        }
+    }
+}
@@ -1,181 +1,721 @@
+pub mod batcher;
 pub mod db;
+pub mod dead_letter;
+pub mod health;
 pub mod manager;
 pub mod mapper;
+pub mod mqtt;
+pub mod parquet_driver;
+pub mod queue;
+pub mod reconnect;
+pub mod router;
+pub mod subscription;
+pub mod supervisor;
 pub mod utils;
-use bytes::Bytes;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+pub mod writer;
+use rumqttc::{v5, AsyncClient, MqttOptions, Transport};
 use serde::Deserialize;
-use serde_humantime;
-use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::time::Duration;
 
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::spawn;
+use tokio::sync::watch;
+use tokio::task::JoinError;
 use tokio::{self, sync::mpsc};
 
 use crate::{
-    db::{DBDriver, MQTable, PostgresDriver},
+    batcher::{BatchLimits, Batcher},
+    db::{AnyDriver, DBDriver, PgWriteMode, DEFAULT_COPY_THRESHOLD},
+    dead_letter::{redrive, DeadLetterCommand},
+    health::WriterHealth,
     manager::Manager,
-    mapper::json_to_data_row,
+    mapper::MapperConfig,
+    mqtt::{
+        credentials, AckOrder, MessagePayload, MqttClient, MqttEvent, MqttEventLoop, MqttProtocol,
+        PendingAck, Secret, TlsFiles,
+    },
+    queue::{DropCounts, OverflowPolicy},
+    reconnect::{Backoff, Broker, Brokers},
+    router::Router,
+    subscription::SubscriptionConfig,
+    supervisor::supervise,
+    writer::Writer,
 };
 
-use chrono::prelude::*;
-
-pub struct MessagePayload {
-    pub topic: String,
-    pub payload: Bytes,
-    pub timestamp: DateTime<Utc>,
-}
-
-impl From<(String, Bytes, DateTime<Utc>)> for MessagePayload {
-    fn from(value: (String, Bytes, DateTime<Utc>)) -> Self {
-        Self {
-            topic: value.0,
-            payload: value.1,
-            timestamp: value.2,
-        }
-    }
-}
-
 #[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize)]
 pub struct Config {
     mqtt_id: String,
     mqtt_host: String,
-    #[serde(flatten)]
+    // not flattened, envy hands flattened fields over as strings and e.g. a u16 fails
+    #[serde(skip)]
     inner: DefaultConfig,
 }
 
 impl Config {
-    pub fn to_mqtt_options(&self) -> MqttOptions {
-        MqttOptions::new(
-            self.mqtt_id.clone(),
-            self.mqtt_host.clone(),
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            inner: envy::from_env()?,
+            ..envy::from_env()?
+        })
+    }
+
+    fn validate_session(&self) -> anyhow::Result<()> {
+        // the broker keys a persistent session by client id, so it has to stay the same
+        // across restarts
+        if !self.inner.mqtt_clean_session && self.mqtt_id.trim().is_empty() {
+            anyhow::bail!("MQTT_CLEAN_SESSION=false needs a non-empty MQTT_ID");
+        }
+        if self.inner.mqtt_inflight == 0 {
+            anyhow::bail!("MQTT_INFLIGHT must be at least 1");
+        }
+        Ok(())
+    }
+
+    fn tls_files(&self) -> TlsFiles {
+        TlsFiles {
+            ca_file: self.inner.mqtt_ca_file.clone(),
+            client_cert_file: self.inner.mqtt_client_cert_file.clone(),
+            client_key_file: self.inner.mqtt_client_key_file.clone(),
+            alpn: self.inner.mqtt_alpn.clone(),
+        }
+    }
+
+    fn to_transport(&self) -> anyhow::Result<Transport> {
+        let tls_files = self.tls_files();
+        if !self.inner.mqtt_tls {
+            if tls_files != TlsFiles::default() {
+                anyhow::bail!("MQTT_CA_FILE, MQTT_CLIENT_*_FILE and MQTT_ALPN need MQTT_TLS=true");
+            }
+            return Ok(Transport::tcp());
+        }
+        Ok(Transport::tls_with_config(
+            tls_files.to_tls_configuration()?,
+        ))
+    }
+
+    fn to_credentials(&self) -> anyhow::Result<Option<(String, String)>> {
+        credentials(
+            self.inner.mqtt_username.as_deref(),
+            self.inner.mqtt_password.as_ref(),
+            self.inner.mqtt_password_file.as_deref(),
+        )
+    }
+
+    pub fn brokers(&self) -> anyhow::Result<Brokers> {
+        let primary = Broker {
+            host: self.mqtt_host.clone(),
+            port: self.inner.mqtt_port,
+        };
+        Brokers::new(
+            primary,
+            self.inner.mqtt_fallback_hosts.as_deref(),
             self.inner.mqtt_port,
         )
     }
+
+    pub fn batch_limits(&self) -> BatchLimits {
+        BatchLimits {
+            max_count: self.inner.batch_count,
+            max_bytes: self.inner.batch_max_bytes,
+            flush_interval: self.inner.batch_flush_interval,
+        }
+    }
+
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(self.inner.mqtt_reconnect_min, self.inner.mqtt_reconnect_max)
+    }
+
+    pub fn writer_backoff(&self) -> Backoff {
+        Backoff::new(self.inner.writer_restart_min, self.inner.writer_restart_max)
+    }
+
+    /// qos 1 and 2 messages have to be acked by hand once they are committed.
+    ///
+    /// MQTT 3.1.1 has no way to tell the broker how many unacked messages it may send, the
+    /// inflight limit set here only covers publishes of the connector and the broker's own
+    /// setting, e.g. `max_inflight_messages` in mosquitto, applies to incoming ones.
+    pub fn to_mqtt_options(&self, broker: &Broker) -> anyhow::Result<MqttOptions> {
+        self.validate_session()?;
+        let mut options = MqttOptions::new(self.mqtt_id.clone(), broker.host.clone(), broker.port);
+        options.set_manual_acks(true);
+        options
+            .set_clean_session(self.inner.mqtt_clean_session)
+            .set_inflight(self.inner.mqtt_inflight)
+            .set_transport(self.to_transport()?);
+        if let Some((username, password)) = self.to_credentials()? {
+            options.set_credentials(username, password);
+        }
+        Ok(options)
+    }
+
+    pub fn to_mqtt_v5_options(&self, broker: &Broker) -> anyhow::Result<v5::MqttOptions> {
+        self.validate_session()?;
+        let mut options =
+            v5::MqttOptions::new(self.mqtt_id.clone(), broker.host.clone(), broker.port);
+        options.set_manual_acks(true);
+        // in MQTT 5 a session ends with the connection unless it has an expiry
+        let session_expiry = match self.inner.mqtt_session_expiry {
+            None if !self.inner.mqtt_clean_session => Some(u32::MAX),
+            expiry => expiry,
+        };
+        options
+            .set_clean_start(self.inner.mqtt_clean_session)
+            .set_session_expiry_interval(session_expiry)
+            // the broker sends at most this many qos 1/2 messages before they are acked
+            .set_receive_maximum(Some(self.inner.mqtt_inflight))
+            .set_transport(self.to_transport()?);
+        if let Some((username, password)) = self.to_credentials()? {
+            options.set_credentials(username, password);
+        }
+        Ok(options)
+    }
+
+    /// Client of the configured protocol, it connects on the first poll of the eventloop
+    pub fn connect(&self, broker: &Broker) -> anyhow::Result<(MqttClient, MqttEventLoop)> {
+        let capacity = self.inner.mqtt_eventloop_capacity;
+        Ok(match self.inner.mqtt_protocol {
+            MqttProtocol::V4 => {
+                let (client, eventloop) = AsyncClient::new(self.to_mqtt_options(broker)?, capacity);
+                (MqttClient::V4(client), MqttEventLoop::V4(eventloop))
+            }
+            MqttProtocol::V5 => {
+                let (client, eventloop) =
+                    v5::AsyncClient::new(self.to_mqtt_v5_options(broker)?, capacity);
+                (MqttClient::V5(client), MqttEventLoop::V5(eventloop))
+            }
+        })
+    }
+
+    /// Points the next connection attempt of `eventloop` at `broker`, the client and any
+    /// requests it queued stay as they are
+    pub fn switch_broker(
+        &self,
+        eventloop: &mut MqttEventLoop,
+        broker: &Broker,
+    ) -> anyhow::Result<()> {
+        match eventloop {
+            MqttEventLoop::V4(eventloop) => {
+                eventloop.mqtt_options = self.to_mqtt_options(broker)?
+            }
+            MqttEventLoop::V5(eventloop) => eventloop.options = self.to_mqtt_v5_options(broker)?,
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize)]
 #[serde(default)]
 pub struct DefaultConfig {
     batch_count: usize,
+    /// payload and topic bytes after which a batch is written even if it has fewer than
+    /// `batch_count` messages
+    batch_max_bytes: usize,
+    /// how long the first message of a batch waits for more before it is written
+    #[serde(with = "serde_humantime")]
+    batch_flush_interval: Duration,
+    /// messages received but not yet taken by the writer
+    queue_capacity: usize,
+    /// what happens to messages arriving while the queue is full
+    queue_overflow: OverflowPolicy,
+    /// how often queue depth and dropped messages are logged, 0s turns it off
+    #[serde(with = "serde_humantime")]
+    queue_report_interval: Duration,
     mqtt_eventloop_capacity: usize,
     mqtt_port: u16,
+    /// comma separated `host` or `host:port`, tried in turn when the connection fails
+    mqtt_fallback_hosts: Option<String>,
+    /// first delay before reconnecting, it doubles with every failed attempt up to
+    /// `mqtt_reconnect_max`
+    #[serde(with = "serde_humantime")]
+    mqtt_reconnect_min: Duration,
+    #[serde(with = "serde_humantime")]
+    mqtt_reconnect_max: Duration,
+    mqtt_protocol: MqttProtocol,
+    /// with `false` the broker keeps subscriptions and queued qos 1/2 messages while the
+    /// connector is away
+    mqtt_clean_session: bool,
+    /// MQTT 5: received qos 1/2 messages awaiting their ack, sent to the broker as receive
+    /// maximum. MQTT 3.1.1 can't ask for it, the broker's inflight setting applies.
+    mqtt_inflight: u16,
+    /// MQTT 5 only, seconds the broker keeps the session after a disconnect. Unset it is
+    /// kept for good unless the session is clean.
+    mqtt_session_expiry: Option<u32>,
+    /// brokers usually take TLS on port 8883
+    mqtt_tls: bool,
+    mqtt_ca_file: Option<PathBuf>,
+    mqtt_client_cert_file: Option<PathBuf>,
+    mqtt_client_key_file: Option<PathBuf>,
+    mqtt_alpn: Option<String>,
+    mqtt_username: Option<String>,
+    mqtt_password: Option<Secret>,
+    /// for secrets mounted as files, instead of `mqtt_password`
+    mqtt_password_file: Option<PathBuf>,
     #[serde(with = "serde_humantime")]
     mqtt_keepalive: Duration,
+    /// first delay before the writer is restarted after a transient database error, it
+    /// doubles with every failure in a row up to `writer_restart_max`
+    #[serde(with = "serde_humantime")]
+    writer_restart_min: Duration,
+    #[serde(with = "serde_humantime")]
+    writer_restart_max: Duration,
+    /// `host:port` answering HTTP requests with the health of the writer
+    health_addr: Option<String>,
+    /// how long a shutdown waits for the writer to store what it has received
+    #[serde(with = "serde_humantime")]
+    shutdown_timeout: Duration,
+    pg_write_mode: PgWriteMode,
+    pg_copy_threshold: usize,
 }
 
 impl Default for DefaultConfig {
     fn default() -> Self {
         Self {
             batch_count: 100,
+            batch_max_bytes: 4 * 1024 * 1024,
+            batch_flush_interval: Duration::ZERO,
+            queue_capacity: 10_000,
+            queue_overflow: OverflowPolicy::default(),
+            queue_report_interval: Duration::from_secs(60),
             mqtt_eventloop_capacity: 100,
             mqtt_port: 1883,
+            mqtt_fallback_hosts: None,
+            mqtt_reconnect_min: Duration::from_millis(500),
+            mqtt_reconnect_max: Duration::from_secs(30),
+            mqtt_protocol: MqttProtocol::default(),
+            mqtt_clean_session: true,
+            mqtt_inflight: 100,
+            mqtt_session_expiry: None,
+            mqtt_tls: false,
+            mqtt_ca_file: None,
+            mqtt_client_cert_file: None,
+            mqtt_client_key_file: None,
+            mqtt_alpn: None,
+            mqtt_username: None,
+            mqtt_password: None,
+            mqtt_password_file: None,
             mqtt_keepalive: Duration::from_secs(5),
+            writer_restart_min: Duration::from_secs(1),
+            writer_restart_max: Duration::from_secs(60),
+            health_addr: None,
+            shutdown_timeout: Duration::from_secs(30),
+            pg_write_mode: PgWriteMode::default(),
+            pg_copy_threshold: DEFAULT_COPY_THRESHOLD,
         }
     }
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     let result = do_main().await;
 
     if let Err(e) = result {
-        println!("Error: {}", e);
+        println!("Error: {:#}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Hands the client the subscribe, if `subscribe` is set, and then the released acks in
+/// order until its request channel is full. What doesn't fit waits for the eventloop to be
+/// polled.
+fn flush_requests(
+    client: &MqttClient,
+    subscriptions: &SubscriptionConfig,
+    subscribe: &mut bool,
+    acks: &mut AckOrder,
+) {
+    if *subscribe && client.try_subscribe(subscriptions) {
+        *subscribe = false;
+    }
+    while let Some(ack) = acks.peek() {
+        if !client.try_ack(ack) {
+            break;
+        }
+        acks.pop();
+    }
+}
+
+/// Why the writer stopped while the poll loop was still running, from what its supervisor
+/// returned
+fn writer_failure(out: Result<anyhow::Result<()>, JoinError>) -> anyhow::Error {
+    match out {
+        Ok(Err(e)) => e,
+        Ok(Ok(())) => anyhow::anyhow!("Writer stopped while the connector was running"),
+        Err(e) => e.into(),
     }
 }
 
+/// Resolves with the name of the first SIGINT or SIGTERM
+async fn shutdown_signal() -> anyhow::Result<&'static str> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    Ok(tokio::select! {
+        ctrl_c = tokio::signal::ctrl_c() => ctrl_c.map(|_| "SIGINT")?,
+        _ = sigterm.recv() => "SIGTERM",
+    })
+}
+
+/// `dead-letters list|redrive`, only the database and mapping settings are needed
+async fn run_dead_letter_command(command: DeadLetterCommand) -> anyhow::Result<()> {
+    let driver = AnyDriver::connect(dotenvy::var("DATABASE_URL")?.as_str()).await?;
+    let mut manager = Manager::new(driver);
+    match command {
+        DeadLetterCommand::List { filter } => {
+            let letters = dead_letter::list(&mut manager, &filter).await?;
+            for letter in letters.iter() {
+                println!("{}", letter);
+            }
+            println!("{} dead letters", letters.len());
+        }
+        DeadLetterCommand::Redrive { filter } => {
+            let router = Router::from_env()?;
+            let mapper_config = MapperConfig::from_env()?;
+            let redriven = redrive(&mut manager, &router, &mapper_config, &filter).await?;
+            for (letter, e) in redriven.failed.iter() {
+                println!("Dead letter {} still fails: {}", letter.id, e);
+            }
+            println!(
+                "Re-drove {} dead letters, {} still fail",
+                redriven.written,
+                redriven.failed.len()
+            );
+        }
+    }
+    Ok(())
+}
+
 async fn do_main() -> anyhow::Result<()> {
     dotenvy::dotenv_override()?;
-    let configs: Config = envy::from_env()?;
-
-    println!("Running with configs \n{configs:#?}");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(command) = DeadLetterCommand::from_args(&args)? {
+        return run_dead_letter_command(command).await;
+    }
 
-    let mqttoptions = configs.to_mqtt_options();
+    let configs = Config::from_env()?;
 
-    let topic_name = dotenvy::var("TOPIC_NAME")?;
+    println!("Running with configs \n{configs:#?}");
 
-    let (client, mut eventloop) =
-        AsyncClient::new(mqttoptions, configs.inner.mqtt_eventloop_capacity);
-    client
-        .subscribe(topic_name.as_str(), QoS::AtMostOnce)
-        .await?;
+    let subscriptions = SubscriptionConfig::from_env()?;
+    subscriptions.check_protocol(configs.inner.mqtt_protocol)?;
+    println!("Subscribing to {:#?}", subscriptions.subscriptions);
 
-    let mut manager =
-        Manager::new(PostgresDriver::connect(dotenvy::var("DATABASE_URL")?.as_str()).await?);
+    let mut brokers = configs.brokers()?;
+    let mut backoff = configs.backoff();
+    let (client, mut eventloop) = configs.connect(brokers.current())?;
 
-    manager
-        .initialize(&MQTable::from_topic(topic_name.as_str()))
-        .await?;
+    let mut driver = AnyDriver::connect(dotenvy::var("DATABASE_URL")?.as_str()).await?;
+    if let AnyDriver::Postgres(pg) = &mut driver {
+        pg.set_write_mode(configs.inner.pg_write_mode, configs.inner.pg_copy_threshold);
+    }
 
-    println!("Manager initialized");
+    let mapper_config = MapperConfig::from_env()?;
+    let router = Router::from_env()?;
 
-    let (tx, rx) = mpsc::unbounded_channel::<MessagePayload>();
+    let mut manager = Manager::new(driver);
 
-    // let handle =
-    spawn(async move {
-        let out = {
-            let mut rx = rx;
+    // fail at startup rather than on the first message if a routed table is taken
+    for name in router.tables() {
+        manager.resolve_routed_table(name).await?;
+    }
 
-            let mut buffer = vec![];
+    for sub in subscriptions.subscriptions.iter() {
+        if !sub.is_concrete() {
+            // tables of topics not seen yet are created once their messages arrive
+            manager.initialize_matching(&sub.filter).await?;
+            continue;
+        }
+        let table = match router.route(&sub.filter).table {
+            Some(name) => manager.resolve_routed_table(name).await?,
+            None => manager.resolve_table(&sub.filter).await?,
+        };
+        manager.initialize(&table).await?;
+    }
 
-            let mut map: HashMap<MQTable, Vec<_>> = HashMap::new();
+    println!("Manager initialized");
 
-            loop {
-                let msgs = rx.recv_many(&mut buffer, configs.inner.batch_count).await;
-                if msgs == 0 {
-                    // channel is closed go home
-                    break;
+    let (tx, rx) = queue::channel(configs.inner.queue_capacity, configs.inner.queue_overflow);
+    let batcher = Batcher::new(rx, configs.batch_limits(), MessagePayload::size);
+
+    // the writer never waits on the request channel of the client: with a full queue the
+    // poll loop waits on the writer and nobody would drain it. The poll loop sends the acks.
+    let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<PendingAck>();
+    let mut acks = AckOrder::default();
+    let mut drops = DropCounts::default();
+    let (health, health_rx) = watch::channel(WriterHealth::Starting);
+    if let Some(addr) = &configs.inner.health_addr {
+        let listener = TcpListener::bind(addr).await?;
+        println!("Serving writer health on {}", addr);
+        spawn(health::serve(listener, health_rx));
+    }
+    let writer = Writer::new(batcher, manager, router, mapper_config, ack_tx);
+    let mut supervisor = spawn(supervise(
+        writer,
+        configs.writer_backoff(),
+        health,
+        Writer::run,
+    ));
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    let report_interval = configs.inner.queue_report_interval;
+    let mut report = tokio::time::interval(report_interval.max(Duration::from_millis(1)));
+    report.tick().await;
+
+    let mut connected = false;
+    let mut subscribe = false;
+    // the broker of the session acks belong to
+    let mut session_broker = None;
+    println!("Connecting to MQTT broker {}", brokers.current());
+    'poll: loop {
+        // requests made while disconnected would wait in the channel and go out on a
+        // connection they may not belong to
+        if connected {
+            flush_requests(&client, &subscriptions, &mut subscribe, &mut acks);
+        }
+        let event = tokio::select! {
+            signal = &mut shutdown => {
+                println!("Received {}, shutting down", signal?);
+                break;
+            }
+            Some(ack) = ack_rx.recv() => {
+                acks.done(ack);
+                continue;
+            }
+            // the supervisor only returns early on a fatal error, polling on would fill a
+            // queue nobody reads
+            out = &mut supervisor => return Err(writer_failure(out)),
+            _ = report.tick(), if !report_interval.is_zero() => {
+                println!("Queue depth {}/{}, {}", tx.len(), tx.capacity(), drops);
+                continue;
+            }
+            event = eventloop.poll() => event,
+        };
+        let event = match event {
+            Ok(event) => event,
+            // the writer task and its batch are untouched, messages just stop coming in
+            Err(e) => {
+                connected = false;
+                println!("MQTT connection to {} failed: {}", brokers.current(), e);
+                let delay = backoff.next_delay();
+                let broker = brokers.advance();
+                configs.switch_broker(&mut eventloop, broker)?;
+                println!("Reconnecting to MQTT broker {} in {:?}", broker, delay);
+                tokio::select! {
+                    signal = &mut shutdown => {
+                        println!("Received {}, shutting down", signal?);
+                        break;
+                    }
+                    _ = tokio::time::sleep(delay) => continue,
+                }
+            }
+        };
+        match event {
+            MqttEvent::Publish(mut msg) => {
+                if msg.to_ack().is_some() {
+                    (msg.generation, msg.seq) = acks.arrived();
                 }
+                let send = tx.send(msg);
+                tokio::pin!(send);
+                // with the block policy this is where the broker is held back
+                let dropped = loop {
+                    tokio::select! {
+                        dropped = &mut send => match dropped {
+                            Ok(dropped) => break dropped,
+                            // the writer and the queue it read from are gone
+                            Err(_) => return Err(writer_failure((&mut supervisor).await)),
+                        },
+                        Some(ack) = ack_rx.recv() => acks.done(ack),
+                        signal = &mut shutdown => {
+                            // not acked, the broker sends it again
+                            println!("Received {}, shutting down", signal?);
+                            break 'poll;
+                        }
+                    }
+                };
+                // a dropped message is acked like a stored one, otherwise it would keep its
+                // slot in the broker's inflight window until the next session. The ack waits
+                // for the messages still queued ahead of it.
+                if let Some(msg) = dropped {
+                    if drops.total() == 0 {
+                        println!("Queue full, dropping messages from {}", msg.topic);
+                    }
+                    drops.record(&msg.topic);
+                    if let Some(ack) = msg.to_ack() {
+                        acks.done(ack);
+                    }
+                }
+            }
+            MqttEvent::ConnAck { session_present } => {
+                connected = true;
+                backoff.reset();
+                println!(
+                    "Connected to MQTT broker {}, session present: {}",
+                    brokers.current(),
+                    session_present
+                );
+                // packet ids of another broker's session don't mean anything here
+                let resumed = session_present && session_broker.as_ref() == Some(brokers.current());
+                session_broker = Some(brokers.current().clone());
+                acks.connected(resumed);
+                // a resumed session is subscribed again too, the broker may not have kept every
+                // subscription. At worst retained messages come again, see `retain_handling`.
+                subscribe = true;
+            }
+            MqttEvent::Skipped(Some(mut ack)) => {
+                (ack.generation, ack.seq) = acks.arrived();
+                acks.done(ack);
+            }
+            MqttEvent::Skipped(None) => {}
+            MqttEvent::Disconnected | MqttEvent::Other => {}
+        }
+    }
 
-                for MessagePayload {
-                    topic,
-                    payload,
-                    timestamp,
-                } in buffer.drain(..)
-                {
-                    let table = MQTable::from_topic(&topic);
+    // the writer stores what is left in the queue and stops once it is empty
+    drop(tx);
+    let deadline = tokio::time::sleep(configs.inner.shutdown_timeout);
+    tokio::pin!(deadline);
+    let written = loop {
+        if connected {
+            flush_requests(&client, &subscriptions, &mut subscribe, &mut acks);
+        }
+        tokio::select! {
+            written = &mut supervisor => break written?,
+            _ = &mut deadline => anyhow::bail!(
+                "Pending messages were not written within {:?}",
+                configs.inner.shutdown_timeout
+            ),
+            Some(ack) = ack_rx.recv() => acks.done(ack),
+            // keeps sending the acks of the last batches, messages arriving now are not
+            // acked and the broker sends them again
+            event = eventloop.poll(), if connected => {
+                if let Err(e) = event {
                     println!(
-                        "Received on topic {} - {} at {}: {:?}",
-                        topic, table.name, timestamp, payload
+                        "MQTT connection to {} lost while shutting down: {}",
+                        brokers.current(),
+                        e
                     );
-                    let obj =
-                        json_to_data_row(String::from_utf8(payload.to_vec())?.as_str(), timestamp)?;
-
-                    map.entry(table).or_default().push(obj);
+                    connected = false;
                 }
-
-                for (key, value) in map.drain() {
-                    let err = manager.insert_many(&key, &value).await;
-                    if err.is_err() {
-                        println!("{:?}", err);
-                    }
-                    err?;
+            }
+        }
+    };
+    written?;
+    println!("Pending messages written, {}", drops);
+
+    if connected {
+        // requested once every ack is in the request channel, so it goes out after them
+        let disconnect = client.disconnect();
+        tokio::pin!(disconnect);
+        let mut disconnecting = false;
+        loop {
+            while let Ok(ack) = ack_rx.try_recv() {
+                acks.done(ack);
+            }
+            flush_requests(&client, &subscriptions, &mut subscribe, &mut acks);
+            tokio::select! {
+                requested = &mut disconnect, if !disconnecting && acks.peek().is_none() => {
+                    requested?;
+                    disconnecting = true;
+                }
+                event = eventloop.poll() => match event {
+                    Ok(MqttEvent::Disconnected) | Err(_) => break,
+                    Ok(_) => {}
+                },
+                _ = &mut deadline => {
+                    println!("MQTT disconnect did not finish in time");
+                    break;
                 }
-
-                println!("Inserted into DB");
             }
+        }
+        println!("Disconnected from MQTT broker {}", brokers.current());
+    }
 
-            anyhow::Ok(())
-        };
-        if out.is_err() {
-            println!("{:?}", out);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    mod config {
+        use crate::{reconnect::Broker, Config, DefaultConfig};
+
+        fn config() -> Config {
+            Config {
+                mqtt_id: "connector".to_string(),
+                mqtt_host: "localhost".to_string(),
+                inner: DefaultConfig {
+                    mqtt_inflight: 7,
+                    ..Default::default()
+                },
+            }
         }
-        out
-    });
 
-    loop {
-        let notification = eventloop.poll().await?;
-        println!("Notification: {:?}", notification);
-        match notification {
-            rumqttc::Event::Incoming(rumqttc::Packet::Publish(p)) => {
-                tx.send((p.topic, p.payload, Utc::now()).into())?;
+        fn broker() -> Broker {
+            Broker {
+                host: "localhost".to_string(),
+                port: 1883,
             }
-            _ => {}
+        }
+
+        #[test]
+        fn test_inflight_is_the_v5_receive_maximum() {
+            let options = config().to_mqtt_v5_options(&broker()).unwrap();
+            assert_eq!(options.receive_maximum(), Some(7));
+        }
+
+        #[test]
+        fn test_inflight_on_v4() {
+            let options = config().to_mqtt_options(&broker()).unwrap();
+            assert_eq!(options.inflight(), 7);
         }
     }
 
-    // move this to RAII drop impl
-    // drop(tx);
+    mod requests {
+        use rumqttc::{AsyncClient, MqttOptions, QoS, Request};
+
+        use crate::{
+            flush_requests,
+            mqtt::{AckOrder, MqttClient, PendingAck},
+            subscription::SubscriptionConfig,
+        };
 
-    // return handle.await?;
+        #[test]
+        fn test_subscribe_waits_for_a_full_channel() {
+            let (client, mut eventloop) =
+                AsyncClient::new(MqttOptions::new("connector", "localhost", 1883), 1);
+            let client = MqttClient::V4(client);
+            let subscriptions = SubscriptionConfig::from_list("a/#", None).unwrap();
+
+            let mut acks = AckOrder::default();
+            acks.connected(false);
+            for pkid in 1..=2 {
+                let (generation, seq) = acks.arrived();
+                acks.done(PendingAck {
+                    pkid,
+                    qos: QoS::AtLeastOnce,
+                    generation,
+                    seq,
+                });
+            }
+            // acks of the previous connection took the room
+            assert!(client.try_ack(&acks.pop().unwrap()));
+
+            let mut subscribe = true;
+            flush_requests(&client, &subscriptions, &mut subscribe, &mut acks);
+            assert!(subscribe);
+            assert!(acks.peek().is_some());
+
+            // what polling does with the channel when the connection is set up again
+            eventloop.clean();
+            flush_requests(&client, &subscriptions, &mut subscribe, &mut acks);
+            assert!(!subscribe);
+            eventloop.clean();
+            assert!(matches!(
+                eventloop.pending.pop_front(),
+                Some(Request::Subscribe(_))
+            ));
+        }
+    }
 }
@@ -1,13 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::{
-    db::{DBDriver, DataRow, MQTable, MQTableColumnInfo, MQTableInfo, Modifier},
-    utils::PreDefinedColumn,
+    db::{
+        Cell, DBDriver, DataRow, MQTable, MQTableColumnInfo, MQTableInfo, Modifier,
+        DEAD_LETTER_TABLE, TABLE_REGISTRY,
+    },
+    dead_letter::DeadLetter,
+    utils::{topic_matches_filter, PreDefinedColumn},
 };
 
 pub struct Manager<T: DBDriver + Send + Sync> {
     driver: T,
     col_cache: HashMap<MQTable, MQTableInfo>,
+    // loaded from the registry on first use
+    topic_tables: Option<HashMap<String, MQTable>>,
+    // created on first use, most deployments never have a dead letter
+    dead_letter_table_created: bool,
 }
 
 impl<T: DBDriver + Send + Sync> Manager<T> {
@@ -15,38 +23,169 @@ impl<T: DBDriver + Send + Sync> Manager<T> {
         Self {
             driver,
             col_cache: HashMap::new(),
+            topic_tables: None,
+            dead_letter_table_created: false,
         }
     }
 
+    async fn registry(&mut self) -> anyhow::Result<&mut HashMap<String, MQTable>> {
+        let topic_tables = match self.topic_tables.take() {
+            Some(topic_tables) => topic_tables,
+            None => self.driver.get_table_registry().await?,
+        };
+        Ok(self.topic_tables.insert(topic_tables))
+    }
+
+    /// Table the messages of `topic` are written to.
+    ///
+    /// The first time a topic is seen it gets the name from [MQTable::from_topic], or a hashed
+    /// one if another topic already owns that name, and the choice is recorded in the registry
+    /// so it holds across restarts.
+    pub async fn resolve_table(&mut self, topic: &str) -> anyhow::Result<MQTable> {
+        let topic_tables = self.registry().await?;
+        if let Some(table) = topic_tables.get(topic) {
+            return Ok(table.clone());
+        }
+
+        let taken: HashSet<&str> = topic_tables
+            .values()
+            .map(|t| t.name.as_str())
+            .chain([TABLE_REGISTRY, DEAD_LETTER_TABLE])
+            .collect();
+        let mut table = MQTable::from_topic(topic);
+        if taken.contains(table.name.as_str()) {
+            table = MQTable::from_topic_hashed(topic);
+        }
+        if taken.contains(table.name.as_str()) {
+            anyhow::bail!(
+                "No free table name for topic {}, {} is taken",
+                topic,
+                table.name
+            );
+        }
+
+        self.register(topic, table).await
+    }
+
+    /// Named table of a routing rule. It is recorded in the registry under a key no published
+    /// topic can have, so topics without a rule can't take the name, and it is an error if a
+    /// topic already owns it.
+    pub async fn resolve_routed_table(&mut self, name: &str) -> anyhow::Result<MQTable> {
+        let table = MQTable::from_topic(name);
+        // wildcards are not allowed in the topic of a publish
+        let key = format!("#{}", table.name);
+
+        let topic_tables = self.registry().await?;
+        if topic_tables.get(&key) == Some(&table) {
+            return Ok(table);
+        }
+        if let Some((topic, _)) = topic_tables.iter().find(|(_, t)| **t == table) {
+            anyhow::bail!(
+                "Routed table {} is already used by topic {}",
+                table.name,
+                topic
+            );
+        }
+        if table.name == TABLE_REGISTRY || table.name == DEAD_LETTER_TABLE {
+            anyhow::bail!("Routed table {} is reserved", table.name);
+        }
+
+        self.register(&key, table).await
+    }
+
+    /// Child table of `parent` for the exploded array `field`, see [MQTable::child]. Like a
+    /// routed table it is recorded under a key no published topic can have, so topics can't
+    /// take its name later, and it is an error if a topic or a routing rule already owns it.
+    async fn resolve_child_table(
+        &mut self,
+        parent: &MQTable,
+        field: &str,
+    ) -> anyhow::Result<MQTable> {
+        let table = parent.child(field);
+        let key = format!("+{}", table.name);
+
+        let topic_tables = self.registry().await?;
+        if topic_tables.get(&key) == Some(&table) {
+            return Ok(table);
+        }
+        if let Some((topic, _)) = topic_tables.iter().find(|(_, t)| **t == table) {
+            anyhow::bail!("Child table {} is already used by {}", table.name, topic);
+        }
+        if table.name == TABLE_REGISTRY || table.name == DEAD_LETTER_TABLE {
+            anyhow::bail!("Child table {} is reserved", table.name);
+        }
+
+        self.register(&key, table).await
+    }
+
+    async fn register(&mut self, key: &str, table: MQTable) -> anyhow::Result<MQTable> {
+        if let Err(e) = self.driver.register_table(key, &table).await {
+            // another connector may have registered the key in the meantime
+            let registry = self.driver.get_table_registry().await?;
+            let registered = registry.get(key).cloned();
+            self.topic_tables = Some(registry);
+            return registered.ok_or(e);
+        }
+        self.registry()
+            .await?
+            .insert(key.to_string(), table.clone());
+
+        Ok(table)
+    }
+
+    /// Initializes the registered tables of the topics `filter` matches, tables of topics that
+    /// arrive later are created when their first message does
+    pub async fn initialize_matching(&mut self, filter: &str) -> anyhow::Result<()> {
+        let tables: Vec<MQTable> = self
+            .registry()
+            .await?
+            .iter()
+            // child tables are created with their parent pointer by their parent's first row
+            .filter(|(topic, _)| !topic.starts_with('+') && topic_matches_filter(filter, topic))
+            .map(|(_, table)| table.clone())
+            .collect();
+        for table in tables {
+            self.initialize(&table).await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets what is known about the tables and the registry, both are read again on
+    /// next use
+    pub fn clear_cache(&mut self) {
+        self.col_cache.clear();
+        self.topic_tables = None;
+        self.dead_letter_table_created = false;
+    }
+
     pub async fn initialize(&mut self, table: &MQTable) -> anyhow::Result<()> {
+        let col_info = self.driver.default_table_info();
+        self.initialize_with(table, col_info).await
+    }
+
+    /// Like [Self::initialize] for the child table of an exploded array, with a column
+    /// pointing at the parent row
+    async fn initialize_child(&mut self, parent: &MQTable, table: &MQTable) -> anyhow::Result<()> {
+        let mut col_info = self.driver.default_table_info();
+        let parent_pkey = MQTableColumnInfo {
+            column_name: PreDefinedColumn::ParentPkey.to_string(),
+            data_type: self.driver.convert_to_db_type_string(&Cell::Number(0)),
+            modifier: Modifier::References(parent.name.clone()),
+            ..Default::default()
+        };
+        col_info
+            .columns
+            .insert(parent_pkey.column_name.clone(), parent_pkey);
+        self.initialize_with(table, col_info).await
+    }
+
+    async fn initialize_with(
+        &mut self,
+        table: &MQTable,
+        col_info: MQTableInfo,
+    ) -> anyhow::Result<()> {
         let mut table_info = self.driver.get_table_info(table).await?;
         if !table_info.exists() {
-            let col_info: MQTableInfo = vec![
-                MQTableColumnInfo {
-                    column_name: PreDefinedColumn::PKey.to_string(),
-                    data_type: "SERIAL".to_string(),
-                    modifier: Modifier::PrimaryKey,
-                    ..Default::default()
-                },
-                MQTableColumnInfo {
-                    column_name: PreDefinedColumn::Raw.to_string(),
-                    data_type: "TEXT".to_string(),
-                    ..Default::default()
-                },
-                MQTableColumnInfo {
-                    column_name: PreDefinedColumn::InsertTs.to_string(),
-                    data_type: "TIMESTAMP".to_string(),
-                    default_value: Some("CURRENT_TIMESTAMP AT TIME ZONE 'UTC'".into()),
-                    ..Default::default()
-                },
-                MQTableColumnInfo {
-                    column_name: PreDefinedColumn::ReceivedTs.to_string(),
-                    data_type: "TIMESTAMP".to_string(),
-                    ..Default::default()
-                },
-            ]
-            .into();
-
             self.driver
                 .create_table_if_not_exists(table, &col_info)
                 .await?;
@@ -64,24 +203,56 @@ impl<T: DBDriver + Send + Sync> Manager<T> {
             self.initialize(table).await?;
         }
         let table_info = self.col_cache.get_mut(table).unwrap();
-        Ok(for (col, val) in row.cells.iter() {
-            if !table_info.has_column(&col) {
+        for (col, val) in row.cells.iter() {
+            if let Some(existing) = table_info.columns.get_mut(col) {
+                // e.g. a BIGINT column that starts receiving floats
+                if let Some(data_type) = self.driver.widen_db_type(&existing.data_type, val) {
+                    let widened = MQTableColumnInfo {
+                        data_type,
+                        ..existing.clone()
+                    };
+                    // the cache keeps the old type if the table could not be altered
+                    self.driver.alter_column_type(table, &widened).await?;
+                    *existing = widened;
+                }
+            } else {
                 let col_info = MQTableColumnInfo {
                     column_name: col.clone(),
                     // infer data type from cell
-                    data_type: self.driver.convert_to_db_type_string(&val),
+                    data_type: self.driver.convert_to_db_type_string(val),
+                    original_name: row.original_keys.get(col).cloned(),
                     ..Default::default()
                 };
+                self.driver.add_column_to_table(table, &col_info).await?;
                 table_info
                     .columns
-                    .insert(col_info.column_name.clone(), col_info.clone());
-                self.driver.add_column_to_table(table, &col_info).await?;
+                    .insert(col_info.column_name.clone(), col_info);
             }
-        })
+        }
+        Ok(())
+    }
+
+    async fn pre_process_children(
+        &mut self,
+        table: &MQTable,
+        row: &DataRow,
+    ) -> Result<(), anyhow::Error> {
+        for (field, children) in row.children.iter() {
+            let child = table.child(field);
+            if !self.col_cache.contains_key(&child) {
+                self.resolve_child_table(table, field).await?;
+                self.initialize_child(table, &child).await?;
+            }
+            for child_row in children {
+                self.pre_process(&child, child_row).await?;
+            }
+        }
+        Ok(())
     }
 
     pub async fn insert(&mut self, table: &MQTable, row: DataRow) -> anyhow::Result<()> {
         self.pre_process(table, &row).await?;
+        self.pre_process_children(table, &row).await?;
 
         self.driver.insert_one(row, table).await
     }
@@ -89,8 +260,35 @@ impl<T: DBDriver + Send + Sync> Manager<T> {
     pub async fn insert_many(&mut self, table: &MQTable, rows: &[DataRow]) -> anyhow::Result<()> {
         for row in rows {
             self.pre_process(table, row).await?;
+            self.pre_process_children(table, row).await?;
         }
 
         self.driver.insert_many(rows, table).await
     }
+
+    async fn create_dead_letter_table(&mut self) -> anyhow::Result<()> {
+        if !self.dead_letter_table_created {
+            self.driver.create_dead_letter_table().await?;
+            self.dead_letter_table_created = true;
+        }
+        Ok(())
+    }
+
+    /// Keeps messages that could not be mapped so they can be re-driven later
+    pub async fn dead_letter(&mut self, letters: &[DeadLetter]) -> anyhow::Result<()> {
+        if letters.is_empty() {
+            return Ok(());
+        }
+        self.create_dead_letter_table().await?;
+        self.driver.insert_dead_letters(letters).await
+    }
+
+    pub async fn dead_letters(&mut self) -> anyhow::Result<Vec<DeadLetter>> {
+        self.create_dead_letter_table().await?;
+        self.driver.get_dead_letters().await
+    }
+
+    pub async fn remove_dead_letter(&mut self, id: i64) -> anyhow::Result<()> {
+        self.driver.delete_dead_letter(id).await
+    }
 }
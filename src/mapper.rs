@@ -1,47 +1,1050 @@
-use std::collections::BTreeMap;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+};
 
-use chrono::{DateTime, Utc};
-use serde_json::Value;
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Deserialize;
+use serde_json::{Number, Value};
 
 use crate::{
     db::{Cell, DataRow},
-    utils::PreDefinedColumn,
+    utils::{
+        to_identifier, to_snake_case, topic_matches_filter, with_hash_suffix, PreDefinedColumn,
+    },
 };
 
+/// Which typed cells to infer from JSON values, everything is off by default
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InferenceRules {
+    /// RFC 3339 strings become TIMESTAMPTZ, ISO 8601 strings without offset TIMESTAMP
+    pub timestamps: bool,
+    pub uuids: bool,
+    pub ip_addresses: bool,
+    /// strings holding a number become NUMERIC
+    pub numeric_strings: bool,
+    /// integer fields holding epoch millis, `*` matches any run of characters
+    pub epoch_millis_fields: Vec<String>,
+}
+
+/// How the payloads of one topic are mapped to a [DataRow]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappingOptions {
+    pub infer: InferenceRules,
+    /// nested objects become their own columns, when unset they are stored as JSONB
+    pub flatten: Option<FlattenOptions>,
+    /// array fields written to a child table with one row per element instead of a JSONB
+    /// column, `*` matches any run of characters
+    pub explode: Vec<String>,
+    /// `camelCase` keys become `snake_case` columns instead of just being lowercased
+    pub snake_case_keys: bool,
+    /// MQTT 5 properties of the message become `mqtt_*` columns, see
+    /// [MessageProperties::merge_into](crate::mqtt::MessageProperties::merge_into)
+    pub mqtt_properties: bool,
+}
+
+/// Turns `{"complex": {"id": 1}}` into the column `complex_id`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlattenOptions {
+    pub separator: String,
+    /// levels of nesting to flatten, objects below that are kept as JSONB
+    pub max_depth: usize,
+}
+
+impl Default for FlattenOptions {
+    fn default() -> Self {
+        Self {
+            separator: "_".to_string(),
+            max_depth: 8,
+        }
+    }
+}
+
+/// Mapping options read from the toml file in `MAPPER_CONFIG`.
+///
+/// ```toml
+/// [default.infer]
+/// timestamps = true
+/// epoch_millis_fields = ["*_ms"]
+///
+/// [default.flatten]
+/// separator = "__"
+/// max_depth = 2
+///
+/// [topics."sensors/+/batch"]
+/// explode = ["readings"]
+///
+/// # replaces the default for the topics matching this filter
+/// [topics."sensors/raw/#".infer]
+/// uuids = true
+/// ```
+///
+/// The keys of `topics` are topic filters. When several match a topic the most specific one
+/// applies, the one with the most literal levels, then one without `#`, then the one whose
+/// first wildcard comes later.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapperConfig {
+    pub default: MappingOptions,
+    pub topics: HashMap<String, MappingOptions>,
+}
+
+impl MapperConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// From the file in `MAPPER_CONFIG`, the defaults without it
+    pub fn from_env() -> anyhow::Result<Self> {
+        match dotenvy::var("MAPPER_CONFIG") {
+            Ok(path) => Self::from_file(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn options_for(&self, topic: &str) -> &MappingOptions {
+        self.topics
+            .iter()
+            .filter(|(filter, _)| topic_matches_filter(filter, topic))
+            // the filter itself breaks ties so the choice doesn't depend on the map's order
+            .max_by_key(|(filter, _)| (filter_specificity(filter), Reverse(filter.as_str())))
+            .map(|(_, options)| options)
+            .unwrap_or(&self.default)
+    }
+}
+
+fn filter_specificity(filter: &str) -> (usize, bool, Vec<u8>) {
+    let ranks: Vec<u8> = filter
+        .split('/')
+        .map(|level| match level {
+            "#" => 0,
+            "+" => 1,
+            _ => 2,
+        })
+        .collect();
+    let literals = ranks.iter().filter(|r| **r == 2).count();
+    (literals, !ranks.contains(&0), ranks)
+}
+
 pub fn json_to_data_row(json: &str, timestamp: DateTime<Utc>) -> anyhow::Result<DataRow> {
+    json_to_data_row_with(json, timestamp, &MappingOptions::default())
+}
+
+pub fn json_to_data_row_with(
+    json: &str,
+    timestamp: DateTime<Utc>,
+    options: &MappingOptions,
+) -> anyhow::Result<DataRow> {
     let v: Value = serde_json::from_str(json)?;
 
     let original_json = v.clone();
 
     if let Value::Object(obj) = v {
         // fix this, create a function in DataRow, to hide impl of type of map
-        let mut cells: BTreeMap<String, Cell> = obj
-            .into_iter()
-            .map(|(k, v)| (k, json_value_to_cell(v)))
-            .collect();
-
-        cells.insert(
-            PreDefinedColumn::Raw.to_string(),
-            Cell::JsonObject(original_json),
-        );
-        cells.insert(
-            PreDefinedColumn::ReceivedTs.to_string(),
-            Cell::DateTime(timestamp.naive_utc()),
-        );
-
-        Ok(DataRow { cells: cells })
+        let mut row = DataRow::default();
+        map_fields(obj, "", "", options, timestamp, true, &mut row);
+        normalize_keys(&mut row, options.snake_case_keys);
+        insert_predefined(&mut row, original_json, timestamp);
+
+        Ok(row)
     } else {
         anyhow::bail!("Not a JSON object");
     }
 }
 
+/// Column of a child row holding an array element that isn't an object
+pub const ELEMENT_VALUE_COLUMN: &str = "value";
+
+fn column_name(key: &str, snake_case: bool) -> String {
+    if snake_case {
+        to_identifier(&to_snake_case(key))
+    } else {
+        to_identifier(key)
+    }
+}
+
+/// Renames the cells and children of `row` to valid column names.
+///
+/// Keys that already are valid keep their name. A renamed key that lands on another key or a
+/// predefined column gets a hash suffix instead, so no value is overwritten.
+fn normalize_keys(row: &mut DataRow, snake_case: bool) {
+    let reserved: Vec<String> = PreDefinedColumn::ALL
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+
+    let cells = std::mem::take(&mut row.cells);
+    row.cells = rename_keys(cells, snake_case, &reserved, |name, key| {
+        row.original_keys.insert(name.to_string(), key.to_string());
+    });
+    // child table names come from these, see MQTable::child
+    let children = std::mem::take(&mut row.children);
+    row.children = rename_keys(children, snake_case, &[], |_, _| {});
+}
+
+fn rename_keys<V>(
+    entries: BTreeMap<String, V>,
+    snake_case: bool,
+    reserved: &[String],
+    mut on_rename: impl FnMut(&str, &str),
+) -> BTreeMap<String, V> {
+    let is_reserved = |name: &str| reserved.iter().any(|r| r == name);
+    let (renamed, mut valid): (BTreeMap<_, _>, BTreeMap<_, _>) = entries
+        .into_iter()
+        .partition(|(key, _)| column_name(key, snake_case) != *key || is_reserved(key));
+
+    for (key, value) in renamed {
+        let mut name = column_name(&key, snake_case);
+        if valid.contains_key(&name) || is_reserved(&name) {
+            name = with_hash_suffix(&name, &key);
+        }
+        on_rename(&name, &key);
+        valid.insert(name, value);
+    }
+    valid
+}
+
+fn insert_predefined(row: &mut DataRow, raw: Value, timestamp: DateTime<Utc>) {
+    row.cells
+        .insert(PreDefinedColumn::Raw.to_string(), Cell::JsonObject(raw));
+    row.cells.insert(
+        PreDefinedColumn::ReceivedTs.to_string(),
+        Cell::DateTime(timestamp.naive_utc()),
+    );
+}
+
+/// Maps the fields of `obj` into `row`, keys of flattened objects joined by the separator.
+///
+/// `pointer` is the JSON pointer of `obj` in the payload. The keys sent as is are mapped
+/// before the flattened ones, a flattened key that lands on a key already mapped gets a hash
+/// suffix of its pointer, so no value is overwritten. Arrays are only exploded at the top
+/// level, arrays inside elements are kept as JSONB.
+fn map_fields(
+    obj: serde_json::Map<String, Value>,
+    prefix: &str,
+    pointer: &str,
+    options: &MappingOptions,
+    timestamp: DateTime<Utc>,
+    explode: bool,
+    row: &mut DataRow,
+) {
+    // escaped keys hold no '/'
+    let depth = pointer.matches('/').count();
+    let mut nested = vec![];
+    for (k, v) in obj {
+        let key = match &options.flatten {
+            Some(flatten) if !prefix.is_empty() => format!("{prefix}{}{k}", flatten.separator),
+            _ => k.clone(),
+        };
+        let pointer = format!("{pointer}/{}", k.replace('~', "~0").replace('/', "~1"));
+        match v {
+            Value::Object(inner)
+                if options
+                    .flatten
+                    .as_ref()
+                    .is_some_and(|f| depth < f.max_depth) =>
+            {
+                nested.push((key, pointer, inner));
+            }
+            Value::Array(items)
+                if explode && options.explode.iter().any(|p| glob_match(p, &key)) =>
+            {
+                if !items.is_empty() {
+                    let children = items
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, item)| element_to_data_row(i, item, options, timestamp))
+                        .collect();
+                    row.children.insert(key, children);
+                }
+            }
+            v => {
+                let cell = infer_cell(&key, v, &options.infer);
+                let key = if !prefix.is_empty() && row.cells.contains_key(&key) {
+                    with_hash_suffix(&column_name(&key, options.snake_case_keys), &pointer)
+                } else {
+                    key
+                };
+                row.cells.insert(key, cell);
+            }
+        }
+    }
+    for (key, pointer, inner) in nested {
+        map_fields(inner, &key, &pointer, options, timestamp, explode, row);
+    }
+}
+
+fn element_to_data_row(
+    index: usize,
+    item: Value,
+    options: &MappingOptions,
+    timestamp: DateTime<Utc>,
+) -> DataRow {
+    let mut row = DataRow::default();
+    match &item {
+        Value::Object(obj) => map_fields(obj.clone(), "", "", options, timestamp, false, &mut row),
+        value => {
+            let cell = infer_cell(ELEMENT_VALUE_COLUMN, value.clone(), &options.infer);
+            row.cells.insert(ELEMENT_VALUE_COLUMN.to_string(), cell);
+        }
+    }
+    normalize_keys(&mut row, options.snake_case_keys);
+    row.cells.insert(
+        PreDefinedColumn::ElementIndex.to_string(),
+        Cell::Number(index as i64),
+    );
+    insert_predefined(&mut row, item, timestamp);
+    row
+}
+
 pub fn json_value_to_cell(value: Value) -> Cell {
     match value {
         Value::Null => Cell::Null,
         Value::Bool(b) => Cell::Bool(b),
-        Value::Number(n) => Cell::Number(n.as_i64().unwrap_or_default()),
+        Value::Number(n) => number_to_cell(&n),
         Value::String(s) => Cell::String(s),
         Value::Array(_) => Cell::JsonObject(value), // store arrays as text
         Value::Object(_) => Cell::JsonObject(value), // store objects as text
     }
 }
+
+/// Like [json_value_to_cell], but strings and epoch fields may become typed cells
+pub fn infer_cell(key: &str, value: Value, rules: &InferenceRules) -> Cell {
+    match &value {
+        Value::String(s) => infer_string(s, rules).unwrap_or_else(|| json_value_to_cell(value)),
+        Value::Number(n)
+            if rules
+                .epoch_millis_fields
+                .iter()
+                .any(|pattern| glob_match(pattern, key)) =>
+        {
+            n.as_i64()
+                .and_then(DateTime::from_timestamp_millis)
+                .map(Cell::DateTimeTz)
+                .unwrap_or_else(|| json_value_to_cell(value))
+        }
+        _ => json_value_to_cell(value),
+    }
+}
+
+fn infer_string(s: &str, rules: &InferenceRules) -> Option<Cell> {
+    if rules.timestamps {
+        if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+            return Some(Cell::DateTimeTz(dt.with_timezone(&Utc)));
+        }
+        for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+            if let Ok(dt) = NaiveDateTime::parse_from_str(s, format) {
+                return Some(Cell::DateTime(dt));
+            }
+        }
+    }
+    // only the hyphenated form, 32 bare hex digits are too likely to be something else
+    if rules.uuids && s.len() == 36 {
+        if let Ok(u) = uuid::Uuid::try_parse(s) {
+            return Some(Cell::Uuid(u));
+        }
+    }
+    if rules.ip_addresses {
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Some(Cell::Inet(ip));
+        }
+    }
+    if rules.numeric_strings && is_numeric_literal(s) {
+        if let Ok(d) = BigDecimal::from_str(s) {
+            return Some(Cell::Decimal(d));
+        }
+    }
+    None
+}
+
+/// Plain decimal notation, BigDecimal alone would also take exponents and a leading `+`
+fn is_numeric_literal(s: &str) -> bool {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    !int.is_empty()
+        && int.chars().all(|c| c.is_ascii_digit())
+        && frac.chars().all(|c| c.is_ascii_digit())
+        && !(digits.contains('.') && frac.is_empty())
+}
+
+/// Matches `name` against `pattern` where `*` matches any run of characters
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len())
+                .filter(|i| name.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+    }
+}
+
+pub fn number_to_cell(n: &Number) -> Cell {
+    if let Some(i) = n.as_i64() {
+        return Cell::Number(i);
+    }
+
+    // arbitrary_precision keeps the literal as written, so nothing is lost yet
+    let literal = n.to_string();
+    let Ok(decimal) = BigDecimal::from_str(&literal) else {
+        return Cell::Null;
+    };
+
+    let is_integer = !literal.contains(['.', 'e', 'E']);
+    match n.as_f64() {
+        Some(f)
+            if !is_integer
+                && BigDecimal::from_str(&f.to_string()).ok() == Some(decimal.clone()) =>
+        {
+            Cell::Float(f)
+        }
+        _ => Cell::Decimal(decimal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod infer_cell {
+        use std::str::FromStr;
+
+        use bigdecimal::BigDecimal;
+        use chrono::{NaiveDate, TimeZone, Utc};
+        use serde_json::Value;
+
+        use crate::{
+            db::Cell,
+            mapper::{glob_match, infer_cell, InferenceRules},
+        };
+
+        fn all_rules() -> InferenceRules {
+            InferenceRules {
+                timestamps: true,
+                uuids: true,
+                ip_addresses: true,
+                numeric_strings: true,
+                epoch_millis_fields: vec!["*_ms".to_string(), "ts".to_string()],
+            }
+        }
+
+        fn infer(key: &str, json: &str, rules: &InferenceRules) -> Cell {
+            infer_cell(key, serde_json::from_str::<Value>(json).unwrap(), rules)
+        }
+
+        #[test]
+        fn test_disabled_by_default() {
+            let rules = InferenceRules::default();
+            assert_eq!(
+                infer("a", r#""2024-01-02T03:04:05Z""#, &rules),
+                Cell::String("2024-01-02T03:04:05Z".to_string())
+            );
+            assert_eq!(
+                infer("ts", "1700000000000", &rules),
+                Cell::Number(1700000000000)
+            );
+        }
+
+        #[test]
+        fn test_rfc3339() {
+            assert_eq!(
+                infer("a", r#""2024-01-02T05:04:05+02:00""#, &all_rules()),
+                Cell::DateTimeTz(Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap())
+            );
+        }
+
+        #[test]
+        fn test_iso8601_without_offset() {
+            let expected = NaiveDate::from_ymd_opt(2024, 1, 2)
+                .unwrap()
+                .and_hms_milli_opt(3, 4, 5, 250)
+                .unwrap();
+            assert_eq!(
+                infer("a", r#""2024-01-02T03:04:05.250""#, &all_rules()),
+                Cell::DateTime(expected)
+            );
+            assert_eq!(
+                infer("a", r#""2024-01-02 03:04:05.250""#, &all_rules()),
+                Cell::DateTime(expected)
+            );
+        }
+
+        #[test]
+        fn test_epoch_millis_by_name() {
+            let expected = Cell::DateTimeTz(Utc.timestamp_millis_opt(1700000000123).unwrap());
+            assert_eq!(infer("seen_ms", "1700000000123", &all_rules()), expected);
+            assert_eq!(infer("ts", "1700000000123", &all_rules()), expected);
+            assert_eq!(
+                infer("count", "1700000000123", &all_rules()),
+                Cell::Number(1700000000123)
+            );
+        }
+
+        #[test]
+        fn test_uuid() {
+            let s = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+            assert_eq!(
+                infer("a", &format!(r#""{s}""#), &all_rules()),
+                Cell::Uuid(uuid::Uuid::parse_str(s).unwrap())
+            );
+            // bare hex is left alone
+            assert_eq!(
+                infer("a", r#""67e5504410b1426f9247bb680e5fe0c8""#, &all_rules()),
+                Cell::String("67e5504410b1426f9247bb680e5fe0c8".to_string())
+            );
+        }
+
+        #[test]
+        fn test_ip_addresses() {
+            assert_eq!(
+                infer("a", r#""10.0.0.1""#, &all_rules()),
+                Cell::Inet("10.0.0.1".parse().unwrap())
+            );
+            assert_eq!(
+                infer("a", r#""::1""#, &all_rules()),
+                Cell::Inet("::1".parse().unwrap())
+            );
+        }
+
+        #[test]
+        fn test_numeric_strings() {
+            assert_eq!(
+                infer("a", r#""-12.50""#, &all_rules()),
+                Cell::Decimal(BigDecimal::from_str("-12.50").unwrap())
+            );
+            for s in ["1e5", "+1", "1.", ".5", "abc", ""] {
+                assert_eq!(
+                    infer("a", &format!(r#""{s}""#), &all_rules()),
+                    Cell::String(s.to_string()),
+                    "{s}"
+                );
+            }
+        }
+
+        #[test]
+        fn test_rules_are_independent() {
+            let rules = InferenceRules {
+                uuids: true,
+                ..Default::default()
+            };
+            assert_eq!(
+                infer("a", r#""10.0.0.1""#, &rules),
+                Cell::String("10.0.0.1".to_string())
+            );
+            assert_eq!(
+                infer("a", r#""42""#, &rules),
+                Cell::String("42".to_string())
+            );
+        }
+
+        #[test]
+        fn test_glob_match() {
+            assert!(glob_match("*_ms", "seen_ms"));
+            assert!(glob_match("ts", "ts"));
+            assert!(glob_match("a*b*c", "axxbyyc"));
+            assert!(!glob_match("*_ms", "seen_s"));
+            assert!(!glob_match("ts", "tss"));
+        }
+    }
+
+    mod flatten {
+        use chrono::Utc;
+        use serde_json::json;
+
+        use crate::{
+            db::{Cell, DataRow},
+            mapper::{json_to_data_row_with, FlattenOptions, InferenceRules, MappingOptions},
+            utils::with_hash_suffix,
+        };
+
+        fn flatten(json: &str, flatten: FlattenOptions) -> DataRow {
+            let options = MappingOptions {
+                flatten: Some(flatten),
+                ..Default::default()
+            };
+            json_to_data_row_with(json, Utc::now(), &options).unwrap()
+        }
+
+        fn keys(row: &DataRow) -> Vec<&str> {
+            row.cells
+                .keys()
+                .map(String::as_str)
+                .filter(|k| !["raw", "received_ts"].contains(k))
+                .collect()
+        }
+
+        #[test]
+        fn test_disabled_keeps_json() {
+            let row = json_to_data_row_with(
+                r#"{"complex": {"id": 1}}"#,
+                Utc::now(),
+                &MappingOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                row.cells.get("complex"),
+                Some(&Cell::JsonObject(json!({"id": 1})))
+            );
+        }
+
+        #[test]
+        fn test_leaves_become_columns() {
+            let row = flatten(
+                r#"{"complex": {"id": 1, "name": "x", "inner": {"ok": true}}, "top": 2}"#,
+                FlattenOptions::default(),
+            );
+            assert_eq!(
+                keys(&row),
+                ["complex_id", "complex_inner_ok", "complex_name", "top"]
+            );
+            assert_eq!(row.cells.get("complex_id"), Some(&Cell::Number(1)));
+            assert_eq!(row.cells.get("complex_inner_ok"), Some(&Cell::Bool(true)));
+            // the raw payload stays untouched
+            assert_eq!(
+                row.cells.get("raw"),
+                Some(&Cell::JsonObject(
+                    json!({"complex": {"id": 1, "name": "x", "inner": {"ok": true}}, "top": 2})
+                ))
+            );
+        }
+
+        #[test]
+        fn test_separator() {
+            let row = flatten(
+                r#"{"a": {"b": 1}}"#,
+                FlattenOptions {
+                    separator: "__".to_string(),
+                    ..Default::default()
+                },
+            );
+            assert_eq!(keys(&row), ["a__b"]);
+        }
+
+        #[test]
+        fn test_max_depth_keeps_deeper_objects_as_json() {
+            let row = flatten(
+                r#"{"a": {"b": {"c": {"d": 1}}}}"#,
+                FlattenOptions {
+                    max_depth: 1,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(
+                row.cells.get("a_b"),
+                Some(&Cell::JsonObject(json!({"c": {"d": 1}})))
+            );
+
+            let row = flatten(
+                r#"{"a": {"b": 1}}"#,
+                FlattenOptions {
+                    max_depth: 0,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(row.cells.get("a"), Some(&Cell::JsonObject(json!({"b": 1}))));
+        }
+
+        #[test]
+        fn test_flattened_key_colliding_with_a_sent_one_gets_a_suffix() {
+            let row = flatten(
+                r#"{"a": {"b": 2, "c": {"d": 3}}, "a_b": 1, "a_c_d": 4}"#,
+                FlattenOptions::default(),
+            );
+            assert_eq!(row.cells.get("a_b"), Some(&Cell::Number(1)));
+            assert_eq!(row.cells.get("a_c_d"), Some(&Cell::Number(4)));
+            assert_eq!(
+                row.cells.get(&with_hash_suffix("a_b", "/a/b")),
+                Some(&Cell::Number(2))
+            );
+            assert_eq!(
+                row.cells.get(&with_hash_suffix("a_c_d", "/a/c/d")),
+                Some(&Cell::Number(3))
+            );
+            assert_eq!(keys(&row).len(), 4);
+        }
+
+        #[test]
+        fn test_arrays_and_nulls_are_leaves() {
+            let row = flatten(
+                r#"{"a": {"list": [1, 2], "none": null}}"#,
+                FlattenOptions::default(),
+            );
+            assert_eq!(
+                row.cells.get("a_list"),
+                Some(&Cell::JsonObject(json!([1, 2])))
+            );
+            assert_eq!(row.cells.get("a_none"), Some(&Cell::Null));
+        }
+
+        #[test]
+        fn test_inference_sees_flattened_key() {
+            let options = MappingOptions {
+                infer: InferenceRules {
+                    epoch_millis_fields: vec!["meta_*_ms".to_string()],
+                    ..Default::default()
+                },
+                flatten: Some(FlattenOptions::default()),
+                ..Default::default()
+            };
+            let row =
+                json_to_data_row_with(r#"{"meta": {"seen_ms": 0}}"#, Utc::now(), &options).unwrap();
+            assert!(matches!(
+                row.cells.get("meta_seen_ms"),
+                Some(Cell::DateTimeTz(_))
+            ));
+        }
+    }
+
+    mod explode {
+        use chrono::Utc;
+        use serde_json::json;
+
+        use crate::{
+            db::{Cell, DataRow},
+            mapper::{json_to_data_row_with, FlattenOptions, MappingOptions},
+        };
+
+        fn explode(json: &str, patterns: &[&str]) -> DataRow {
+            let options = MappingOptions {
+                explode: patterns.iter().map(|p| p.to_string()).collect(),
+                ..Default::default()
+            };
+            json_to_data_row_with(json, Utc::now(), &options).unwrap()
+        }
+
+        #[test]
+        fn test_scalars_become_value_rows() {
+            let row = explode(r#"{"id": 1, "readings": [1.5, 2.5]}"#, &["readings"]);
+            assert!(!row.cells.contains_key("readings"));
+            assert_eq!(row.cells.get("id"), Some(&Cell::Number(1)));
+
+            let children = &row.children["readings"];
+            assert_eq!(children.len(), 2);
+            assert_eq!(children[1].cells.get("value"), Some(&Cell::Float(2.5)));
+            assert_eq!(
+                children[1].cells.get("element_index"),
+                Some(&Cell::Number(1))
+            );
+            assert_eq!(
+                children[1].cells.get("raw"),
+                Some(&Cell::JsonObject(json!(2.5)))
+            );
+            assert!(children[1].cells.contains_key("received_ts"));
+        }
+
+        #[test]
+        fn test_objects_become_columns() {
+            let row = explode(
+                r#"{"items": [{"sku": "a", "qty": 2, "tags": ["x"]}]}"#,
+                &["*"],
+            );
+            let child = &row.children["items"][0];
+            assert_eq!(child.cells.get("sku"), Some(&Cell::String("a".to_string())));
+            assert_eq!(child.cells.get("qty"), Some(&Cell::Number(2)));
+            // only top level arrays are exploded
+            assert_eq!(
+                child.cells.get("tags"),
+                Some(&Cell::JsonObject(json!(["x"])))
+            );
+            assert!(child.children.is_empty());
+        }
+
+        #[test]
+        fn test_unmatched_arrays_stay_json() {
+            let row = explode(r#"{"items": [1], "other": [2]}"#, &["items"]);
+            assert_eq!(row.cells.get("other"), Some(&Cell::JsonObject(json!([2]))));
+            assert_eq!(row.children.keys().collect::<Vec<_>>(), ["items"]);
+        }
+
+        #[test]
+        fn test_empty_array_has_no_children() {
+            let row = explode(r#"{"items": []}"#, &["items"]);
+            assert!(!row.has_children());
+            assert!(!row.cells.contains_key("items"));
+        }
+
+        #[test]
+        fn test_flattened_arrays_use_the_joined_key() {
+            let options = MappingOptions {
+                flatten: Some(FlattenOptions::default()),
+                explode: vec!["order_items".to_string()],
+                ..Default::default()
+            };
+            let row = json_to_data_row_with(
+                r#"{"order": {"id": 7, "items": [{"sku": "a"}]}}"#,
+                Utc::now(),
+                &options,
+            )
+            .unwrap();
+            assert_eq!(row.cells.get("order_id"), Some(&Cell::Number(7)));
+            assert_eq!(row.children["order_items"].len(), 1);
+        }
+    }
+
+    mod normalize_keys {
+        use chrono::Utc;
+
+        use crate::{
+            db::{Cell, DataRow},
+            mapper::{json_to_data_row, json_to_data_row_with, MappingOptions},
+            utils::{with_hash_suffix, MAX_IDENTIFIER_LEN},
+        };
+
+        fn map(json: &str) -> DataRow {
+            json_to_data_row(json, Utc::now()).unwrap()
+        }
+
+        #[test]
+        fn test_hostile_keys_become_plain_columns() {
+            let row = map(r#"{"x; DROP TABLE y": 1, "\"Temp C\"": 2, "a`b'c": 3}"#);
+            assert_eq!(row.cells.get("x__drop_table_y"), Some(&Cell::Number(1)));
+            assert_eq!(row.cells.get("_temp_c_"), Some(&Cell::Number(2)));
+            assert_eq!(row.cells.get("a_b_c"), Some(&Cell::Number(3)));
+            assert_eq!(
+                row.original_keys.get("x__drop_table_y").map(String::as_str),
+                Some("x; DROP TABLE y")
+            );
+        }
+
+        #[test]
+        fn test_valid_keys_are_not_recorded() {
+            let row = map(r#"{"temp": 1}"#);
+            assert!(row.original_keys.is_empty());
+        }
+
+        #[test]
+        fn test_valid_key_wins_collision() {
+            let row = map(r#"{"a b": 1, "a_b": 2, "A_B": 3}"#);
+            assert_eq!(row.cells.get("a_b"), Some(&Cell::Number(2)));
+            assert_eq!(
+                row.cells.get(&with_hash_suffix("a_b", "a b")),
+                Some(&Cell::Number(1))
+            );
+            assert_eq!(
+                row.cells.get(&with_hash_suffix("a_b", "A_B")),
+                Some(&Cell::Number(3))
+            );
+        }
+
+        #[test]
+        fn test_predefined_columns_are_not_overwritten() {
+            let row = map(r#"{"raw": "mine", "PKEY": 7}"#);
+            assert!(matches!(row.cells.get("raw"), Some(Cell::JsonObject(_))));
+            assert!(!row.cells.contains_key("pkey"));
+            assert_eq!(
+                row.cells.get(&with_hash_suffix("raw", "raw")),
+                Some(&Cell::String("mine".to_string()))
+            );
+            assert_eq!(
+                row.cells.get(&with_hash_suffix("pkey", "PKEY")),
+                Some(&Cell::Number(7))
+            );
+        }
+
+        #[test]
+        fn test_long_key_is_cut() {
+            let key = "k".repeat(100);
+            let row = map(&format!(r#"{{"{key}": 1}}"#));
+            let column = row.original_keys.keys().next().unwrap();
+            assert_eq!(column.len(), MAX_IDENTIFIER_LEN);
+            assert_eq!(row.original_keys[column], key);
+        }
+
+        #[test]
+        fn test_snake_case_keys() {
+            let json = r#"{"tempCelsius": 1, "deviceID": 2}"#;
+            let row = map(json);
+            assert!(row.cells.contains_key("tempcelsius"));
+
+            let options = MappingOptions {
+                snake_case_keys: true,
+                ..Default::default()
+            };
+            let row = json_to_data_row_with(json, Utc::now(), &options).unwrap();
+            assert!(row.cells.contains_key("temp_celsius"));
+            assert!(row.cells.contains_key("device_id"));
+            assert_eq!(row.original_keys["temp_celsius"], "tempCelsius");
+        }
+
+        #[test]
+        fn test_child_keys_are_normalized() {
+            let options = MappingOptions {
+                explode: vec!["Line Items".to_string()],
+                ..Default::default()
+            };
+            let row = json_to_data_row_with(
+                r#"{"Line Items": [{"Unit Price": 2}]}"#,
+                Utc::now(),
+                &options,
+            )
+            .unwrap();
+            let child = &row.children["line_items"][0];
+            assert_eq!(child.cells.get("unit_price"), Some(&Cell::Number(2)));
+            assert_eq!(child.original_keys["unit_price"], "Unit Price");
+        }
+    }
+
+    mod mapper_config {
+        use crate::mapper::{json_to_data_row_with, MapperConfig};
+
+        const CONFIG: &str = r#"
+            [default.infer]
+            timestamps = true
+
+            [topics."sensors/raw".infer]
+            uuids = true
+
+            [topics."sensors/nested".flatten]
+            separator = "."
+
+            [topics."sensors/batch"]
+            explode = ["readings"]
+
+            [topics."sensors/v5"]
+            mqtt_properties = true
+        "#;
+
+        #[test]
+        fn test_topic_overrides_default() {
+            let config: MapperConfig = toml::from_str(CONFIG).unwrap();
+            assert!(config.options_for("sensors/other").infer.timestamps);
+            assert!(!config.options_for("sensors/raw").infer.timestamps);
+            assert!(config.options_for("sensors/raw").infer.uuids);
+            assert_eq!(config.options_for("sensors/raw").flatten, None);
+
+            let flatten = config
+                .options_for("sensors/nested")
+                .flatten
+                .as_ref()
+                .unwrap();
+            assert_eq!(flatten.separator, ".");
+            assert_eq!(flatten.max_depth, 8);
+
+            assert_eq!(config.options_for("sensors/batch").explode, ["readings"]);
+
+            assert!(config.options_for("sensors/v5").mqtt_properties);
+            assert!(!config.options_for("sensors/other").mqtt_properties);
+        }
+
+        #[test]
+        fn test_most_specific_filter_applies() {
+            let config: MapperConfig = toml::from_str(
+                r#"
+                [topics."sensors/#"]
+                explode = ["all"]
+
+                [topics."sensors/+/batch"]
+                explode = ["plus"]
+
+                [topics."sensors/hall/#"]
+                explode = ["hall"]
+
+                [topics."sensors/hall/batch"]
+                explode = ["exact"]
+                "#,
+            )
+            .unwrap();
+            let explode = |topic: &str| config.options_for(topic).explode.clone();
+            assert_eq!(explode("sensors/hall/batch"), ["exact"]);
+            // a literal level beats a wildcard in its place
+            assert_eq!(explode("sensors/hall/other"), ["hall"]);
+            assert_eq!(explode("sensors/roof/batch"), ["plus"]);
+            assert_eq!(explode("sensors/roof"), ["all"]);
+            assert!(explode("other/hall/batch").is_empty());
+        }
+
+        #[test]
+        fn test_empty_config_infers_nothing() {
+            let config: MapperConfig = toml::from_str("").unwrap();
+            let row = json_to_data_row_with(
+                r#"{"at": "2024-01-02T03:04:05Z"}"#,
+                chrono::Utc::now(),
+                config.options_for("any"),
+            )
+            .unwrap();
+            assert_eq!(
+                row.cells.get("at"),
+                Some(&crate::db::Cell::String("2024-01-02T03:04:05Z".to_string()))
+            );
+        }
+
+        #[test]
+        fn test_unknown_rule_is_rejected() {
+            assert!(toml::from_str::<MapperConfig>("[default.infer]\nguess = true").is_err());
+        }
+    }
+
+    mod number_to_cell {
+        use std::str::FromStr;
+
+        use bigdecimal::BigDecimal;
+        use serde_json::Value;
+
+        use crate::{db::Cell, mapper::json_value_to_cell};
+
+        fn cell(json: &str) -> Cell {
+            json_value_to_cell(serde_json::from_str::<Value>(json).unwrap())
+        }
+
+        fn decimal(s: &str) -> Cell {
+            Cell::Decimal(BigDecimal::from_str(s).unwrap())
+        }
+
+        #[test]
+        fn test_integer() {
+            assert_eq!(cell("42"), Cell::Number(42));
+            assert_eq!(cell("-7"), Cell::Number(-7));
+        }
+
+        #[test]
+        fn test_float() {
+            assert_eq!(cell("21.7"), Cell::Float(21.7));
+            assert_eq!(cell("-0.5"), Cell::Float(-0.5));
+        }
+
+        #[test]
+        fn test_exponent_is_float() {
+            assert_eq!(cell("1e3"), Cell::Float(1000.0));
+        }
+
+        #[test]
+        fn test_u64_above_i64_max() {
+            assert_eq!(
+                cell("18446744073709551615"),
+                decimal("18446744073709551615")
+            );
+        }
+
+        #[test]
+        fn test_integer_beyond_u64() {
+            assert_eq!(
+                cell("123456789012345678901234567890"),
+                decimal("123456789012345678901234567890")
+            );
+        }
+
+        #[test]
+        fn test_fraction_beyond_f64_precision() {
+            assert_eq!(
+                cell("0.1000000000000000000001"),
+                decimal("0.1000000000000000000001")
+            );
+        }
+
+        #[test]
+        fn test_float_in_data_row() {
+            let row =
+                crate::mapper::json_to_data_row(r#"{"temp": 21.7}"#, chrono::Utc::now()).unwrap();
+            assert_eq!(row.cells.get("temp"), Some(&Cell::Float(21.7)));
+        }
+    }
+}
@@ -0,0 +1,758 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use rumqttc::{v5, AsyncClient, EventLoop, Publish, QoS, TlsConfiguration};
+use serde::Deserialize;
+
+use crate::{
+    db::{Cell, DataRow},
+    subscription::{Subscription, SubscriptionConfig},
+};
+
+/// Protocol version spoken with the broker, `MQTT_PROTOCOL=v5` for MQTT 5
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttProtocol {
+    /// MQTT 3.1.1
+    #[default]
+    V4,
+    V5,
+}
+
+/// Config value that must not end up in logs
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// TLS settings of the broker connection, every file is PEM
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct TlsFiles {
+    /// trusted roots, the platform ones when unset
+    pub ca_file: Option<PathBuf>,
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    /// comma separated protocols, e.g. `mqtt` for brokers sharing 443
+    pub alpn: Option<String>,
+}
+
+impl TlsFiles {
+    pub fn to_tls_configuration(&self) -> anyhow::Result<TlsConfiguration> {
+        let client_auth = match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert), Some(key)) => Some((read_pem(cert)?, read_pem(key)?)),
+            (None, None) => None,
+            _ => anyhow::bail!(
+                "MQTT_CLIENT_CERT_FILE and MQTT_CLIENT_KEY_FILE have to be set together"
+            ),
+        };
+        let alpn = self.alpn.as_deref().map(|alpn| {
+            alpn.split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|p| p.as_bytes().to_vec())
+                .collect::<Vec<_>>()
+        });
+
+        let Some(ca_file) = &self.ca_file else {
+            if client_auth.is_some() || alpn.is_some() {
+                // the platform roots only come with rustls' plain client config
+                anyhow::bail!("MQTT_CLIENT_CERT_FILE and MQTT_ALPN need MQTT_CA_FILE");
+            }
+            return Ok(TlsConfiguration::default());
+        };
+        Ok(TlsConfiguration::Simple {
+            ca: read_pem(ca_file)?,
+            alpn,
+            client_auth,
+        })
+    }
+}
+
+fn read_pem(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let content = std::fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+    // rustls only complains once it connects, without saying which file
+    if !content.windows(11).any(|w| w == b"-----BEGIN ") {
+        anyhow::bail!("{} is not a PEM file", path.display());
+    }
+    Ok(content)
+}
+
+/// Username and password for the broker, the password given inline or read from a file
+pub fn credentials(
+    username: Option<&str>,
+    password: Option<&Secret>,
+    password_file: Option<&Path>,
+) -> anyhow::Result<Option<(String, String)>> {
+    let password = match (password, password_file) {
+        (Some(_), Some(_)) => {
+            anyhow::bail!("Only one of MQTT_PASSWORD and MQTT_PASSWORD_FILE can be set")
+        }
+        (Some(password), None) => Some(password.0.clone()),
+        (None, Some(path)) => {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Can't read {}", path.display()))?;
+            // secret files usually end with a newline
+            Some(content.trim_end_matches(['\r', '\n']).to_string())
+        }
+        (None, None) => None,
+    };
+    match (username, password) {
+        (Some(username), password) => {
+            Ok(Some((username.to_string(), password.unwrap_or_default())))
+        }
+        (None, Some(_)) => anyhow::bail!("MQTT_PASSWORD needs MQTT_USERNAME"),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Properties an MQTT 5 publish can carry, all empty for MQTT 3.1.1
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MessageProperties {
+    pub user_properties: Vec<(String, String)>,
+    pub content_type: Option<String>,
+    /// seconds
+    pub message_expiry_interval: Option<u32>,
+    pub correlation_data: Option<Bytes>,
+}
+
+impl MessageProperties {
+    pub const USER_PROPERTIES_COLUMN: &str = "mqtt_user_props";
+    pub const CONTENT_TYPE_COLUMN: &str = "mqtt_content_type";
+    pub const MESSAGE_EXPIRY_COLUMN: &str = "mqtt_message_expiry";
+    pub const CORRELATION_DATA_COLUMN: &str = "mqtt_correlation_data";
+
+    /// Adds the properties the message has as `mqtt_*` columns, replacing payload fields of
+    /// the same name.
+    ///
+    /// User properties become one JSON object, a key sent more than once maps to the array
+    /// of its values. Correlation data is kept as text when it is UTF-8 and hex otherwise.
+    pub fn merge_into(&self, row: &mut DataRow) {
+        let mut cells = vec![];
+        if !self.user_properties.is_empty() {
+            cells.push((
+                Self::USER_PROPERTIES_COLUMN,
+                Cell::JsonObject(self.user_properties_json()),
+            ));
+        }
+        if let Some(content_type) = &self.content_type {
+            cells.push((
+                Self::CONTENT_TYPE_COLUMN,
+                Cell::String(content_type.clone()),
+            ));
+        }
+        if let Some(expiry) = self.message_expiry_interval {
+            cells.push((Self::MESSAGE_EXPIRY_COLUMN, Cell::Number(expiry.into())));
+        }
+        if let Some(data) = &self.correlation_data {
+            let text = match std::str::from_utf8(data) {
+                Ok(text) => text.to_string(),
+                Err(_) => data.iter().map(|b| format!("{b:02x}")).collect(),
+            };
+            cells.push((Self::CORRELATION_DATA_COLUMN, Cell::String(text)));
+        }
+
+        for (name, cell) in cells {
+            row.cells.insert(name.to_string(), cell);
+            row.original_keys.remove(name);
+        }
+    }
+
+    fn user_properties_json(&self) -> serde_json::Value {
+        let mut object = serde_json::Map::new();
+        for (key, value) in &self.user_properties {
+            let value = serde_json::Value::String(value.clone());
+            match object.get_mut(key) {
+                Some(serde_json::Value::Array(values)) => values.push(value),
+                Some(first) => *first = serde_json::Value::Array(vec![first.take(), value]),
+                None => {
+                    object.insert(key.clone(), value);
+                }
+            }
+        }
+        serde_json::Value::Object(object)
+    }
+}
+
+impl From<v5::mqttbytes::v5::PublishProperties> for MessageProperties {
+    fn from(properties: v5::mqttbytes::v5::PublishProperties) -> Self {
+        Self {
+            user_properties: properties.user_properties,
+            content_type: properties.content_type,
+            message_expiry_interval: properties.message_expiry_interval,
+            correlation_data: properties.correlation_data,
+        }
+    }
+}
+
+/// What the writer needs to ack a message once it is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingAck {
+    pub pkid: u16,
+    pub qos: QoS,
+    /// the connection the message came in on, see [AckOrder::connected]
+    pub generation: u64,
+    /// position of the message among the ones to ack, see [AckOrder]
+    pub seq: u64,
+}
+
+/// Releases acks in the order their messages arrived, MQTT requires PUBACK and PUBREC to go
+/// out in that order. A message that is done before an earlier one, e.g. because its table
+/// was written first or it was dropped from a full queue, waits for it.
+///
+/// Packet ids are only unique within a session, acks of a session the broker no longer has
+/// are dropped so they can't ack a new message that reuses the id.
+#[derive(Debug, Default)]
+pub struct AckOrder {
+    /// counts the connections, bumped on every ConnAck
+    generation: u64,
+    /// the first connection of the current session
+    session_generation: u64,
+    /// `seq` of the next message to arrive
+    next_seq: u64,
+    /// the first message whose ack isn't released
+    released_to: u64,
+    /// acks of messages done ahead of an earlier one
+    held: BTreeMap<u64, PendingAck>,
+    /// released acks the client hasn't taken yet
+    ready: VecDeque<PendingAck>,
+}
+
+impl AckOrder {
+    /// The `generation` and `seq` of a message with an ack that just arrived
+    pub fn arrived(&mut self) -> (u64, u64) {
+        self.next_seq += 1;
+        (self.generation, self.next_seq - 1)
+    }
+
+    /// A ConnAck came in, `resumed` if the broker still has the session of the previous
+    /// connection. Otherwise the acks of earlier connections are dropped, the ones not
+    /// released yet included, and the next message is the first to ack.
+    pub fn connected(&mut self, resumed: bool) {
+        self.generation += 1;
+        if !resumed {
+            self.session_generation = self.generation;
+            self.held.clear();
+            self.ready.clear();
+            self.released_to = self.next_seq;
+        }
+    }
+
+    /// The message of `ack` needs nothing more, it is stored, dead-lettered or dropped
+    pub fn done(&mut self, ack: PendingAck) {
+        if ack.generation < self.session_generation {
+            return;
+        }
+        self.held.insert(ack.seq, ack);
+        while let Some(ack) = self.held.remove(&self.released_to) {
+            self.ready.push_back(ack);
+            self.released_to += 1;
+        }
+    }
+
+    /// The first released ack, it stays until [Self::pop]
+    pub fn peek(&self) -> Option<&PendingAck> {
+        self.ready.front()
+    }
+
+    pub fn pop(&mut self) -> Option<PendingAck> {
+        self.ready.pop_front()
+    }
+}
+
+pub struct MessagePayload {
+    pub topic: String,
+    pub payload: Bytes,
+    pub timestamp: DateTime<Utc>,
+    pub pkid: u16,
+    pub qos: QoS,
+    /// set by the poll loop when the message arrives, see [AckOrder::arrived]
+    pub generation: u64,
+    pub seq: u64,
+    pub properties: MessageProperties,
+}
+
+impl MessagePayload {
+    /// What the message counts against `batch_max_bytes`
+    pub fn size(&self) -> usize {
+        self.topic.len() + self.payload.len()
+    }
+
+    /// `None` for qos 0, which has nothing to ack
+    pub fn to_ack(&self) -> Option<PendingAck> {
+        (self.qos != QoS::AtMostOnce).then_some(PendingAck {
+            pkid: self.pkid,
+            qos: self.qos,
+            generation: self.generation,
+            seq: self.seq,
+        })
+    }
+}
+
+impl From<(Publish, DateTime<Utc>)> for MessagePayload {
+    fn from((publish, timestamp): (Publish, DateTime<Utc>)) -> Self {
+        Self {
+            topic: publish.topic,
+            payload: publish.payload,
+            timestamp,
+            pkid: publish.pkid,
+            qos: publish.qos,
+            generation: 0,
+            seq: 0,
+            properties: MessageProperties::default(),
+        }
+    }
+}
+
+impl TryFrom<(v5::mqttbytes::v5::Publish, DateTime<Utc>)> for MessagePayload {
+    type Error = anyhow::Error;
+
+    fn try_from(
+        (publish, timestamp): (v5::mqttbytes::v5::Publish, DateTime<Utc>),
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            topic: String::from_utf8(publish.topic.to_vec())?,
+            payload: publish.payload,
+            timestamp,
+            pkid: publish.pkid,
+            qos: qos_from_v5(publish.qos),
+            generation: 0,
+            seq: 0,
+            properties: publish.properties.map(Into::into).unwrap_or_default(),
+        })
+    }
+}
+
+fn qos_from_v5(qos: v5::mqttbytes::QoS) -> QoS {
+    match qos {
+        v5::mqttbytes::QoS::AtMostOnce => QoS::AtMostOnce,
+        v5::mqttbytes::QoS::AtLeastOnce => QoS::AtLeastOnce,
+        v5::mqttbytes::QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+pub(crate) fn qos_to_v5(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+/// The part of an eventloop notification the connector acts on
+pub enum MqttEvent {
+    Publish(MessagePayload),
+    ConnAck {
+        session_present: bool,
+    },
+    /// our disconnect went out, the broker won't count it as an unexpected loss
+    Disconnected,
+    /// a publish that can't be stored, e.g. a v5 topic that isn't UTF-8. It is acked like
+    /// a stored one, otherwise the broker would send it again on every reconnect.
+    Skipped(Option<PendingAck>),
+    Other,
+}
+
+impl MqttEvent {
+    fn from_v5_publish(publish: v5::mqttbytes::v5::Publish, timestamp: DateTime<Utc>) -> Self {
+        let qos = qos_from_v5(publish.qos);
+        let ack = (qos != QoS::AtMostOnce).then_some(PendingAck {
+            pkid: publish.pkid,
+            qos,
+            generation: 0,
+            seq: 0,
+        });
+        match MessagePayload::try_from((publish, timestamp)) {
+            Ok(msg) => MqttEvent::Publish(msg),
+            Err(e) => {
+                println!("Skipping unreadable message: {}", e);
+                MqttEvent::Skipped(ack)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum MqttClient {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl MqttClient {
+    /// Only valid once the message is stored, the broker won't send it again afterwards.
+    /// Not awaited like [Self::try_subscribe], `false` means the request channel is full and
+    /// the ack has to be retried after the eventloop was polled.
+    pub fn try_ack(&self, ack: &PendingAck) -> bool {
+        // acking only looks at the packet id and qos, the topic and payload are left out
+        match self {
+            MqttClient::V4(client) => {
+                let mut publish = Publish::new("", ack.qos, Vec::new());
+                publish.pkid = ack.pkid;
+                client.try_ack(&publish).is_ok()
+            }
+            MqttClient::V5(client) => {
+                let mut publish =
+                    v5::mqttbytes::v5::Publish::new("", qos_to_v5(ack.qos), Bytes::new(), None);
+                publish.pkid = ack.pkid;
+                client.try_ack(&publish).is_ok()
+            }
+        }
+    }
+
+    pub async fn disconnect(&self) -> anyhow::Result<()> {
+        match self {
+            MqttClient::V4(client) => client.disconnect().await?,
+            MqttClient::V5(client) => client.disconnect().await?,
+        }
+        Ok(())
+    }
+
+    /// Not awaited, the eventloop that drains the request channel may be the caller. Like
+    /// [Self::try_ack] `false` means the request channel is full, the filters were checked
+    /// by [SubscriptionConfig].
+    pub fn try_subscribe(&self, subscriptions: &SubscriptionConfig) -> bool {
+        match self {
+            MqttClient::V4(client) => client
+                .try_subscribe_many(subscriptions.to_subscribe_filters())
+                .is_ok(),
+            MqttClient::V5(client) => client
+                .try_subscribe_many(
+                    subscriptions
+                        .subscriptions
+                        .iter()
+                        .map(Subscription::to_v5_filter),
+                )
+                .is_ok(),
+        }
+    }
+}
+
+// there is only ever one and it is never moved around
+#[allow(clippy::large_enum_variant)]
+pub enum MqttEventLoop {
+    V4(EventLoop),
+    V5(v5::EventLoop),
+}
+
+impl MqttEventLoop {
+    pub async fn poll(&mut self) -> anyhow::Result<MqttEvent> {
+        let event = match self {
+            MqttEventLoop::V4(eventloop) => {
+                let notification = eventloop.poll().await?;
+                println!("Notification: {:?}", notification);
+                match notification {
+                    rumqttc::Event::Incoming(rumqttc::Packet::Publish(p)) => {
+                        MqttEvent::Publish((p, Utc::now()).into())
+                    }
+                    rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(ack)) => MqttEvent::ConnAck {
+                        session_present: ack.session_present,
+                    },
+                    rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect) => {
+                        MqttEvent::Disconnected
+                    }
+                    _ => MqttEvent::Other,
+                }
+            }
+            MqttEventLoop::V5(eventloop) => {
+                let notification = eventloop.poll().await?;
+                println!("Notification: {:?}", notification);
+                match notification {
+                    v5::Event::Incoming(v5::Incoming::Publish(p)) => {
+                        MqttEvent::from_v5_publish(p, Utc::now())
+                    }
+                    v5::Event::Incoming(v5::Incoming::ConnAck(ack)) => MqttEvent::ConnAck {
+                        session_present: ack.session_present,
+                    },
+                    v5::Event::Outgoing(rumqttc::Outgoing::Disconnect) => MqttEvent::Disconnected,
+                    _ => MqttEvent::Other,
+                }
+            }
+        };
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod connection_options {
+        use std::path::Path;
+
+        use rumqttc::TlsConfiguration;
+
+        use crate::mqtt::{credentials, Secret, TlsFiles};
+
+        const PEM: &str = "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n";
+
+        #[test]
+        fn test_password_file_is_trimmed() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("password");
+            std::fs::write(&path, "s3cret\n").unwrap();
+            assert_eq!(
+                credentials(Some("user"), None, Some(&path)).unwrap(),
+                Some(("user".to_string(), "s3cret".to_string()))
+            );
+        }
+
+        #[test]
+        fn test_credentials_errors() {
+            let password = Secret("p".to_string());
+            assert!(credentials(None, Some(&password), None).is_err());
+            assert!(credentials(Some("u"), Some(&password), Some(Path::new("/p"))).is_err());
+            assert!(credentials(Some("u"), None, Some(Path::new("/does/not/exist"))).is_err());
+            assert_eq!(credentials(None, None, None).unwrap(), None);
+        }
+
+        #[test]
+        fn test_secret_is_redacted() {
+            let debug = format!("{:?}", Some(Secret("s3cret".to_string())));
+            assert!(!debug.contains("s3cret"));
+        }
+
+        #[test]
+        fn test_tls_with_client_auth_and_alpn() {
+            let dir = tempfile::tempdir().unwrap();
+            for name in ["ca.pem", "cert.pem", "key.pem"] {
+                std::fs::write(dir.path().join(name), PEM).unwrap();
+            }
+            let files = TlsFiles {
+                ca_file: Some(dir.path().join("ca.pem")),
+                client_cert_file: Some(dir.path().join("cert.pem")),
+                client_key_file: Some(dir.path().join("key.pem")),
+                alpn: Some("mqtt, x-amzn-mqtt-ca".to_string()),
+            };
+            match files.to_tls_configuration().unwrap() {
+                TlsConfiguration::Simple {
+                    ca,
+                    alpn,
+                    client_auth,
+                } => {
+                    assert_eq!(ca, PEM.as_bytes());
+                    assert_eq!(
+                        alpn,
+                        Some(vec![b"mqtt".to_vec(), b"x-amzn-mqtt-ca".to_vec()])
+                    );
+                    assert!(client_auth.is_some());
+                }
+                _ => panic!("expected TlsConfiguration::Simple"),
+            }
+        }
+
+        #[test]
+        fn test_tls_errors() {
+            let dir = tempfile::tempdir().unwrap();
+            let pem = dir.path().join("ca.pem");
+            let not_pem = dir.path().join("ca.der");
+            std::fs::write(&pem, PEM).unwrap();
+            std::fs::write(&not_pem, [0x30, 0x82]).unwrap();
+
+            for files in [
+                TlsFiles {
+                    ca_file: Some(not_pem),
+                    ..Default::default()
+                },
+                TlsFiles {
+                    ca_file: Some(dir.path().join("missing.pem")),
+                    ..Default::default()
+                },
+                TlsFiles {
+                    ca_file: Some(pem.clone()),
+                    client_cert_file: Some(pem.clone()),
+                    ..Default::default()
+                },
+                TlsFiles {
+                    client_cert_file: Some(pem.clone()),
+                    client_key_file: Some(pem.clone()),
+                    ..Default::default()
+                },
+            ] {
+                assert!(files.to_tls_configuration().is_err(), "{files:?}");
+            }
+        }
+    }
+
+    mod ack_order {
+        use rumqttc::QoS;
+
+        use crate::mqtt::{AckOrder, PendingAck};
+
+        fn arrived(order: &mut AckOrder, pkid: u16) -> PendingAck {
+            let (generation, seq) = order.arrived();
+            PendingAck {
+                pkid,
+                qos: QoS::AtLeastOnce,
+                generation,
+                seq,
+            }
+        }
+
+        fn released(order: &mut AckOrder) -> Vec<u16> {
+            std::iter::from_fn(|| order.pop()).map(|a| a.pkid).collect()
+        }
+
+        #[test]
+        fn test_acks_wait_for_earlier_messages() {
+            let mut order = AckOrder::default();
+            let acks: Vec<PendingAck> = (1..=4).map(|pkid| arrived(&mut order, pkid)).collect();
+
+            order.done(acks[2]);
+            order.done(acks[1]);
+            assert_eq!(order.peek(), None);
+            order.done(acks[0]);
+            assert_eq!(released(&mut order), [1, 2, 3]);
+            order.done(acks[3]);
+            assert_eq!(released(&mut order), [4]);
+        }
+
+        #[test]
+        fn test_acks_of_a_lost_session_are_dropped() {
+            let mut order = AckOrder::default();
+            order.connected(false);
+            let old: Vec<PendingAck> = (1..=3).map(|pkid| arrived(&mut order, pkid)).collect();
+            order.done(old[0]);
+            order.done(old[2]);
+
+            // a resumed session keeps them
+            order.connected(true);
+            let resumed = arrived(&mut order, 4);
+            order.done(old[1]);
+            order.done(resumed);
+            assert_eq!(released(&mut order), [1, 2, 3, 4]);
+
+            let stale = arrived(&mut order, 5);
+            let released_late = arrived(&mut order, 6);
+            order.done(released_late);
+            order.connected(false);
+            // the broker may hand the same packet id to a new message
+            let new = arrived(&mut order, 5);
+            order.done(stale);
+            assert_eq!(order.peek(), None);
+            order.done(new);
+            assert_eq!(released(&mut order), [5]);
+            assert_eq!(order.peek(), None);
+        }
+    }
+
+    mod message_properties {
+        use bytes::Bytes;
+        use chrono::Utc;
+        use rumqttc::v5::mqttbytes::{
+            v5::{Publish, PublishProperties},
+            QoS,
+        };
+        use serde_json::json;
+
+        use crate::{
+            db::Cell,
+            mapper::json_to_data_row,
+            mqtt::{MessagePayload, MessageProperties, MqttEvent, PendingAck},
+        };
+
+        #[test]
+        fn test_from_v5_publish() {
+            let properties = PublishProperties {
+                content_type: Some("application/json".to_string()),
+                message_expiry_interval: Some(60),
+                user_properties: vec![("site".to_string(), "berlin".to_string())],
+                ..Default::default()
+            };
+            let mut publish = Publish::new("a/b", QoS::AtLeastOnce, "{}", Some(properties));
+            publish.pkid = 7;
+
+            let msg = MessagePayload::try_from((publish, Utc::now())).unwrap();
+            assert_eq!(msg.topic, "a/b");
+            assert_eq!(msg.to_ack().unwrap().pkid, 7);
+            assert_eq!(
+                msg.properties.content_type.as_deref(),
+                Some("application/json")
+            );
+            assert_eq!(msg.properties.message_expiry_interval, Some(60));
+        }
+
+        #[test]
+        fn test_non_utf8_topic_is_skipped_and_acked() {
+            let mut publish = Publish::new("", QoS::AtLeastOnce, "{}", None);
+            publish.topic = Bytes::from_static(b"a/\xff");
+            publish.pkid = 7;
+
+            match MqttEvent::from_v5_publish(publish, Utc::now()) {
+                MqttEvent::Skipped(ack) => assert_eq!(
+                    ack,
+                    Some(PendingAck {
+                        pkid: 7,
+                        qos: rumqttc::QoS::AtLeastOnce,
+                        generation: 0,
+                        seq: 0,
+                    })
+                ),
+                _ => panic!("expected the publish to be skipped"),
+            }
+        }
+
+        #[test]
+        fn test_merge_into() {
+            let properties = MessageProperties {
+                user_properties: vec![
+                    ("site".to_string(), "berlin".to_string()),
+                    ("tag".to_string(), "a".to_string()),
+                    ("tag".to_string(), "b".to_string()),
+                ],
+                content_type: Some("application/json".to_string()),
+                message_expiry_interval: Some(60),
+                correlation_data: Some(Bytes::from_static(b"req-1")),
+            };
+            let mut row = json_to_data_row(r#"{"temp": 1}"#, Utc::now()).unwrap();
+            properties.merge_into(&mut row);
+
+            assert_eq!(
+                row.cells.get("mqtt_user_props"),
+                Some(&Cell::JsonObject(
+                    json!({"site": "berlin", "tag": ["a", "b"]})
+                ))
+            );
+            assert_eq!(
+                row.cells.get("mqtt_content_type"),
+                Some(&Cell::String("application/json".to_string()))
+            );
+            assert_eq!(
+                row.cells.get("mqtt_message_expiry"),
+                Some(&Cell::Number(60))
+            );
+            assert_eq!(
+                row.cells.get("mqtt_correlation_data"),
+                Some(&Cell::String("req-1".to_string()))
+            );
+        }
+
+        #[test]
+        fn test_binary_correlation_data_is_hex() {
+            let properties = MessageProperties {
+                correlation_data: Some(Bytes::from_static(&[0xff, 0x00])),
+                ..Default::default()
+            };
+            let mut row = json_to_data_row("{}", Utc::now()).unwrap();
+            properties.merge_into(&mut row);
+            assert_eq!(
+                row.cells.get("mqtt_correlation_data"),
+                Some(&Cell::String("ff00".to_string()))
+            );
+        }
+
+        #[test]
+        fn test_absent_properties_add_nothing() {
+            let mut row = json_to_data_row(r#"{"temp": 1}"#, Utc::now()).unwrap();
+            let before = row.clone();
+            MessageProperties::default().merge_into(&mut row);
+            assert_eq!(row, before);
+        }
+    }
+}
@@ -0,0 +1,1670 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use arrow_array::{
+    builder::{
+        BooleanBuilder, Float64Builder, Int64Builder, StringBuilder, TimestampMicrosecondBuilder,
+    },
+    Array, ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray,
+    TimestampMicrosecondArray,
+};
+use arrow_ipc::{reader::StreamReader, writer::StreamWriter};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+    basic::Compression,
+    file::{
+        properties::WriterProperties, reader::FileReader, serialized_reader::SerializedFileReader,
+    },
+    format::KeyValue,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    db::{child_rows, Cell, DBDriver, DataRow, MQTable, MQTableColumnInfo, MQTableInfo, Modifier},
+    dead_letter::DeadLetter,
+    utils::PreDefinedColumn,
+};
+
+const SCHEMA_FILE: &str = "_schema.json";
+const REGISTRY_FILE: &str = "_topic_tables.json";
+const DEAD_LETTER_FILE: &str = "_dead_letters.json";
+const IN_PROGRESS_EXTENSION: &str = "inprogress";
+const WAL_EXTENSION: &str = "wal";
+/// A finished file being rewritten to the current schema, it replaces the file once complete
+const REWRITE_EXTENSION: &str = "rewrite";
+/// Key of the file metadata holding the version of [SCHEMA_FILE] a file was written with
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Archives every table as a directory of rolling parquet files under a root directory.
+///
+/// Connection string is `parquet://<dir>?max_file_bytes=<bytes>&max_file_age=<humantime>`.
+/// A parquet file can only be read once it is finished, so every write is also appended to a
+/// write-ahead log next to the open file and synced before it returns. Files a crash left open
+/// are finished from their log on the next connect.
+///
+/// All finished files of a table have the same schema. When a column is added or widened the
+/// earlier files are rewritten with it, null-filled or with their values converted, and each
+/// replaces its original through a rename. Every file carries the `schema_version` it was
+/// written with in its metadata, files a crash left behind on an older version are rewritten
+/// when their table is next loaded.
+pub struct ParquetDriver {
+    shared: Arc<Shared>,
+}
+
+/// State of [ParquetDriver], shared with the blocking tasks doing the file I/O and the task
+/// finishing aged files
+struct Shared {
+    root: PathBuf,
+    max_file_bytes: usize,
+    max_file_age: Duration,
+    tables: Mutex<HashMap<String, TableSink>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SchemaColumn {
+    column_name: String,
+    data_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original_name: Option<String>,
+}
+
+/// Content of [SCHEMA_FILE]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct StoredSchema {
+    /// bumped whenever a column is added or widened
+    version: u64,
+    columns: Vec<SchemaColumn>,
+}
+
+/// [DeadLetter] as kept in [DEAD_LETTER_FILE]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct StoredDeadLetter {
+    id: i64,
+    topic: String,
+    payload: Vec<u8>,
+    error_kind: String,
+    error_message: String,
+    /// RFC 3339
+    received_ts: String,
+}
+
+impl StoredDeadLetter {
+    fn new(id: i64, letter: &DeadLetter) -> Self {
+        Self {
+            id,
+            topic: letter.topic.clone(),
+            payload: letter.payload.clone(),
+            error_kind: letter.error_kind.clone(),
+            error_message: letter.error_message.clone(),
+            received_ts: letter.received_ts.to_rfc3339(),
+        }
+    }
+
+    fn to_dead_letter(&self) -> anyhow::Result<DeadLetter> {
+        Ok(DeadLetter {
+            id: self.id,
+            topic: self.topic.clone(),
+            payload: self.payload.clone(),
+            error_kind: self.error_kind.clone(),
+            error_message: self.error_message.clone(),
+            received_ts: chrono::DateTime::parse_from_rfc3339(&self.received_ts)?.to_utc(),
+        })
+    }
+}
+
+struct OpenFile {
+    writer: ArrowWriter<File>,
+    /// the batches of `writer` in arrow IPC, it only writes a row group once one is full and
+    /// the footer on close
+    wal: StreamWriter<File>,
+    path: PathBuf,
+    opened_at: Instant,
+}
+
+impl OpenFile {
+    /// `batch` is durable once this returns
+    fn append(&mut self, batch: &RecordBatch) -> anyhow::Result<()> {
+        self.wal.write(batch)?;
+        self.wal.flush()?;
+        self.wal.get_ref().sync_data()?;
+        self.writer.write(batch)?;
+        Ok(())
+    }
+}
+
+struct TableSink {
+    dir: PathBuf,
+    // ordered, unlike MQTableInfo, so the arrow schema is stable
+    columns: Vec<SchemaColumn>,
+    schema_version: u64,
+    file: Option<OpenFile>,
+    next_pkey: i64,
+}
+
+impl TableSink {
+    fn load(dir: PathBuf) -> anyhow::Result<Option<TableSink>> {
+        let schema_path = dir.join(SCHEMA_FILE);
+        if !schema_path.exists() {
+            return Ok(None);
+        }
+        let schema: StoredSchema = serde_json::from_slice(&fs::read(schema_path)?)?;
+
+        let mut rows = 0;
+        for path in finished_files(&dir)? {
+            rows += SerializedFileReader::new(File::open(path)?)?
+                .metadata()
+                .file_metadata()
+                .num_rows();
+        }
+
+        let sink = TableSink {
+            dir,
+            columns: schema.columns,
+            schema_version: schema.version,
+            file: None,
+            next_pkey: rows + 1,
+        };
+        // a crash may have come between a schema change and the rewrite of the files
+        sink.conform_finished_files()?;
+        Ok(Some(sink))
+    }
+
+    /// Rewrites the finished files written with an older schema version, see [ParquetDriver]
+    fn conform_finished_files(&self) -> anyhow::Result<()> {
+        let schema = self.arrow_schema()?;
+        let version = self.schema_version.to_string();
+        for path in finished_files(&self.dir)? {
+            let written_with = SerializedFileReader::new(File::open(&path)?)?
+                .metadata()
+                .file_metadata()
+                .key_value_metadata()
+                .and_then(|kvs| kvs.iter().find(|kv| kv.key == SCHEMA_VERSION_KEY))
+                .and_then(|kv| kv.value.clone());
+            if written_with.as_deref() == Some(version.as_str()) {
+                continue;
+            }
+
+            let tmp = path.with_extension(REWRITE_EXTENSION);
+            let mut writer = ArrowWriter::try_new(
+                File::create(&tmp)?,
+                schema.clone(),
+                Some(writer_properties(&schema)),
+            )?;
+            for batch in ParquetRecordBatchReaderBuilder::try_new(File::open(&path)?)?.build()? {
+                // through cells so the values come out as if they were written to these columns
+                let rows = batch_rows(&batch?)?;
+                let columns = self
+                    .columns
+                    .iter()
+                    .map(|c| build_column(c, &rows, 0, chrono::Utc::now()))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                writer.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+            }
+            writer.into_inner()?.sync_all()?;
+            fs::rename(&tmp, &path)?;
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Closes the open file and stores `columns` as the next schema version, the finished
+    /// files are rewritten to match
+    fn change_schema(&mut self, columns: Vec<SchemaColumn>) -> anyhow::Result<()> {
+        // the open file's schema is fixed, the next one gets the new columns
+        self.close()?;
+        self.columns = columns;
+        self.schema_version += 1;
+        self.save_schema()?;
+        self.conform_finished_files()
+    }
+
+    fn save_schema(&self) -> anyhow::Result<()> {
+        let schema = StoredSchema {
+            version: self.schema_version,
+            columns: self.columns.clone(),
+        };
+        let tmp = self.dir.join(format!("{SCHEMA_FILE}.tmp"));
+        fs::write(&tmp, serde_json::to_vec_pretty(&schema)?)?;
+        fs::rename(tmp, self.dir.join(SCHEMA_FILE))?;
+        Ok(())
+    }
+
+    fn info(&self) -> MQTableInfo {
+        self.columns
+            .iter()
+            .map(|c| MQTableColumnInfo {
+                column_name: c.column_name.clone(),
+                data_type: c.data_type.clone(),
+                original_name: c.original_name.clone(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    fn arrow_schema(&self) -> anyhow::Result<SchemaRef> {
+        let fields = self
+            .columns
+            .iter()
+            .map(|c| {
+                Ok(Field::new(
+                    &c.column_name,
+                    to_arrow_type(&c.data_type)?,
+                    true,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let metadata = HashMap::from([(
+            SCHEMA_VERSION_KEY.to_string(),
+            self.schema_version.to_string(),
+        )]);
+        Ok(Arc::new(Schema::new_with_metadata(fields, metadata)))
+    }
+
+    fn open(&mut self) -> anyhow::Result<&mut OpenFile> {
+        if self.file.is_none() {
+            let path = self.dir.join(format!(
+                "part-{:020}.parquet.{IN_PROGRESS_EXTENSION}",
+                self.next_pkey
+            ));
+            let schema = self.arrow_schema()?;
+            let writer = ArrowWriter::try_new(
+                File::create(&path)?,
+                schema.clone(),
+                Some(writer_properties(&schema)),
+            )?;
+            let wal =
+                StreamWriter::try_new(File::create(path.with_extension(WAL_EXTENSION))?, &schema)?;
+            // the log has to be found after a crash
+            sync_dir(&self.dir)?;
+            self.file = Some(OpenFile {
+                writer,
+                wal,
+                path,
+                opened_at: Instant::now(),
+            });
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn is_due(&self, max_file_age: Duration) -> bool {
+        self.file
+            .as_ref()
+            .is_some_and(|f| f.opened_at.elapsed() >= max_file_age)
+    }
+
+    /// `items` as a batch of the current schema, the rows missing a `pkey` are numbered from
+    /// the next one
+    fn batch(&mut self, items: &[DataRow], max_file_age: Duration) -> anyhow::Result<RecordBatch> {
+        if self.is_due(max_file_age) {
+            self.close()?;
+        }
+
+        let now = chrono::Utc::now();
+        let columns = self
+            .columns
+            .iter()
+            .map(|c| build_column(c, items, self.next_pkey, now))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(RecordBatch::try_new(self.arrow_schema()?, columns)?)
+    }
+
+    /// Appends `batch` to the open file, returns the `pkey` of its first row
+    fn append(&mut self, batch: &RecordBatch, max_file_bytes: usize) -> anyhow::Result<i64> {
+        let file = self.open()?;
+        if let Err(e) = file.append(batch) {
+            // a torn batch in the log would hide the ones after it, the file is finished
+            // with what was appended before and the next write starts a new one
+            if let Err(close) = self.close() {
+                println!("Failed to finish parquet file: {:?}", close);
+            }
+            return Err(e);
+        }
+        let written = file.writer.bytes_written() + file.writer.in_progress_size();
+        let first_pkey = self.next_pkey;
+        self.next_pkey += batch.num_rows() as i64;
+
+        if written >= max_file_bytes {
+            self.close()?;
+        }
+        Ok(first_pkey)
+    }
+
+    /// Finishes the open file, if any, and makes it visible to readers
+    fn close(&mut self) -> anyhow::Result<()> {
+        if let Some(OpenFile {
+            writer, wal, path, ..
+        }) = self.file.take()
+        {
+            finish_file(&self.dir, writer, &path)?;
+            drop(wal);
+            fs::remove_file(path.with_extension(WAL_EXTENSION))?;
+        }
+        Ok(())
+    }
+}
+
+/// Snappy compressed, the schema metadata is repeated as file metadata for readers that don't
+/// decode the embedded arrow schema
+fn writer_properties(schema: &Schema) -> WriterProperties {
+    let metadata = schema
+        .metadata()
+        .iter()
+        .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+        .collect();
+    WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_key_value_metadata(Some(metadata))
+        .build()
+}
+
+/// Writes the footer of the in progress file at `path` and renames it to its finished name
+fn finish_file(dir: &Path, writer: ArrowWriter<File>, path: &Path) -> anyhow::Result<()> {
+    writer.into_inner()?.sync_all()?;
+    fs::rename(path, path.with_extension(""))?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// Makes created, renamed and removed entries of `dir` durable
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Finishes the files a crash left open in `dir` from their write-ahead logs
+fn recover(dir: &Path) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let wal_path = entry?.path();
+        if wal_path.extension().is_none_or(|e| e != WAL_EXTENSION) {
+            continue;
+        }
+        // the log is only removed after its file is finished
+        if !wal_path.with_extension("").exists() {
+            recover_file(dir, &wal_path)?;
+        }
+        fs::remove_file(&wal_path)?;
+    }
+
+    // in progress files without a log never got a row, a rewrite is done again from the
+    // file it was meant to replace
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|e| e == IN_PROGRESS_EXTENSION || e == REWRITE_EXTENSION)
+        {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the batches logged at `wal_path` to the finished file it belongs to
+fn recover_file(dir: &Path, wal_path: &Path) -> anyhow::Result<()> {
+    // no schema yet when the crash came right after the log was created
+    let Ok(reader) = StreamReader::try_new(File::open(wal_path)?, None) else {
+        return Ok(());
+    };
+    let schema = reader.schema();
+    let mut batches = vec![];
+    for batch in reader {
+        match batch {
+            Ok(batch) => batches.push(batch),
+            // torn by the crash, the write never returned
+            Err(e) => {
+                println!("Dropping the torn end of {}: {}", wal_path.display(), e);
+                break;
+            }
+        }
+    }
+    if batches.is_empty() {
+        return Ok(());
+    }
+
+    let path = wal_path.with_extension(IN_PROGRESS_EXTENSION);
+    let mut writer = ArrowWriter::try_new(
+        File::create(&path)?,
+        schema.clone(),
+        Some(writer_properties(&schema)),
+    )?;
+    let mut rows = 0;
+    for batch in &batches {
+        writer.write(batch)?;
+        rows += batch.num_rows();
+    }
+    finish_file(dir, writer, &path)?;
+    println!(
+        "Recovered {} rows into {}",
+        rows,
+        path.with_extension("").display()
+    );
+    Ok(())
+}
+
+/// Replaces `path` with `content` through a rename, readers never see half a file
+fn write_replacing(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+fn finished_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "parquet") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn to_arrow_type(data_type: &str) -> anyhow::Result<DataType> {
+    match data_type {
+        "BIGINT" => Ok(DataType::Int64),
+        "DOUBLE PRECISION" => Ok(DataType::Float64),
+        // arrow decimals have a fixed precision, keep the exact digits as text
+        "TEXT" | "JSON" | "NUMERIC" | "UUID" | "INET" => Ok(DataType::Utf8),
+        "BOOLEAN" => Ok(DataType::Boolean),
+        "TIMESTAMP" => Ok(DataType::Timestamp(TimeUnit::Microsecond, None)),
+        "TIMESTAMPTZ" => Ok(DataType::Timestamp(
+            TimeUnit::Microsecond,
+            Some("UTC".into()),
+        )),
+        _ => anyhow::bail!("Unsupported parquet column type: {}", data_type),
+    }
+}
+
+/// Cells of the rows in `batch`, nulls are kept as [Cell::Null] so the predefined columns are
+/// not filled again
+fn batch_rows(batch: &RecordBatch) -> anyhow::Result<Vec<DataRow>> {
+    let mut rows = vec![DataRow::default(); batch.num_rows()];
+    for (field, array) in batch.schema().fields().iter().zip(batch.columns()) {
+        let any = array.as_any();
+        let cell = |i: usize| -> anyhow::Result<Cell> {
+            if array.is_null(i) {
+                return Ok(Cell::Null);
+            }
+            Ok(match array.data_type() {
+                DataType::Int64 => Cell::Number(any.downcast_ref::<Int64Array>().unwrap().value(i)),
+                DataType::Float64 => {
+                    Cell::Float(any.downcast_ref::<Float64Array>().unwrap().value(i))
+                }
+                DataType::Utf8 => Cell::String(
+                    any.downcast_ref::<StringArray>()
+                        .unwrap()
+                        .value(i)
+                        .to_string(),
+                ),
+                DataType::Boolean => {
+                    Cell::Bool(any.downcast_ref::<BooleanArray>().unwrap().value(i))
+                }
+                DataType::Timestamp(TimeUnit::Microsecond, tz) => {
+                    let micros = any
+                        .downcast_ref::<TimestampMicrosecondArray>()
+                        .unwrap()
+                        .value(i);
+                    let dt = chrono::DateTime::from_timestamp_micros(micros)
+                        .ok_or_else(|| anyhow::anyhow!("Timestamp out of range: {}", micros))?;
+                    match tz {
+                        Some(_) => Cell::DateTimeTz(dt),
+                        None => Cell::DateTime(dt.naive_utc()),
+                    }
+                }
+                other => anyhow::bail!("Unsupported parquet column type: {}", other),
+            })
+        };
+        for (i, row) in rows.iter_mut().enumerate() {
+            row.cells.insert(field.name().clone(), cell(i)?);
+        }
+    }
+    Ok(rows)
+}
+
+fn build_column(
+    column: &SchemaColumn,
+    rows: &[DataRow],
+    first_pkey: i64,
+    now: chrono::DateTime<chrono::Utc>,
+) -> anyhow::Result<ArrayRef> {
+    let name = column.column_name.as_str();
+    let mismatch = |cell: &Cell| {
+        anyhow::anyhow!(
+            "Cannot write {:?} to {} column {}",
+            cell,
+            column.data_type,
+            name
+        )
+    };
+
+    let array: ArrayRef = match column.data_type.as_str() {
+        "BIGINT" => {
+            let mut builder = Int64Builder::with_capacity(rows.len());
+            for (i, row) in rows.iter().enumerate() {
+                match row.cells.get(name) {
+                    Some(Cell::Number(n)) => builder.append_value(*n),
+                    Some(Cell::Null) => builder.append_null(),
+                    Some(cell) => return Err(mismatch(cell)),
+                    None if name == PreDefinedColumn::PKey.to_string() => {
+                        builder.append_value(first_pkey + i as i64)
+                    }
+                    None => builder.append_null(),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        "DOUBLE PRECISION" => {
+            let mut builder = Float64Builder::with_capacity(rows.len());
+            for row in rows {
+                match row.cells.get(name) {
+                    Some(Cell::Float(f)) => builder.append_value(*f),
+                    Some(Cell::Number(n)) => builder.append_value(*n as f64),
+                    Some(Cell::Null) | None => builder.append_null(),
+                    Some(cell) => return Err(mismatch(cell)),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        "TEXT" | "JSON" | "NUMERIC" | "UUID" | "INET" => {
+            let mut builder = StringBuilder::new();
+            for row in rows {
+                match row.cells.get(name) {
+                    Some(Cell::String(s)) => builder.append_value(s),
+                    Some(Cell::JsonObject(v)) => builder.append_value(v.to_string()),
+                    Some(Cell::Number(n)) => builder.append_value(n.to_string()),
+                    Some(Cell::Float(f)) => builder.append_value(f.to_string()),
+                    Some(Cell::Decimal(d)) => builder.append_value(d.to_string()),
+                    Some(Cell::Uuid(u)) => builder.append_value(u.to_string()),
+                    Some(Cell::Inet(ip)) => builder.append_value(ip.to_string()),
+                    Some(Cell::Bool(b)) => builder.append_value(b.to_string()),
+                    Some(Cell::DateTime(dt)) => builder.append_value(dt.to_string()),
+                    Some(Cell::DateTimeTz(dt)) => builder.append_value(dt.to_rfc3339()),
+                    Some(Cell::Null) | None => builder.append_null(),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        "BOOLEAN" => {
+            let mut builder = BooleanBuilder::with_capacity(rows.len());
+            for row in rows {
+                match row.cells.get(name) {
+                    Some(Cell::Bool(b)) => builder.append_value(*b),
+                    Some(Cell::Null) | None => builder.append_null(),
+                    Some(cell) => return Err(mismatch(cell)),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        "TIMESTAMP" | "TIMESTAMPTZ" => {
+            let mut builder = TimestampMicrosecondBuilder::with_capacity(rows.len());
+            for row in rows {
+                match row.cells.get(name) {
+                    Some(Cell::DateTime(dt)) => {
+                        builder.append_value(dt.and_utc().timestamp_micros())
+                    }
+                    Some(Cell::DateTimeTz(dt)) => builder.append_value(dt.timestamp_micros()),
+                    Some(Cell::Null) => builder.append_null(),
+                    Some(cell) => return Err(mismatch(cell)),
+                    None if name == PreDefinedColumn::InsertTs.to_string() => {
+                        builder.append_value(now.timestamp_micros())
+                    }
+                    None => builder.append_null(),
+                }
+            }
+            let array = builder.finish();
+            if column.data_type == "TIMESTAMPTZ" {
+                Arc::new(array.with_timezone("UTC"))
+            } else {
+                Arc::new(array)
+            }
+        }
+        other => anyhow::bail!("Unsupported parquet column type: {}", other),
+    };
+    Ok(array)
+}
+
+impl Shared {
+    fn with_sink<R>(
+        &self,
+        table: &MQTable,
+        f: impl FnOnce(&mut TableSink) -> anyhow::Result<R>,
+    ) -> anyhow::Result<Option<R>> {
+        let mut tables = self.tables.lock().unwrap();
+        match self.loaded(&mut tables, table)? {
+            Some(sink) => f(sink).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The sink of `table` in `tables`, loaded from disk the first time
+    fn loaded<'a>(
+        &self,
+        tables: &'a mut HashMap<String, TableSink>,
+        table: &MQTable,
+    ) -> anyhow::Result<Option<&'a mut TableSink>> {
+        if !tables.contains_key(&table.name) {
+            match TableSink::load(self.root.join(&table.name))? {
+                Some(sink) => {
+                    tables.insert(table.name.clone(), sink);
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(tables.get_mut(&table.name))
+    }
+
+    /// No transactions here. The batches of the parents and their children are all built
+    /// before the first is appended, so a row that doesn't fit its table writes nothing, only
+    /// a failing disk can leave parents without their children.
+    fn insert_many(&self, items: &[DataRow], table: &MQTable) -> anyhow::Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        let mut batch =
+            |table: &MQTable, items: &[DataRow]| -> anyhow::Result<(i64, RecordBatch)> {
+                let Some(sink) = self.loaded(&mut tables, table)? else {
+                    anyhow::bail!("Table {} does not exist", table.name);
+                };
+                let batch = sink.batch(items, self.max_file_age)?;
+                Ok((sink.next_pkey, batch))
+            };
+
+        let (first_pkey, parent_batch) = batch(table, items)?;
+        let parents = items
+            .iter()
+            .enumerate()
+            .filter(|(_, row)| row.has_children())
+            .map(|(i, row)| (first_pkey + i as i64, row));
+        let mut batches = vec![(table.name.clone(), parent_batch)];
+        for (child, rows) in child_rows(table, parents) {
+            let (_, child_batch) = batch(&child, &rows)?;
+            batches.push((child.name, child_batch));
+        }
+
+        for (name, batch) in batches {
+            let sink = tables.get_mut(&name).unwrap();
+            sink.append(&batch, self.max_file_bytes)?;
+        }
+        Ok(())
+    }
+
+    /// `topic -> table name`, sorted so the file diffs cleanly
+    fn read_registry(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        match fs::read(self.root.join(REGISTRY_FILE)) {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_dead_letters(&self) -> anyhow::Result<Vec<StoredDeadLetter>> {
+        match fs::read(self.root.join(DEAD_LETTER_FILE)) {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_dead_letters(&self, letters: &[StoredDeadLetter]) -> anyhow::Result<()> {
+        write_replacing(
+            &self.root.join(DEAD_LETTER_FILE),
+            &serde_json::to_vec_pretty(letters)?,
+        )
+    }
+
+    /// Finishes the files open for `max_file_age`, also of tables that get no more writes
+    fn close_aged(&self) -> anyhow::Result<()> {
+        for sink in self.tables.lock().unwrap().values_mut() {
+            if sink.is_due(self.max_file_age) {
+                sink.close()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        for sink in self.tables.lock().unwrap().values_mut() {
+            sink.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs [Shared::close_aged] every `period` until the driver is dropped
+async fn close_aged_files(shared: Weak<Shared>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let closed = tokio::task::spawn_blocking(move || shared.close_aged()).await;
+        if let Err(e) = closed.map_err(anyhow::Error::from).and_then(|r| r) {
+            println!("Failed to finish aged parquet files: {:?}", e);
+        }
+    }
+}
+
+impl ParquetDriver {
+    /// Runs `f` on the blocking pool, file I/O on the runtime thread would stall MQTT polling
+    async fn blocking<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Shared) -> anyhow::Result<R> + Send + 'static,
+    ) -> anyhow::Result<R> {
+        let shared = self.shared.clone();
+        tokio::task::spawn_blocking(move || f(&shared)).await?
+    }
+
+    /// Finishes all open files so they become readable
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.shared.flush()
+    }
+}
+
+impl Drop for ParquetDriver {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            println!("Failed to finish parquet files: {:?}", e);
+        }
+    }
+}
+
+impl DBDriver for ParquetDriver {
+    #[allow(refining_impl_trait)]
+    async fn connect(connection_string: &str) -> anyhow::Result<ParquetDriver> {
+        let Some(rest) = connection_string.strip_prefix("parquet://") else {
+            anyhow::bail!("Parquet connection string must start with parquet://");
+        };
+        let (path, params) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut max_file_bytes = 128 * 1024 * 1024;
+        let mut max_file_age = Duration::from_secs(60 * 60);
+        for (key, value) in params.split('&').filter_map(|p| p.split_once('=')) {
+            match key {
+                "max_file_bytes" => max_file_bytes = value.parse()?,
+                "max_file_age" => max_file_age = humantime::parse_duration(value)?,
+                _ => anyhow::bail!("Unknown parquet connection parameter: {}", key),
+            }
+        }
+
+        let root = PathBuf::from(path);
+        let recovered = root.clone();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            fs::create_dir_all(&recovered)?;
+            for entry in fs::read_dir(&recovered)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    recover(&entry.path())?;
+                }
+            }
+            Ok(())
+        })
+        .await??;
+        println!("Writing parquet files to {}", root.display());
+
+        let shared = Arc::new(Shared {
+            root,
+            max_file_bytes,
+            max_file_age,
+            tables: Mutex::new(HashMap::new()),
+        });
+        // files of idle tables have to be finished as well, not just on the next write
+        let period = max_file_age.clamp(Duration::from_millis(1), Duration::from_secs(1));
+        tokio::spawn(close_aged_files(Arc::downgrade(&shared), period));
+        anyhow::Ok(ParquetDriver { shared })
+    }
+
+    async fn execute_query(&self, _: &str) -> anyhow::Result<String> {
+        anyhow::bail!("Parquet driver does not support queries")
+    }
+
+    async fn insert_one(&self, row: DataRow, table: &MQTable) -> anyhow::Result<()> {
+        self.insert_many(&[row], table).await
+    }
+
+    async fn insert_many(&self, items: &[DataRow], table: &MQTable) -> anyhow::Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let (items, table) = (items.to_vec(), table.clone());
+        self.blocking(move |shared| shared.insert_many(&items, &table))
+            .await
+    }
+
+    async fn get_table_info(&self, table: &MQTable) -> anyhow::Result<MQTableInfo> {
+        let table = table.clone();
+        self.blocking(move |shared| {
+            Ok(shared
+                .with_sink(&table, |sink| Ok(sink.info()))?
+                .unwrap_or_default())
+        })
+        .await
+    }
+
+    async fn get_table_registry(&self) -> anyhow::Result<HashMap<String, MQTable>> {
+        let registry = self.blocking(|shared| shared.read_registry()).await?;
+        Ok(registry
+            .into_iter()
+            .map(|(topic, name)| (topic, MQTable { name }))
+            .collect())
+    }
+
+    async fn register_table(&self, topic: &str, table: &MQTable) -> anyhow::Result<()> {
+        let (topic, table) = (topic.to_string(), table.clone());
+        self.blocking(move |shared| {
+            // held so two registrations can't both pass the checks
+            let _tables = shared.tables.lock().unwrap();
+            let mut registry = shared.read_registry()?;
+            if registry.contains_key(&topic) {
+                anyhow::bail!("Topic {} is already registered", topic);
+            }
+            if registry.values().any(|name| *name == table.name) {
+                anyhow::bail!("Table {} is already registered", table.name);
+            }
+            registry.insert(topic, table.name);
+
+            write_replacing(
+                &shared.root.join(REGISTRY_FILE),
+                &serde_json::to_vec_pretty(&registry)?,
+            )
+        })
+        .await
+    }
+
+    async fn create_dead_letter_table(&self) -> anyhow::Result<()> {
+        // the file is created with the first dead letter
+        Ok(())
+    }
+
+    async fn insert_dead_letters(&self, letters: &[DeadLetter]) -> anyhow::Result<()> {
+        let letters = letters.to_vec();
+        self.blocking(move |shared| {
+            let _tables = shared.tables.lock().unwrap();
+            let mut stored = shared.read_dead_letters()?;
+            let first_id = stored.iter().map(|l| l.id).max().unwrap_or(0) + 1;
+            for (id, letter) in (first_id..).zip(&letters) {
+                stored.push(StoredDeadLetter::new(id, letter));
+            }
+            shared.write_dead_letters(&stored)
+        })
+        .await
+    }
+
+    async fn get_dead_letters(&self) -> anyhow::Result<Vec<DeadLetter>> {
+        self.blocking(|shared| {
+            shared
+                .read_dead_letters()?
+                .iter()
+                .map(StoredDeadLetter::to_dead_letter)
+                .collect()
+        })
+        .await
+    }
+
+    async fn delete_dead_letter(&self, id: i64) -> anyhow::Result<()> {
+        self.blocking(move |shared| {
+            let _tables = shared.tables.lock().unwrap();
+            let mut stored = shared.read_dead_letters()?;
+            stored.retain(|l| l.id != id);
+            shared.write_dead_letters(&stored)
+        })
+        .await
+    }
+
+    async fn add_column_to_table(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()> {
+        let column = column.clone();
+        let added = self
+            .blocking({
+                let table = table.clone();
+                move |shared| {
+                    shared.with_sink(&table, |sink| {
+                        if sink
+                            .columns
+                            .iter()
+                            .any(|c| c.column_name == column.column_name)
+                        {
+                            return Ok(());
+                        }
+                        to_arrow_type(&column.data_type)?;
+
+                        let mut columns = sink.columns.clone();
+                        columns.push(SchemaColumn {
+                            column_name: column.column_name.clone(),
+                            data_type: column.data_type.clone(),
+                            original_name: column.original_name.clone(),
+                        });
+                        sink.change_schema(columns)
+                    })
+                }
+            })
+            .await?;
+
+        match added {
+            Some(()) => Ok(()),
+            None => anyhow::bail!("Table {} does not exist", table.name),
+        }
+    }
+
+    async fn alter_column_type(
+        &self,
+        table: &MQTable,
+        column: &MQTableColumnInfo,
+    ) -> anyhow::Result<()> {
+        let column = column.clone();
+        let altered = self
+            .blocking({
+                let table = table.clone();
+                move |shared| {
+                    shared.with_sink(&table, |sink| {
+                        to_arrow_type(&column.data_type)?;
+                        let Some(index) = sink
+                            .columns
+                            .iter()
+                            .position(|c| c.column_name == column.column_name)
+                        else {
+                            anyhow::bail!(
+                                "Column {} does not exist in {}",
+                                column.column_name,
+                                table.name
+                            );
+                        };
+
+                        let mut columns = sink.columns.clone();
+                        columns[index].data_type = column.data_type.clone();
+                        sink.change_schema(columns)
+                    })
+                }
+            })
+            .await?;
+
+        match altered {
+            Some(()) => Ok(()),
+            None => anyhow::bail!("Table {} does not exist", table.name),
+        }
+    }
+
+    async fn create_table_if_not_exists(
+        &self,
+        table: &MQTable,
+        info: &MQTableInfo,
+    ) -> anyhow::Result<()> {
+        let (table, info) = (table.clone(), info.clone());
+        self.blocking(move |shared| {
+            let mut tables = shared.tables.lock().unwrap();
+            let dir = shared.root.join(&table.name);
+            if tables.contains_key(&table.name) || dir.join(SCHEMA_FILE).exists() {
+                return Ok(());
+            }
+
+            let mut columns = info
+                .columns()
+                .into_iter()
+                .map(|c| {
+                    to_arrow_type(&c.data_type)?;
+                    Ok(SchemaColumn {
+                        column_name: c.column_name.clone(),
+                        data_type: c.data_type.clone(),
+                        original_name: c.original_name.clone(),
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            columns.sort_by(|a, b| a.column_name.cmp(&b.column_name));
+
+            fs::create_dir_all(&dir)?;
+            let sink = TableSink {
+                dir,
+                columns,
+                schema_version: 1,
+                file: None,
+                next_pkey: 1,
+            };
+            sink.save_schema()?;
+            tables.insert(table.name.clone(), sink);
+            Ok(())
+        })
+        .await
+    }
+
+    fn convert_to_db_type_string(&self, cell: &Cell) -> String {
+        match cell {
+            Cell::Number(_) => "BIGINT".to_string(),
+            Cell::Float(_) => "DOUBLE PRECISION".to_string(),
+            Cell::Decimal(_) => "NUMERIC".to_string(),
+            Cell::String(_) => "TEXT".to_string(),
+            Cell::Uuid(_) => "UUID".to_string(),
+            Cell::Inet(_) => "INET".to_string(),
+            Cell::Bool(_) => "BOOLEAN".to_string(),
+            Cell::Null => "TEXT".to_string(), // Default to TEXT for NULLs
+            Cell::JsonObject(_) => "JSON".to_string(),
+            Cell::DateTime(_) => "TIMESTAMP".to_string(),
+            Cell::DateTimeTz(_) => "TIMESTAMPTZ".to_string(),
+        }
+    }
+
+    fn widen_db_type(&self, data_type: &str, cell: &Cell) -> Option<String> {
+        match (data_type, cell) {
+            ("BIGINT", Cell::Float(_)) => Some("DOUBLE PRECISION".to_string()),
+            ("BIGINT" | "DOUBLE PRECISION", Cell::Decimal(_)) => Some("NUMERIC".to_string()),
+            ("TIMESTAMP" | "TIMESTAMPTZ", Cell::String(_)) => Some("TEXT".to_string()),
+            _ => None,
+        }
+    }
+
+    fn default_table_info(&self) -> MQTableInfo {
+        vec![
+            MQTableColumnInfo {
+                // filled from a per table counter
+                column_name: PreDefinedColumn::PKey.to_string(),
+                data_type: "BIGINT".to_string(),
+                modifier: Modifier::PrimaryKey,
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::Raw.to_string(),
+                data_type: "JSON".to_string(),
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                // filled with the write time
+                column_name: PreDefinedColumn::InsertTs.to_string(),
+                data_type: "TIMESTAMP".to_string(),
+                ..Default::default()
+            },
+            MQTableColumnInfo {
+                column_name: PreDefinedColumn::ReceivedTs.to_string(),
+                data_type: "TIMESTAMP".to_string(),
+                ..Default::default()
+            },
+        ]
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        io::Write,
+        path::Path,
+    };
+
+    use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+    use chrono::Utc;
+    use parquet::{
+        arrow::arrow_reader::ParquetRecordBatchReaderBuilder,
+        file::{reader::FileReader, serialized_reader::SerializedFileReader},
+    };
+    use tempfile::TempDir;
+
+    use crate::{
+        db::{DBDriver, MQTable},
+        dead_letter::{to_data_row, DeadLetter},
+        manager::Manager,
+        mapper::{json_to_data_row, json_to_data_row_with, MappingOptions},
+        parquet_driver::{
+            finished_files, ParquetDriver, SchemaColumn, StoredSchema, REWRITE_EXTENSION,
+            SCHEMA_FILE,
+        },
+    };
+
+    async fn connect(params: &str) -> (TempDir, ParquetDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("parquet://{}{}", dir.path().display(), params);
+        let driver = ParquetDriver::connect(&url).await.unwrap();
+        (dir, driver)
+    }
+
+    fn read_all(dir: &Path) -> Vec<RecordBatch> {
+        finished_files(dir)
+            .unwrap()
+            .into_iter()
+            .flat_map(|path| {
+                ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+                    .unwrap()
+                    .build()
+                    .unwrap()
+                    .map(|b| b.unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    fn rows(json: &[&str]) -> Vec<crate::db::DataRow> {
+        json.iter()
+            .map(|j| json_to_data_row(j, Utc::now()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_rejects_unknown_parameter() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("parquet://{}?rotate=yes", dir.path().display());
+        assert!(ParquetDriver::connect(&url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_table_has_no_info() {
+        let (_dir, driver) = connect("").await;
+        let info = driver
+            .get_table_info(&MQTable::from_topic("missing"))
+            .await
+            .unwrap();
+        assert!(!info.exists());
+    }
+
+    #[tokio::test]
+    async fn test_insert_into_missing_table_fails() {
+        let (_dir, driver) = connect("").await;
+        let result = driver
+            .insert_many(&rows(&[r#"{"a": 1}"#]), &MQTable::from_topic("missing"))
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_through_manager() {
+        let (dir, driver) = connect("").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("sensors/temp");
+
+        manager
+            .insert_many(&table, &rows(&[r#"{"temp": 21}"#, r#"{"temp": 22}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        let batches = read_all(&dir.path().join("sensors_temp"));
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+        let temp = batches[0]
+            .column_by_name("temp")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(temp.values(), &[21, 22]);
+        let pkey = batches[0]
+            .column_by_name("pkey")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(pkey.values(), &[1, 2]);
+        assert_eq!(
+            batches[0].column_by_name("insert_ts").unwrap().null_count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rotates_by_size() {
+        let (dir, driver) = connect("?max_file_bytes=1").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("rotating");
+
+        for i in 0..3 {
+            manager
+                .insert_many(&table, &rows(&[&format!(r#"{{"n": {i}}}"#)]))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            finished_files(&dir.path().join("rotating")).unwrap().len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rotates_by_age() {
+        let (dir, driver) = connect("?max_file_age=1ms").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("aging");
+
+        manager
+            .insert_many(&table, &rows(&[r#"{"n": 1}"#]))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        manager
+            .insert_many(&table, &rows(&[r#"{"n": 2}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        assert_eq!(finished_files(&dir.path().join("aging")).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_new_column_is_null_filled_in_earlier_files() {
+        let (dir, driver) = connect("?max_file_bytes=1").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("evolving");
+
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 1}"#]))
+            .await
+            .unwrap();
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 2, "b": "late"}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        let batches = read_all(&dir.path().join("evolving"));
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].schema(), batches[1].schema());
+        let b = |i: usize| batches[i].column_by_name("b").unwrap().clone();
+        assert!(b(0).is_null(0));
+        let b_second = b(1);
+        let b_second = b_second.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(b_second.value(0), "late");
+        let a = batches[0].column_by_name("a").unwrap();
+        assert_eq!(
+            a.as_any().downcast_ref::<Int64Array>().unwrap().values(),
+            &[1]
+        );
+
+        // plain file metadata, readers need not decode the arrow schema
+        let versions: Vec<String> = finished_files(&dir.path().join("evolving"))
+            .unwrap()
+            .into_iter()
+            .map(|path| {
+                SerializedFileReader::new(File::open(path).unwrap())
+                    .unwrap()
+                    .metadata()
+                    .file_metadata()
+                    .key_value_metadata()
+                    .unwrap()
+                    .iter()
+                    .find(|kv| kv.key == "schema_version")
+                    .and_then(|kv| kv.value.clone())
+                    .unwrap()
+            })
+            .collect();
+        // created at 1, adding a and b bumped it and the first file was rewritten
+        assert_eq!(versions, ["3", "3"]);
+    }
+
+    #[tokio::test]
+    async fn test_bigint_column_widens_to_double() {
+        let (dir, driver) = connect("?max_file_bytes=1").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("widening");
+
+        manager
+            .insert_many(&table, &rows(&[r#"{"temp": 21}"#]))
+            .await
+            .unwrap();
+        manager
+            .insert_many(&table, &rows(&[r#"{"temp": 21.7}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        let batches = read_all(&dir.path().join("widening"));
+        let column = |i: usize| batches[i].column_by_name("temp").unwrap().clone();
+        let first = column(0);
+        let first = first.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(first.values(), &[21.0]);
+        let second = column(1);
+        let second = second.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(second.values(), &[21.7]);
+    }
+
+    #[tokio::test]
+    async fn test_rows_survive_a_crash() {
+        let (dir, driver) = connect("").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("crashing");
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 1}"#, r#"{"a": 2}"#]))
+            .await
+            .unwrap();
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 3}"#]))
+            .await
+            .unwrap();
+        // nothing is finished, like a kill
+        std::mem::forget(manager);
+        let table_dir = dir.path().join("crashing");
+        assert!(finished_files(&table_dir).unwrap().is_empty());
+
+        let url = format!("parquet://{}", dir.path().display());
+        let mut manager = Manager::new(ParquetDriver::connect(&url).await.unwrap());
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 4}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        let pkeys: Vec<i64> = read_all(&table_dir)
+            .iter()
+            .flat_map(|b| {
+                b.column_by_name("pkey")
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap()
+                    .values()
+                    .to_vec()
+            })
+            .collect();
+        assert_eq!(pkeys, vec![1, 2, 3, 4]);
+        let leftovers = fs::read_dir(&table_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| {
+                p.extension()
+                    .is_some_and(|e| e == "wal" || e == "inprogress")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_rewrite_is_done_on_load() {
+        let (dir, driver) = connect("?max_file_bytes=1").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("rewriting");
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 1}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        // the schema change was saved, the crash came during the rewrite
+        let table_dir = dir.path().join("rewriting");
+        let schema_path = table_dir.join(SCHEMA_FILE);
+        let mut schema: StoredSchema =
+            serde_json::from_slice(&fs::read(&schema_path).unwrap()).unwrap();
+        schema.version += 1;
+        schema.columns.push(SchemaColumn {
+            column_name: "b".to_string(),
+            data_type: "TEXT".to_string(),
+            original_name: None,
+        });
+        fs::write(&schema_path, serde_json::to_vec(&schema).unwrap()).unwrap();
+        let finished = finished_files(&table_dir).unwrap();
+        fs::write(finished[0].with_extension(REWRITE_EXTENSION), b"torn").unwrap();
+
+        let url = format!("parquet://{}", dir.path().display());
+        let mut manager = Manager::new(ParquetDriver::connect(&url).await.unwrap());
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 2, "b": "late"}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        let batches = read_all(&table_dir);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].schema(), batches[1].schema());
+        assert!(batches[0].column_by_name("b").unwrap().is_null(0));
+        assert!(!fs::read_dir(&table_dir).unwrap().any(|e| e
+            .unwrap()
+            .path()
+            .extension()
+            .is_some_and(|e| e == "rewrite")));
+    }
+
+    #[tokio::test]
+    async fn test_torn_log_keeps_earlier_batches() {
+        let (dir, driver) = connect("").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("torn");
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 1}"#]))
+            .await
+            .unwrap();
+        std::mem::forget(manager);
+
+        let table_dir = dir.path().join("torn");
+        let wal = fs::read_dir(&table_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|e| e == "wal"))
+            .unwrap();
+        let mut log = fs::OpenOptions::new().append(true).open(wal).unwrap();
+        log.write_all(&[0xff, 0xff, 0xff, 0xff, 0x40]).unwrap();
+        // an in progress file of a table that never got a row
+        fs::write(table_dir.join("part-9.parquet.inprogress"), b"PAR1").unwrap();
+
+        let url = format!("parquet://{}", dir.path().display());
+        drop(ParquetDriver::connect(&url).await.unwrap());
+
+        assert_eq!(finished_files(&table_dir).unwrap().len(), 1);
+        assert_eq!(
+            read_all(&table_dir)
+                .iter()
+                .map(|b| b.num_rows())
+                .sum::<usize>(),
+            1
+        );
+        assert!(!table_dir.join("part-9.parquet.inprogress").exists());
+    }
+
+    #[tokio::test]
+    async fn test_idle_file_is_finished_by_age() {
+        let (dir, driver) = connect("?max_file_age=20ms").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("idle");
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 1}"#]))
+            .await
+            .unwrap();
+
+        // still connected and nothing else written
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(finished_files(&dir.path().join("idle")).unwrap().len(), 1);
+        drop(manager);
+    }
+
+    #[tokio::test]
+    async fn test_schema_and_pkey_survive_reconnect() {
+        let (dir, driver) = connect("").await;
+        let table = MQTable::from_topic("persisted");
+        let mut manager = Manager::new(driver);
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 1}"#, r#"{"a": 2}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        let url = format!("parquet://{}", dir.path().display());
+        let driver = ParquetDriver::connect(&url).await.unwrap();
+        let info = driver.get_table_info(&table).await.unwrap();
+        assert!(info.has_column("a"));
+        assert!(info.has_column("raw"));
+
+        let mut manager = Manager::new(driver);
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 3}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        let pkeys: Vec<i64> = read_all(&dir.path().join("persisted"))
+            .iter()
+            .flat_map(|b| {
+                b.column_by_name("pkey")
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap()
+                    .values()
+                    .to_vec()
+            })
+            .collect();
+        assert_eq!(pkeys, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_type_mismatch_is_an_error() {
+        let (_dir, driver) = connect("").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("mismatch");
+
+        manager
+            .insert_many(&table, &rows(&[r#"{"a": 1}"#]))
+            .await
+            .unwrap();
+        let result = manager
+            .insert_many(&table, &rows(&[r#"{"a": true}"#]))
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exploded_arrays_go_to_child_tables() {
+        let (dir, driver) = connect("").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("batches");
+        let options = MappingOptions {
+            explode: vec!["readings".to_string()],
+            ..Default::default()
+        };
+
+        let rows = [
+            r#"{"id": 1}"#,
+            r#"{"id": 2, "readings": [10, 20]}"#,
+            r#"{"id": 3, "readings": [30]}"#,
+        ]
+        .map(|json| json_to_data_row_with(json, Utc::now(), &options).unwrap());
+        manager.insert_many(&table, &rows).await.unwrap();
+        drop(manager);
+
+        let batches = read_all(&dir.path().join("batches__readings"));
+        let column = |name| {
+            batches[0]
+                .column_by_name(name)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap()
+                .values()
+                .to_vec()
+        };
+        assert_eq!(column("parent_pkey"), [2, 2, 3]);
+        assert_eq!(column("element_index"), [0, 1, 0]);
+        assert_eq!(column("value"), [10, 20, 30]);
+    }
+
+    #[tokio::test]
+    async fn test_child_that_does_not_fit_writes_no_parent() {
+        let (dir, driver) = connect("").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("orders");
+        let options = MappingOptions {
+            explode: vec!["items".to_string()],
+            ..Default::default()
+        };
+        let row = |json| json_to_data_row_with(json, Utc::now(), &options).unwrap();
+
+        manager
+            .insert_many(&table, &[row(r#"{"id": 1, "items": [10]}"#)])
+            .await
+            .unwrap();
+        // a text element can't go into the BIGINT value column
+        let result = manager
+            .insert_many(&table, &[row(r#"{"id": 2, "items": ["x"]}"#)])
+            .await;
+        assert!(result.is_err());
+        manager
+            .insert_many(&table, &[row(r#"{"id": 3, "items": [30]}"#)])
+            .await
+            .unwrap();
+        drop(manager);
+
+        let int_column = |table: &str, name: &str| -> Vec<i64> {
+            read_all(&dir.path().join(table))
+                .iter()
+                .flat_map(|b| {
+                    b.column_by_name(name)
+                        .unwrap()
+                        .as_any()
+                        .downcast_ref::<Int64Array>()
+                        .unwrap()
+                        .values()
+                        .to_vec()
+                })
+                .collect()
+        };
+        assert_eq!(int_column("orders", "id"), [1, 3]);
+        assert_eq!(int_column("orders", "pkey"), [1, 2]);
+        assert_eq!(int_column("orders__items", "parent_pkey"), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_registry_survives_reconnect() {
+        let (dir, driver) = connect("").await;
+        let mut manager = Manager::new(driver);
+        manager.resolve_table("a/b").await.unwrap();
+        let second = manager.resolve_table("a_b").await.unwrap();
+        assert_eq!(second, MQTable::from_topic_hashed("a_b"));
+        drop(manager);
+
+        let url = format!("parquet://{}", dir.path().display());
+        let mut manager = Manager::new(ParquetDriver::connect(&url).await.unwrap());
+        assert_eq!(manager.resolve_table("a_b").await.unwrap(), second);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters_survive_reconnect() {
+        let (dir, driver) = connect("").await;
+        let mut manager = Manager::new(driver);
+        let error = to_data_row(b"\xff", Utc::now(), &MappingOptions::default()).unwrap_err();
+        let letters: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|topic| DeadLetter::new(topic.to_string(), b"\xff".to_vec(), Utc::now(), &error))
+            .collect();
+        manager.dead_letter(&letters).await.unwrap();
+        let stored = manager.dead_letters().await.unwrap();
+        manager.remove_dead_letter(stored[1].id).await.unwrap();
+        drop(manager);
+
+        let url = format!("parquet://{}", dir.path().display());
+        let mut manager = Manager::new(ParquetDriver::connect(&url).await.unwrap());
+        let left = manager.dead_letters().await.unwrap();
+        assert_eq!(left, [stored[0].clone(), stored[2].clone()]);
+        assert_eq!(left[0].received_ts, letters[0].received_ts);
+        assert_eq!(left[0].payload, b"\xff");
+    }
+
+    #[tokio::test]
+    async fn test_original_key_is_kept_in_schema() {
+        let (dir, driver) = connect("").await;
+        let mut manager = Manager::new(driver);
+        let table = MQTable::from_topic("keys");
+
+        manager
+            .insert_many(&table, &rows(&[r#"{"Temp C": 1, "plain": 2}"#]))
+            .await
+            .unwrap();
+        drop(manager);
+
+        let url = format!("parquet://{}", dir.path().display());
+        let info = ParquetDriver::connect(&url)
+            .await
+            .unwrap()
+            .get_table_info(&table)
+            .await
+            .unwrap();
+        assert_eq!(
+            info.columns["temp_c"].original_name.as_deref(),
+            Some("Temp C")
+        );
+        assert_eq!(info.columns["plain"].original_name, None);
+    }
+}
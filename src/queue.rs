@@ -0,0 +1,284 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+/// What [QueueSender::send] does when the queue is full
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// wait for the writer to make room, the broker is not read from meanwhile
+    #[default]
+    Block,
+    /// make room by dropping the message that has waited longest
+    DropOldest,
+    /// drop the message being sent
+    DropNewest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueClosed;
+
+impl std::fmt::Display for QueueClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the receiving end of the queue is gone")
+    }
+}
+
+impl std::error::Error for QueueClosed {}
+
+struct State<T> {
+    items: VecDeque<T>,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    item_ready: Notify,
+    space_ready: Notify,
+}
+
+/// Bounded single producer, single consumer queue. Unlike a tokio channel a full queue can
+/// also shed messages, see [OverflowPolicy].
+pub fn channel<T>(capacity: usize, policy: OverflowPolicy) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            sender_dropped: false,
+            receiver_dropped: false,
+        }),
+        capacity: capacity.max(1),
+        item_ready: Notify::new(),
+        space_ready: Notify::new(),
+    });
+    (
+        QueueSender {
+            shared: shared.clone(),
+            policy,
+        },
+        QueueReceiver { shared },
+    )
+}
+
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+    policy: OverflowPolicy,
+}
+
+impl<T> QueueSender<T> {
+    /// Queues `item`, returning the message that was dropped to make room if any
+    pub async fn send(&self, item: T) -> Result<Option<T>, QueueClosed> {
+        let mut item = Some(item);
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.receiver_dropped {
+                    return Err(QueueClosed);
+                }
+                let dropped = if state.items.len() < self.shared.capacity {
+                    None
+                } else {
+                    match self.policy {
+                        OverflowPolicy::Block => None,
+                        OverflowPolicy::DropNewest => return Ok(item),
+                        OverflowPolicy::DropOldest => state.items.pop_front(),
+                    }
+                };
+                if state.items.len() < self.shared.capacity {
+                    state.items.extend(item.take());
+                    drop(state);
+                    self.shared.item_ready.notify_one();
+                    return Ok(dropped);
+                }
+            }
+            // a permit is stored if the receiver made room in between, no wakeup is lost
+            self.shared.space_ready.notified().await;
+        }
+    }
+
+    /// Messages waiting for the writer
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().sender_dropped = true;
+        self.shared.item_ready.notify_one();
+    }
+}
+
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// Next message, `None` once the sender is gone and the queue is empty. Cancelling it
+    /// loses nothing.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    drop(state);
+                    self.shared.space_ready.notify_one();
+                    return Some(item);
+                }
+                if state.sender_dropped {
+                    return None;
+                }
+            }
+            self.shared.item_ready.notified().await;
+        }
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_dropped = true;
+        self.shared.space_ready.notify_one();
+    }
+}
+
+/// Messages shed by a full queue per topic, since startup
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DropCounts {
+    counts: BTreeMap<String, u64>,
+    total: u64,
+}
+
+impl DropCounts {
+    pub fn record(&mut self, topic: &str) {
+        *self.counts.entry(topic.to_string()).or_default() += 1;
+        self.total += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+impl std::fmt::Display for DropCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} dropped", self.total)?;
+        for (i, (topic, count)) in self.counts.iter().enumerate() {
+            let sep = if i == 0 { " (" } else { ", " };
+            write!(f, "{sep}{topic}: {count}")?;
+        }
+        if !self.counts.is_empty() {
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod channel {
+        use std::time::Duration;
+
+        use tokio::time::{sleep, timeout};
+
+        use crate::queue::{channel, OverflowPolicy, QueueClosed};
+
+        #[tokio::test(start_paused = true)]
+        async fn test_block_waits_for_room() {
+            let (tx, mut rx) = channel(2, OverflowPolicy::Block);
+            tx.send(1).await.unwrap();
+            tx.send(2).await.unwrap();
+            assert_eq!(tx.len(), 2);
+            assert!(timeout(Duration::from_millis(10), tx.send(3))
+                .await
+                .is_err());
+
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(50)).await;
+                assert_eq!(rx.recv().await, Some(1));
+                sleep(Duration::from_secs(1)).await;
+                rx
+            });
+            assert_eq!(tx.send(3).await, Ok(None));
+            assert_eq!(tx.len(), 2);
+        }
+
+        #[tokio::test]
+        async fn test_drop_oldest() {
+            let (tx, mut rx) = channel(2, OverflowPolicy::DropOldest);
+            for i in 1..=2 {
+                assert_eq!(tx.send(i).await, Ok(None));
+            }
+            assert_eq!(tx.send(3).await, Ok(Some(1)));
+            assert_eq!(tx.send(4).await, Ok(Some(2)));
+            drop(tx);
+            assert_eq!(rx.recv().await, Some(3));
+            assert_eq!(rx.recv().await, Some(4));
+            assert_eq!(rx.recv().await, None);
+        }
+
+        #[tokio::test]
+        async fn test_drop_newest() {
+            let (tx, mut rx) = channel(2, OverflowPolicy::DropNewest);
+            for i in 1..=2 {
+                assert_eq!(tx.send(i).await, Ok(None));
+            }
+            assert_eq!(tx.send(3).await, Ok(Some(3)));
+            assert_eq!(rx.recv().await, Some(1));
+            assert_eq!(tx.send(4).await, Ok(None));
+            drop(tx);
+            assert_eq!(rx.recv().await, Some(2));
+            assert_eq!(rx.recv().await, Some(4));
+            assert_eq!(rx.recv().await, None);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_recv_waits_for_send() {
+            let (tx, mut rx) = channel(2, OverflowPolicy::Block);
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(50)).await;
+                tx.send("a").await.unwrap();
+            });
+            assert_eq!(rx.recv().await, Some("a"));
+            assert_eq!(rx.recv().await, None);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_dropped_receiver_wakes_blocked_sender() {
+            let (tx, rx) = channel(1, OverflowPolicy::Block);
+            tx.send(1).await.unwrap();
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(50)).await;
+                drop(rx);
+            });
+            assert_eq!(tx.send(2).await, Err(QueueClosed));
+        }
+    }
+
+    mod drop_counts {
+        use crate::queue::DropCounts;
+
+        #[test]
+        fn test_counts_per_topic() {
+            let mut drops = DropCounts::default();
+            assert_eq!(drops.to_string(), "0 dropped");
+            for topic in ["b/c", "a", "b/c"] {
+                drops.record(topic);
+            }
+            assert_eq!(drops.total(), 3);
+            assert_eq!(drops.to_string(), "3 dropped (a: 1, b/c: 2)");
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+/// Broker address, `host` or `host:port` in the config
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Broker {
+    /// `default_port` applies when `address` has none, IPv6 hosts with a port go in brackets
+    pub fn parse(address: &str, default_port: u16) -> anyhow::Result<Self> {
+        let address = address.trim();
+        let (host, port) = match address.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => anyhow::bail!("Invalid broker address {}", address),
+                },
+                None => anyhow::bail!("Invalid broker address {}", address),
+            },
+            // more than one colon is a bare IPv6 address
+            None => match address.split_once(':') {
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                _ => (address, None),
+            },
+        };
+        if host.is_empty() {
+            anyhow::bail!("Invalid broker address {}", address);
+        }
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid port in broker address {}", address))?,
+            None => default_port,
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for Broker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// The primary broker followed by the fallbacks, tried in turn while connecting fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Brokers {
+    brokers: Vec<Broker>,
+    current: usize,
+}
+
+impl Brokers {
+    /// `fallbacks` is a comma separated list as in `MQTT_FALLBACK_HOSTS`
+    pub fn new(
+        primary: Broker,
+        fallbacks: Option<&str>,
+        default_port: u16,
+    ) -> anyhow::Result<Self> {
+        let mut brokers = vec![primary];
+        for address in fallbacks.unwrap_or_default().split(',') {
+            if !address.trim().is_empty() {
+                brokers.push(Broker::parse(address, default_port)?);
+            }
+        }
+        Ok(Self {
+            brokers,
+            current: 0,
+        })
+    }
+
+    pub fn current(&self) -> &Broker {
+        &self.brokers[self.current]
+    }
+
+    /// Moves on to the next broker, back to the primary after the last fallback
+    pub fn advance(&mut self) -> &Broker {
+        self.current = (self.current + 1) % self.brokers.len();
+        self.current()
+    }
+}
+
+/// Exponential backoff between reconnect attempts, randomized so a fleet of connectors
+/// doesn't reconnect in lockstep after a broker restart. Writer restarts use it too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            failures: 0,
+        }
+    }
+
+    /// Delay before the next attempt, somewhere between half and all of the current step
+    pub fn next_delay(&mut self) -> Duration {
+        self.next_delay_with(rand::random_range(0.5..=1.0))
+    }
+
+    fn next_delay_with(&mut self, jitter: f64) -> Duration {
+        // doubling stops mattering long before the shift overflows
+        let step = self
+            .min
+            .saturating_mul(1 << self.failures.min(20))
+            .min(self.max);
+        self.failures = self.failures.saturating_add(1);
+        step.mul_f64(jitter)
+    }
+
+    /// Longest delay [Self::next_delay] returns
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Called once connected, the next outage starts at the shortest delay again
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod broker {
+        use crate::reconnect::Broker;
+
+        fn parse(address: &str) -> (String, u16) {
+            let broker = Broker::parse(address, 1883).unwrap();
+            (broker.host, broker.port)
+        }
+
+        #[test]
+        fn test_host_and_port() {
+            assert_eq!(parse("broker-2"), ("broker-2".to_string(), 1883));
+            assert_eq!(parse(" broker-2:8883 "), ("broker-2".to_string(), 8883));
+        }
+
+        #[test]
+        fn test_ipv6() {
+            assert_eq!(parse("::1"), ("::1".to_string(), 1883));
+            assert_eq!(parse("[::1]:8883"), ("::1".to_string(), 8883));
+            assert_eq!(parse("[::1]"), ("::1".to_string(), 1883));
+        }
+
+        #[test]
+        fn test_invalid() {
+            for address in ["", "host:port", "host:70000", "[::1", "[::1]8883", ":1883"] {
+                assert!(Broker::parse(address, 1883).is_err(), "{address}");
+            }
+        }
+
+        #[test]
+        fn test_display_round_trips() {
+            for address in ["broker:1883", "[::1]:8883"] {
+                assert_eq!(Broker::parse(address, 1).unwrap().to_string(), address);
+            }
+        }
+    }
+
+    mod brokers {
+        use crate::reconnect::{Broker, Brokers};
+
+        #[test]
+        fn test_fails_over_and_wraps_around() {
+            let primary = Broker::parse("a", 1883).unwrap();
+            let mut brokers = Brokers::new(primary, Some("b:8883, c,"), 1883).unwrap();
+            assert_eq!(brokers.current().to_string(), "a:1883");
+            assert_eq!(brokers.advance().to_string(), "b:8883");
+            assert_eq!(brokers.advance().to_string(), "c:1883");
+            assert_eq!(brokers.advance().to_string(), "a:1883");
+        }
+
+        #[test]
+        fn test_without_fallbacks() {
+            let primary = Broker::parse("a", 1883).unwrap();
+            let mut brokers = Brokers::new(primary, None, 1883).unwrap();
+            assert_eq!(brokers.advance().to_string(), "a:1883");
+        }
+    }
+
+    mod backoff {
+        use std::time::Duration;
+
+        use crate::reconnect::Backoff;
+
+        #[test]
+        fn test_doubles_up_to_max() {
+            let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+            let delays: Vec<_> = (0..6).map(|_| backoff.next_delay_with(1.0)).collect();
+            assert_eq!(
+                delays,
+                [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+            );
+        }
+
+        #[test]
+        fn test_jitter_and_reset() {
+            let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+            backoff.next_delay_with(1.0);
+            assert_eq!(backoff.next_delay_with(0.5), Duration::from_millis(100));
+            backoff.reset();
+            assert_eq!(backoff.next_delay_with(1.0), Duration::from_millis(100));
+
+            for _ in 0..100 {
+                let delay = backoff.next_delay();
+                assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_secs(1));
+            }
+        }
+    }
+}
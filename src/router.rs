@@ -0,0 +1,309 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{
+    db::{Cell, DataRow},
+    utils::{sanitize_identifier, PreDefinedColumn},
+};
+
+/// One entry of the `ROUTING_CONFIG` file.
+///
+/// `pattern` is matched segment by segment, `{name}` captures a segment into the column
+/// `name`, `+` matches any one segment and a trailing `#` matches the rest of the topic.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+    pub pattern: String,
+    /// table every matching topic is written to, when unset each topic keeps its own table
+    pub table: Option<String>,
+}
+
+/// Routing rules read from the toml file in `ROUTING_CONFIG`, the first matching rule wins.
+///
+/// ```toml
+/// [[routes]]
+/// pattern = "sites/{site}/devices/{device_id}/telemetry"
+/// table = "telemetry"
+///
+/// # own table per topic as without rules, but with the site column
+/// [[routes]]
+/// pattern = "sites/{site}/#"
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    pub routes: Vec<RouteRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Capture(String),
+    Single,
+    Rest,
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    segments: Vec<Segment>,
+    table: Option<String>,
+}
+
+/// Where a topic is written and which of its segments become columns
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Route<'a> {
+    pub table: Option<&'a str>,
+    pub captures: Vec<(&'a str, String)>,
+}
+
+impl Route<'_> {
+    /// Adds the captured segments as text columns, a payload field of the same name is
+    /// replaced since the topic is where the rule says the value lives
+    pub fn merge_into(&self, row: &mut DataRow) {
+        for (name, value) in &self.captures {
+            row.cells
+                .insert(name.to_string(), Cell::String(value.clone()));
+            row.original_keys.remove(*name);
+        }
+    }
+}
+
+/// Matches topics against the [RoutingConfig] rules
+#[derive(Debug, Default)]
+pub struct Router {
+    rules: Vec<CompiledRule>,
+}
+
+impl Router {
+    pub fn new(config: RoutingConfig) -> anyhow::Result<Self> {
+        let rules = config
+            .routes
+            .into_iter()
+            .map(|rule| {
+                Ok(CompiledRule {
+                    segments: compile_pattern(&rule.pattern)?,
+                    table: rule.table,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::new(toml::from_str(&content)?)
+    }
+
+    /// From the file in `ROUTING_CONFIG`, without it every topic gets its own table
+    pub fn from_env() -> anyhow::Result<Self> {
+        match dotenvy::var("ROUTING_CONFIG") {
+            Ok(path) => Self::from_file(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Named tables of all rules, so they can be claimed before any message arrives
+    pub fn tables(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().filter_map(|r| r.table.as_deref())
+    }
+
+    /// Route of the first matching rule, topics no rule matches keep their own table
+    pub fn route(&self, topic: &str) -> Route<'_> {
+        self.rules
+            .iter()
+            .find_map(|rule| {
+                match_segments(&rule.segments, topic).map(|captures| Route {
+                    table: rule.table.as_deref(),
+                    captures,
+                })
+            })
+            .unwrap_or_default()
+    }
+}
+
+fn compile_pattern(pattern: &str) -> anyhow::Result<Vec<Segment>> {
+    let parts: Vec<&str> = pattern.split('/').collect();
+    let mut captures = vec![];
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| match *part {
+            "#" if i + 1 == parts.len() => Ok(Segment::Rest),
+            "#" => anyhow::bail!("# must be the last segment in route {}", pattern),
+            "+" => Ok(Segment::Single),
+            _ => match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(name) => {
+                    // captures become columns as they are, so they have to be valid already
+                    if name.is_empty() || sanitize_identifier(name) != name {
+                        anyhow::bail!("Invalid capture {{{}}} in route {}", name, pattern);
+                    }
+                    if PreDefinedColumn::ALL.iter().any(|c| c.to_string() == name) {
+                        anyhow::bail!("Capture {{{}}} in route {} is reserved", name, pattern);
+                    }
+                    if captures.contains(&name) {
+                        anyhow::bail!("Capture {{{}}} repeats in route {}", name, pattern);
+                    }
+                    captures.push(name);
+                    Ok(Segment::Capture(name.to_string()))
+                }
+                None if part.contains(['{', '}', '+', '#']) => {
+                    anyhow::bail!("Invalid segment {} in route {}", part, pattern)
+                }
+                None => Ok(Segment::Literal(part.to_string())),
+            },
+        })
+        .collect()
+}
+
+fn match_segments<'a>(segments: &'a [Segment], topic: &str) -> Option<Vec<(&'a str, String)>> {
+    let mut captures = vec![];
+    let mut levels = topic.split('/');
+    for segment in segments {
+        match segment {
+            // also matches the parent level, like the mqtt wildcard
+            Segment::Rest => return Some(captures),
+            Segment::Literal(literal) => {
+                if levels.next()? != literal {
+                    return None;
+                }
+            }
+            Segment::Single => {
+                levels.next()?;
+            }
+            Segment::Capture(name) => captures.push((name.as_str(), levels.next()?.to_string())),
+        }
+    }
+    levels.next().is_none().then_some(captures)
+}
+
+#[cfg(test)]
+mod tests {
+    mod route {
+        use crate::router::{Route, RouteRule, Router, RoutingConfig};
+
+        fn router(rules: &[(&str, Option<&str>)]) -> Router {
+            Router::new(RoutingConfig {
+                routes: rules
+                    .iter()
+                    .map(|(pattern, table)| RouteRule {
+                        pattern: pattern.to_string(),
+                        table: table.map(str::to_string),
+                    })
+                    .collect(),
+            })
+            .unwrap()
+        }
+
+        #[test]
+        fn test_captures_segments() {
+            let router = router(&[(
+                "sites/{site}/devices/{device_id}/telemetry",
+                Some("telemetry"),
+            )]);
+            assert_eq!(
+                router.route("sites/berlin/devices/d-7/telemetry"),
+                Route {
+                    table: Some("telemetry"),
+                    captures: vec![
+                        ("site", "berlin".to_string()),
+                        ("device_id", "d-7".to_string())
+                    ],
+                }
+            );
+        }
+
+        #[test]
+        fn test_no_match_keeps_own_table() {
+            let router = router(&[("sites/{site}/telemetry", Some("telemetry"))]);
+            assert_eq!(router.route("sites/berlin/status"), Route::default());
+            assert_eq!(
+                router.route("sites/berlin/telemetry/extra"),
+                Route::default()
+            );
+            assert_eq!(router.route("sites/berlin"), Route::default());
+        }
+
+        #[test]
+        fn test_first_match_wins() {
+            let router = router(&[
+                ("sites/+/special", Some("special")),
+                ("sites/#", Some("all")),
+            ]);
+            assert_eq!(router.route("sites/a/special").table, Some("special"));
+            assert_eq!(router.route("sites/a/other").table, Some("all"));
+            assert_eq!(router.route("sites").table, Some("all"));
+        }
+
+        #[test]
+        fn test_rule_without_table_only_captures() {
+            let router = router(&[("sites/{site}/#", None)]);
+            let route = router.route("sites/berlin/x/y");
+            assert_eq!(route.table, None);
+            assert_eq!(route.captures, [("site", "berlin".to_string())]);
+        }
+
+        #[test]
+        fn test_tables() {
+            let router = router(&[("a/#", Some("a")), ("b/#", None), ("c/#", Some("c"))]);
+            assert_eq!(router.tables().collect::<Vec<_>>(), ["a", "c"]);
+        }
+
+        #[test]
+        fn test_invalid_patterns() {
+            for pattern in [
+                "a/#/b",
+                "a/{}",
+                "a/{Site}",
+                "a/{site}/{site}",
+                "a/{pkey}",
+                "a/b+",
+                "a/{site",
+            ] {
+                let config = RoutingConfig {
+                    routes: vec![RouteRule {
+                        pattern: pattern.to_string(),
+                        table: None,
+                    }],
+                };
+                assert!(Router::new(config).is_err(), "{pattern}");
+            }
+        }
+    }
+
+    mod merge_into {
+        use chrono::Utc;
+
+        use crate::{
+            db::Cell,
+            mapper::json_to_data_row,
+            router::{Router, RoutingConfig},
+        };
+
+        #[test]
+        fn test_captures_become_columns() {
+            let config: RoutingConfig = toml::from_str(
+                r#"
+                [[routes]]
+                pattern = "sites/{site}/devices/{device_id}"
+                table = "devices"
+                "#,
+            )
+            .unwrap();
+            let router = Router::new(config).unwrap();
+
+            let mut row =
+                json_to_data_row(r#"{"temp": 1, "site": "payload"}"#, Utc::now()).unwrap();
+            router.route("sites/berlin/devices/7").merge_into(&mut row);
+            assert_eq!(
+                row.cells.get("site"),
+                Some(&Cell::String("berlin".to_string()))
+            );
+            assert_eq!(
+                row.cells.get("device_id"),
+                Some(&Cell::String("7".to_string()))
+            );
+            assert_eq!(row.cells.get("temp"), Some(&Cell::Number(1)));
+        }
+    }
+}
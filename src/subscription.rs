@@ -0,0 +1,345 @@
+use std::path::Path;
+
+use rumqttc::{
+    v5::mqttbytes::v5::{Filter, RetainForwardRule},
+    QoS, SubscribeFilter,
+};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    mqtt::{qos_to_v5, MqttProtocol},
+    utils::is_wildcard_filter,
+};
+
+/// When the broker sends the retained messages of a filter, MQTT 5 only
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetainHandling {
+    /// every time the filter is subscribed
+    #[default]
+    SendOnSubscribe,
+    /// only if the subscription didn't exist yet, e.g. not after resuming a session
+    SendOnNewSubscribe,
+    DontSend,
+}
+
+/// One topic filter the connector subscribes to
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Subscription {
+    pub filter: String,
+    #[serde(default = "default_qos", deserialize_with = "deserialize_qos")]
+    pub qos: QoS,
+    /// MQTT 5 only, messages published by this connection are not sent back to it
+    #[serde(default)]
+    pub no_local: bool,
+    /// MQTT 5 only, messages keep the retain flag they were published with
+    #[serde(default)]
+    pub retain_as_published: bool,
+    /// MQTT 5 only
+    #[serde(default)]
+    pub retain_handling: RetainHandling,
+}
+
+fn default_qos() -> QoS {
+    QoS::AtMostOnce
+}
+
+fn deserialize_qos<'de, D: Deserializer<'de>>(deserializer: D) -> Result<QoS, D::Error> {
+    let qos = u8::deserialize(deserializer)?;
+    rumqttc::qos(qos).map_err(|_| serde::de::Error::custom(format!("invalid qos {qos}")))
+}
+
+impl Subscription {
+    /// Whether the filter names a single topic, only those get a table up front
+    pub fn is_concrete(&self) -> bool {
+        !is_wildcard_filter(&self.filter)
+    }
+
+    /// Name of the first MQTT 5 option that differs from its default
+    fn v5_option(&self) -> Option<&'static str> {
+        if self.no_local {
+            Some("no_local")
+        } else if self.retain_as_published {
+            Some("retain_as_published")
+        } else if self.retain_handling != RetainHandling::default() {
+            Some("retain_handling")
+        } else {
+            None
+        }
+    }
+
+    pub fn to_subscribe_filter(&self) -> SubscribeFilter {
+        SubscribeFilter::new(self.filter.clone(), self.qos)
+    }
+
+    pub fn to_v5_filter(&self) -> Filter {
+        Filter {
+            nolocal: self.no_local,
+            preserve_retain: self.retain_as_published,
+            retain_forward_rule: match self.retain_handling {
+                RetainHandling::SendOnSubscribe => RetainForwardRule::OnEverySubscribe,
+                RetainHandling::SendOnNewSubscribe => RetainForwardRule::OnNewSubscribe,
+                RetainHandling::DontSend => RetainForwardRule::Never,
+            },
+            ..Filter::new(self.filter.clone(), qos_to_v5(self.qos))
+        }
+    }
+}
+
+/// Subscriptions read from the toml file in `SUBSCRIPTIONS_CONFIG`.
+///
+/// ```toml
+/// [[subscriptions]]
+/// filter = "sites/+/telemetry"
+/// qos = 1
+///
+/// [[subscriptions]]
+/// filter = "sites/+/status"
+/// no_local = true
+/// retain_as_published = true
+/// retain_handling = "send_on_new_subscribe"
+/// ```
+///
+/// `no_local`, `retain_as_published` and `retain_handling` are MQTT 5 subscription options,
+/// see [Self::check_protocol].
+///
+/// Without the file the comma separated filters in `TOPIC_NAME` are used, all with the qos
+/// in `TOPIC_QOS` (0 if unset).
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubscriptionConfig {
+    pub subscriptions: Vec<Subscription>,
+}
+
+impl SubscriptionConfig {
+    pub fn new(subscriptions: Vec<Subscription>) -> anyhow::Result<Self> {
+        if subscriptions.is_empty() {
+            anyhow::bail!("No topic filters to subscribe to");
+        }
+        for (i, sub) in subscriptions.iter().enumerate() {
+            if !rumqttc::valid_filter(&sub.filter) {
+                anyhow::bail!("Invalid topic filter {:?}", sub.filter);
+            }
+            if subscriptions[..i].iter().any(|s| s.filter == sub.filter) {
+                anyhow::bail!("Topic filter {} is subscribed twice", sub.filter);
+            }
+        }
+        Ok(Self { subscriptions })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let config: Self = toml::from_str(&content)?;
+        Self::new(config.subscriptions)
+    }
+
+    /// `filters` as in `TOPIC_NAME`, `qos` as in `TOPIC_QOS`
+    pub fn from_list(filters: &str, qos: Option<&str>) -> anyhow::Result<Self> {
+        let qos = match qos {
+            Some(qos) => rumqttc::qos(qos.trim().parse()?)
+                .map_err(|_| anyhow::anyhow!("Invalid qos {}", qos))?,
+            None => default_qos(),
+        };
+        let subscriptions = filters
+            .split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .map(|filter| Subscription {
+                filter: filter.to_string(),
+                qos,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: RetainHandling::default(),
+            })
+            .collect();
+        Self::new(subscriptions)
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        match dotenvy::var("SUBSCRIPTIONS_CONFIG") {
+            Ok(path) => Self::from_file(path),
+            Err(_) => Self::from_list(
+                &dotenvy::var("TOPIC_NAME")?,
+                dotenvy::var("TOPIC_QOS").ok().as_deref(),
+            ),
+        }
+    }
+
+    /// Fails if a subscription sets an option `protocol` doesn't have, MQTT 3.1.1 would
+    /// silently subscribe without it
+    pub fn check_protocol(&self, protocol: MqttProtocol) -> anyhow::Result<()> {
+        if protocol == MqttProtocol::V5 {
+            return Ok(());
+        }
+        for sub in self.subscriptions.iter() {
+            if let Some(option) = sub.v5_option() {
+                anyhow::bail!(
+                    "Subscription option {} of topic filter {} needs MQTT_PROTOCOL=v5",
+                    option,
+                    sub.filter
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_subscribe_filters(&self) -> Vec<SubscribeFilter> {
+        self.subscriptions
+            .iter()
+            .map(Subscription::to_subscribe_filter)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod subscription_config {
+        use rumqttc::{
+            v5::mqttbytes::{self, v5::RetainForwardRule},
+            QoS,
+        };
+
+        use crate::{
+            mqtt::MqttProtocol,
+            subscription::{RetainHandling, Subscription, SubscriptionConfig},
+        };
+
+        fn parse(content: &str) -> anyhow::Result<SubscriptionConfig> {
+            let config: SubscriptionConfig = toml::from_str(content)?;
+            SubscriptionConfig::new(config.subscriptions)
+        }
+
+        #[test]
+        fn test_per_filter_qos() {
+            let config = parse(
+                r#"
+                [[subscriptions]]
+                filter = "sites/+/telemetry"
+                qos = 2
+
+                [[subscriptions]]
+                filter = "sites/a/status"
+                "#,
+            )
+            .unwrap();
+            assert_eq!(
+                config.subscriptions,
+                [
+                    Subscription {
+                        filter: "sites/+/telemetry".to_string(),
+                        qos: QoS::ExactlyOnce,
+                        no_local: false,
+                        retain_as_published: false,
+                        retain_handling: RetainHandling::SendOnSubscribe,
+                    },
+                    Subscription {
+                        filter: "sites/a/status".to_string(),
+                        qos: QoS::AtMostOnce,
+                        no_local: false,
+                        retain_as_published: false,
+                        retain_handling: RetainHandling::SendOnSubscribe,
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn test_rejects_bad_config() {
+            for content in [
+                "",
+                "[[subscriptions]]\nfilter = \"a\"\nqos = 3",
+                "[[subscriptions]]\nfilter = \"a/#/b\"",
+                "[[subscriptions]]\nfilter = \"a\"\nretain = true",
+                "[[subscriptions]]\nfilter = \"a\"\nretain_handling = \"sometimes\"",
+                "[[subscriptions]]\nfilter = \"a\"\n[[subscriptions]]\nfilter = \"a\"",
+            ] {
+                assert!(parse(content).is_err(), "{content}");
+            }
+        }
+
+        #[test]
+        fn test_v5_options() {
+            let config = parse(
+                r#"
+                [[subscriptions]]
+                filter = "a"
+                qos = 1
+                no_local = true
+                retain_as_published = true
+                retain_handling = "dont_send"
+
+                [[subscriptions]]
+                filter = "b"
+                "#,
+            )
+            .unwrap();
+
+            let filter = config.subscriptions[0].to_v5_filter();
+            assert_eq!(filter.path, "a");
+            assert_eq!(filter.qos, mqttbytes::QoS::AtLeastOnce);
+            assert!(filter.nolocal);
+            assert!(filter.preserve_retain);
+            assert_eq!(filter.retain_forward_rule, RetainForwardRule::Never);
+
+            let filter = config.subscriptions[1].to_v5_filter();
+            assert!(!filter.nolocal && !filter.preserve_retain);
+            assert_eq!(
+                filter.retain_forward_rule,
+                RetainForwardRule::OnEverySubscribe
+            );
+        }
+
+        #[test]
+        fn test_v5_options_need_v5() {
+            for option in [
+                "no_local = true",
+                "retain_as_published = true",
+                "retain_handling = \"send_on_new_subscribe\"",
+            ] {
+                let config =
+                    parse(&format!("[[subscriptions]]\nfilter = \"a/b\"\n{option}")).unwrap();
+                assert!(config.check_protocol(MqttProtocol::V5).is_ok());
+                let err = config.check_protocol(MqttProtocol::V4).unwrap_err();
+                let name = option.split(' ').next().unwrap();
+                assert_eq!(
+                    err.to_string(),
+                    format!(
+                        "Subscription option {name} of topic filter a/b needs MQTT_PROTOCOL=v5"
+                    )
+                );
+            }
+
+            let config = parse("[[subscriptions]]\nfilter = \"a\"\nno_local = false").unwrap();
+            assert!(config.check_protocol(MqttProtocol::V4).is_ok());
+        }
+
+        #[test]
+        fn test_from_list() {
+            let config = SubscriptionConfig::from_list("a/#, b/c,", Some("1")).unwrap();
+            let filters: Vec<_> = config
+                .subscriptions
+                .iter()
+                .map(|s| (s.filter.as_str(), s.qos))
+                .collect();
+            assert_eq!(
+                filters,
+                [("a/#", QoS::AtLeastOnce), ("b/c", QoS::AtLeastOnce)]
+            );
+
+            assert!(SubscriptionConfig::from_list("a", Some("7")).is_err());
+            assert!(SubscriptionConfig::from_list(" , ", None).is_err());
+        }
+
+        #[test]
+        fn test_is_concrete() {
+            let config = SubscriptionConfig::from_list("a/b,a/+/c,a/#", None).unwrap();
+            let concrete: Vec<_> = config
+                .subscriptions
+                .iter()
+                .map(|s| s.is_concrete())
+                .collect();
+            assert_eq!(concrete, [true, false, false]);
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use std::future::Future;
+
+use tokio::{sync::watch, time::Instant};
+
+use crate::{health::WriterHealth, reconnect::Backoff};
+
+/// Whether retrying the operation behind `err` later may work, e.g. the database restarting
+/// or a deadlock. Anything else, like a rejected statement, fails again on retry.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<sqlx::Error>() {
+            return match e {
+                sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+                sqlx::Error::Database(e) => {
+                    if let Some(e) = e.try_downcast_ref::<sqlx::mysql::MySqlDatabaseError>() {
+                        if is_transient_mysql_number(e.number()) {
+                            return true;
+                        }
+                    }
+                    let Some(code) = e.code() else {
+                        return false;
+                    };
+                    if e.try_downcast_ref::<sqlx::sqlite::SqliteError>().is_some() {
+                        return code.parse().is_ok_and(is_transient_sqlite_code);
+                    }
+                    is_transient_sqlstate(&code)
+                }
+                _ => false,
+            };
+        }
+        cause.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::StorageFull
+            )
+        })
+    })
+}
+
+/// SQLSTATE connection exceptions, operator intervention, too many connections,
+/// serialization failures and deadlocks
+fn is_transient_sqlstate(code: &str) -> bool {
+    code.starts_with("08")
+        || code.starts_with("57P0")
+        || matches!(code, "53300" | "40001" | "40P01")
+}
+
+/// SQLite reports extended result codes, their low byte is the primary code. Busy and locked
+/// include e.g. `SQLITE_BUSY_SNAPSHOT` (517) and `SQLITE_LOCKED_SHAREDCACHE` (262).
+fn is_transient_sqlite_code(code: i32) -> bool {
+    matches!(code & 0xff, 5 | 6)
+}
+
+/// MySQL error numbers of a lock wait timeout and a deadlock, the SQLSTATE of the former is
+/// the catch-all `HY000`
+fn is_transient_mysql_number(number: u16) -> bool {
+    matches!(number, 1205 | 1213)
+}
+
+/// Runs `run` on its own task and restarts it with the state it hands back after transient
+/// errors, waiting longer after every failure in a row. Returns once a run finishes, with the
+/// error of a fatal failure or a panic. The outcome of every run is published to `health`.
+///
+/// A run that lasted longer than the longest delay counts as recovered and the next failure
+/// starts over at the shortest delay.
+pub async fn supervise<S, F, Fut>(
+    mut state: S,
+    mut backoff: Backoff,
+    health: watch::Sender<WriterHealth>,
+    run: F,
+) -> anyhow::Result<()>
+where
+    S: Send + 'static,
+    F: Fn(S) -> Fut,
+    Fut: Future<Output = (S, anyhow::Result<()>)> + Send + 'static,
+{
+    let mut restarts = 0;
+    loop {
+        health.send_replace(WriterHealth::Running { restarts });
+        let started = Instant::now();
+        let (returned, out) = match tokio::spawn(run(state)).await {
+            Ok(returned) => returned,
+            Err(e) => {
+                let error = format!("Writer panicked: {}", e);
+                health.send_replace(WriterHealth::Failed {
+                    error: error.clone(),
+                });
+                anyhow::bail!(error);
+            }
+        };
+        match out {
+            Ok(()) => {
+                health.send_replace(WriterHealth::Stopped);
+                return Ok(());
+            }
+            Err(e) if is_transient(&e) => {
+                if started.elapsed() > backoff.max() {
+                    backoff.reset();
+                }
+                let delay = backoff.next_delay();
+                restarts += 1;
+                println!("Restarting writer in {:?} after: {:#}", delay, e);
+                health.send_replace(WriterHealth::Restarting {
+                    restarts,
+                    error: format!("{:#}", e),
+                });
+                tokio::time::sleep(delay).await;
+                state = returned;
+            }
+            Err(e) => {
+                health.send_replace(WriterHealth::Failed {
+                    error: format!("{:#}", e),
+                });
+                return Err(e.context("Writer failed"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod is_transient {
+        use std::borrow::Cow;
+
+        use sqlx::{
+            error::{DatabaseError, ErrorKind},
+            Connection,
+        };
+
+        use crate::supervisor::{
+            is_transient, is_transient_mysql_number, is_transient_sqlite_code,
+        };
+
+        /// Database error of a backend without its own classification, like Postgres
+        #[derive(Debug)]
+        struct CodedError(&'static str);
+
+        impl std::fmt::Display for CodedError {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "error {}", self.0)
+            }
+        }
+
+        impl std::error::Error for CodedError {}
+
+        impl DatabaseError for CodedError {
+            fn message(&self) -> &str {
+                self.0
+            }
+
+            fn code(&self) -> Option<Cow<'_, str>> {
+                Some(self.0.into())
+            }
+
+            fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+                self
+            }
+
+            fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+                self
+            }
+
+            fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+                self
+            }
+
+            fn kind(&self) -> ErrorKind {
+                ErrorKind::Other
+            }
+        }
+
+        fn database(code: &'static str) -> anyhow::Error {
+            sqlx::Error::Database(Box::new(CodedError(code))).into()
+        }
+
+        fn io(kind: std::io::ErrorKind) -> std::io::Error {
+            std::io::Error::from(kind)
+        }
+
+        #[test]
+        fn test_classifies_errors() {
+            let cases: Vec<(&str, anyhow::Error, bool)> = vec![
+                ("pool timed out", sqlx::Error::PoolTimedOut.into(), true),
+                (
+                    "with context",
+                    anyhow::Error::from(sqlx::Error::PoolTimedOut).context("inserting into a_b"),
+                    true,
+                ),
+                (
+                    "connection reset",
+                    sqlx::Error::Io(io(std::io::ErrorKind::ConnectionReset)).into(),
+                    true,
+                ),
+                (
+                    "disk full",
+                    io(std::io::ErrorKind::StorageFull).into(),
+                    true,
+                ),
+                ("connection failure", database("08006"), true),
+                ("admin shutdown", database("57P01"), true),
+                ("too many connections", database("53300"), true),
+                ("serialization failure", database("40001"), true),
+                ("deadlock", database("40P01"), true),
+                ("unique violation", database("23505"), false),
+                ("undefined column", database("42703"), false),
+                ("row not found", sqlx::Error::RowNotFound.into(), false),
+                ("pool closed", sqlx::Error::PoolClosed.into(), false),
+                (
+                    "not a database error",
+                    anyhow::anyhow!("Routed table x is reserved"),
+                    false,
+                ),
+            ];
+            for (name, err, transient) in cases {
+                assert_eq!(is_transient(&err), transient, "{name}");
+            }
+        }
+
+        #[tokio::test]
+        async fn test_sqlite_busy_is_transient() {
+            let dir = tempfile::tempdir().unwrap();
+            let options = sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(dir.path().join("test.db"))
+                .create_if_missing(true)
+                .busy_timeout(std::time::Duration::ZERO);
+            let mut holder = sqlx::SqliteConnection::connect_with(&options)
+                .await
+                .unwrap();
+            let mut other = sqlx::SqliteConnection::connect_with(&options)
+                .await
+                .unwrap();
+            sqlx::query("CREATE TABLE t (x INTEGER)")
+                .execute(&mut holder)
+                .await
+                .unwrap();
+            sqlx::query("BEGIN IMMEDIATE")
+                .execute(&mut holder)
+                .await
+                .unwrap();
+
+            let busy = sqlx::query("INSERT INTO t VALUES (1)")
+                .execute(&mut other)
+                .await
+                .unwrap_err();
+            assert!(is_transient(&busy.into()));
+
+            let rejected = sqlx::query("INSERT INTO missing VALUES (1)")
+                .execute(&mut holder)
+                .await
+                .unwrap_err();
+            assert!(!is_transient(&rejected.into()));
+        }
+
+        #[test]
+        fn test_sqlite_codes() {
+            for (code, transient) in [
+                (5, true),     // SQLITE_BUSY
+                (6, true),     // SQLITE_LOCKED
+                (261, true),   // SQLITE_BUSY_RECOVERY
+                (517, true),   // SQLITE_BUSY_SNAPSHOT
+                (773, true),   // SQLITE_BUSY_TIMEOUT
+                (262, true),   // SQLITE_LOCKED_SHAREDCACHE
+                (1, false),    // SQLITE_ERROR
+                (19, false),   // SQLITE_CONSTRAINT
+                (2067, false), // SQLITE_CONSTRAINT_UNIQUE
+                (1555, false), // SQLITE_CONSTRAINT_PRIMARYKEY
+            ] {
+                assert_eq!(is_transient_sqlite_code(code), transient, "{code}");
+            }
+        }
+
+        #[test]
+        fn test_mysql_numbers() {
+            for (number, transient) in [
+                (1205, true),  // ER_LOCK_WAIT_TIMEOUT
+                (1213, true),  // ER_LOCK_DEADLOCK
+                (1062, false), // ER_DUP_ENTRY
+                (1054, false), // ER_BAD_FIELD_ERROR
+            ] {
+                assert_eq!(is_transient_mysql_number(number), transient, "{number}");
+            }
+        }
+    }
+
+    mod supervise {
+        use std::{
+            sync::{
+                atomic::{AtomicU32, Ordering},
+                Arc,
+            },
+            time::Duration,
+        };
+
+        use tokio::{sync::watch, time::Instant};
+
+        use crate::{health::WriterHealth, reconnect::Backoff, supervisor::supervise};
+
+        fn backoff() -> Backoff {
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(10))
+        }
+
+        /// Fails with `errors` in turn, then finishes. The state counts the runs.
+        async fn run_failing(
+            errors: Vec<fn() -> anyhow::Error>,
+        ) -> (anyhow::Result<()>, u32, watch::Receiver<WriterHealth>) {
+            let (health, health_rx) = watch::channel(WriterHealth::Starting);
+            let runs = Arc::new(AtomicU32::new(0));
+            let out = supervise(0u32, backoff(), health, {
+                let runs = runs.clone();
+                move |state: u32| {
+                    let run = runs.fetch_add(1, Ordering::SeqCst) as usize;
+                    let error = errors.get(run).map(|e| e());
+                    async move {
+                        match error {
+                            Some(e) => (state + 1, Err(e)),
+                            None => (state + 1, Ok(())),
+                        }
+                    }
+                }
+            })
+            .await;
+            (out, runs.load(Ordering::SeqCst), health_rx)
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_restarts_after_transient_errors() {
+            let start = Instant::now();
+            let (out, runs, health) =
+                run_failing(vec![|| sqlx::Error::PoolTimedOut.into(), || {
+                    sqlx::Error::PoolTimedOut.into()
+                }])
+                .await;
+            assert!(out.is_ok());
+            assert_eq!(runs, 3);
+            assert_eq!(*health.borrow(), WriterHealth::Stopped);
+            // two jittered delays of at most 1s and 2s
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(1500) && elapsed <= Duration::from_secs(3));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_stops_on_fatal_error() {
+            let (out, runs, health) =
+                run_failing(vec![|| sqlx::Error::PoolTimedOut.into(), || {
+                    anyhow::anyhow!("column type mismatch")
+                }])
+                .await;
+            assert_eq!(
+                format!("{:#}", out.unwrap_err()),
+                "Writer failed: column type mismatch"
+            );
+            assert_eq!(runs, 2);
+            assert_eq!(
+                *health.borrow(),
+                WriterHealth::Failed {
+                    error: "column type mismatch".to_string()
+                }
+            );
+        }
+
+        #[tokio::test]
+        async fn test_panic_is_fatal() {
+            let (health, health_rx) = watch::channel(WriterHealth::Starting);
+            let out = supervise((), backoff(), health, |_| async {
+                panic!("boom");
+                #[allow(unreachable_code)]
+                ((), Ok(()))
+            })
+            .await;
+            assert!(out.unwrap_err().to_string().starts_with("Writer panicked"));
+            assert!(matches!(*health_rx.borrow(), WriterHealth::Failed { .. }));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_state_is_handed_to_the_restart() {
+            let (health, _health_rx) = watch::channel(WriterHealth::Starting);
+            let seen = Arc::new(AtomicU32::new(0));
+            supervise(0u32, backoff(), health, {
+                let seen = seen.clone();
+                move |state: u32| {
+                    seen.store(state, Ordering::SeqCst);
+                    async move {
+                        if state < 2 {
+                            (state + 1, Err(sqlx::Error::PoolTimedOut.into()))
+                        } else {
+                            (state, Ok(()))
+                        }
+                    }
+                }
+            })
+            .await
+            .unwrap();
+            assert_eq!(seen.load(Ordering::SeqCst), 2);
+        }
+    }
+}
@@ -11,22 +11,137 @@ pub fn get_wildcard_string(column_len: usize, items_len: usize) -> String {
     format!("({})", placeholders)
 }
 
+/// Number of rows of `column_len` placeholders that fit in one statement, never less than one
+pub fn get_rows_per_statement(column_len: usize, max_params: usize) -> usize {
+    (max_params / column_len.max(1)).max(1)
+}
+
+/// Longest identifier Postgres keeps, anything past it is silently cut off
+pub const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// Lowercase identifier made of `[a-z0-9_]` only, every other character becomes `_`
+pub fn sanitize_identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if identifier.is_empty() || identifier.starts_with(|c: char| c.is_ascii_digit()) {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
+
+/// FNV-1a, stable across builds unlike the std hasher
+pub fn stable_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// `identifier` cut short enough to end with a hash of `source`, so it stays distinct from
+/// identifiers of other sources sharing the same prefix
+pub fn with_hash_suffix(identifier: &str, source: &str) -> String {
+    let suffix = format!("_{:08x}", stable_hash(source.as_bytes()) as u32);
+    let keep = (MAX_IDENTIFIER_LEN - suffix.len()).min(identifier.len());
+    // sanitized identifiers are ascii, any index is a char boundary
+    format!("{}{}", &identifier[..keep], suffix)
+}
+
+/// Sanitized identifier for `source`, with a hash suffix if it would be too long
+pub fn to_identifier(source: &str) -> String {
+    let identifier = sanitize_identifier(source);
+    if identifier.len() > MAX_IDENTIFIER_LEN {
+        with_hash_suffix(&identifier, source)
+    } else {
+        identifier
+    }
+}
+
+/// `camelCase` and `PascalCase` to `snake_case`, acronyms stay together: `HTTPCode` is `http_code`
+pub fn to_snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev.is_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_uppercase() && next_is_lower)
+            {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+/// Whether an MQTT topic filter contains `+` or `#` and so can match more than one topic
+pub fn is_wildcard_filter(filter: &str) -> bool {
+    filter.split('/').any(|level| level == "+" || level == "#")
+}
+
+/// Whether `topic` matches the MQTT topic `filter`.
+///
+/// `+` matches exactly one level and a trailing `#` the rest, including the parent level
+/// (`a/#` matches `a`). Topics starting with `$` are not matched by a leading wildcard.
+pub fn topic_matches_filter(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum PreDefinedColumn {
     PKey,
     Raw,
     InsertTs,
     ReceivedTs,
+    /// `pkey` of the parent row in a child table
+    ParentPkey,
+    /// position of the element in the exploded array
+    ElementIndex,
 }
 
-impl ToString for PreDefinedColumn {
-    fn to_string(&self) -> String {
-        match self {
-            PreDefinedColumn::PKey => "pkey".to_string(),
-            PreDefinedColumn::Raw => "raw".to_string(),
-            PreDefinedColumn::InsertTs => "insert_ts".to_string(),
-            PreDefinedColumn::ReceivedTs => "received_ts".to_string(),
-        }
+impl PreDefinedColumn {
+    pub const ALL: [PreDefinedColumn; 6] = [
+        PreDefinedColumn::PKey,
+        PreDefinedColumn::Raw,
+        PreDefinedColumn::InsertTs,
+        PreDefinedColumn::ReceivedTs,
+        PreDefinedColumn::ParentPkey,
+        PreDefinedColumn::ElementIndex,
+    ];
+}
+
+impl std::fmt::Display for PreDefinedColumn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PreDefinedColumn::PKey => "pkey",
+            PreDefinedColumn::Raw => "raw",
+            PreDefinedColumn::InsertTs => "insert_ts",
+            PreDefinedColumn::ReceivedTs => "received_ts",
+            PreDefinedColumn::ParentPkey => "parent_pkey",
+            PreDefinedColumn::ElementIndex => "element_index",
+        };
+        f.write_str(name)
     }
 }
 
@@ -39,6 +154,8 @@ impl std::str::FromStr for PreDefinedColumn {
             "raw" => Ok(PreDefinedColumn::Raw),
             "insert_ts" => Ok(PreDefinedColumn::InsertTs),
             "received_ts" => Ok(PreDefinedColumn::ReceivedTs),
+            "parent_pkey" => Ok(PreDefinedColumn::ParentPkey),
+            "element_index" => Ok(PreDefinedColumn::ElementIndex),
             _ => anyhow::bail!("Unknown PreDefinedColumn: {}", s),
         }
     }
@@ -46,6 +163,116 @@ impl std::str::FromStr for PreDefinedColumn {
 
 #[cfg(test)]
 mod tests {
+    mod to_identifier {
+        use crate::utils::{
+            sanitize_identifier, stable_hash, to_identifier, with_hash_suffix, MAX_IDENTIFIER_LEN,
+        };
+
+        #[test]
+        fn test_plain_topic() {
+            assert_eq!(to_identifier("sensors/temp-1.raw"), "sensors_temp_1_raw");
+        }
+
+        #[test]
+        fn test_lowercases() {
+            assert_eq!(to_identifier("Sensors/Temp"), "sensors_temp");
+        }
+
+        #[test]
+        fn test_replaces_wildcards_spaces_and_unicode() {
+            assert_eq!(sanitize_identifier("a b/+/#"), "a_b____");
+            assert_eq!(sanitize_identifier("température"), "temp_rature");
+        }
+
+        #[test]
+        fn test_leading_digit_and_empty() {
+            assert_eq!(sanitize_identifier("1/a"), "_1_a");
+            assert_eq!(sanitize_identifier(""), "_");
+        }
+
+        #[test]
+        fn test_long_names_get_hash_suffix() {
+            let long = format!("site/{}", "x".repeat(100));
+            let other = format!("site/{}/", "x".repeat(100));
+            let identifier = to_identifier(&long);
+            assert_eq!(identifier.len(), MAX_IDENTIFIER_LEN);
+            assert!(identifier.starts_with("site_xxx"));
+            assert_ne!(identifier, to_identifier(&other));
+            // deterministic
+            assert_eq!(identifier, to_identifier(&long));
+        }
+
+        #[test]
+        fn test_hash_suffix_on_short_name() {
+            let identifier = with_hash_suffix("a_b", "a_b");
+            assert_eq!(
+                identifier,
+                format!("a_b_{:08x}", stable_hash(b"a_b") as u32)
+            );
+        }
+
+        #[test]
+        fn test_snake_case() {
+            use crate::utils::to_snake_case;
+
+            assert_eq!(to_snake_case("tempCelsius"), "temp_celsius");
+            assert_eq!(to_snake_case("DeviceId"), "device_id");
+            assert_eq!(to_snake_case("HTTPCode"), "http_code");
+            assert_eq!(to_snake_case("sensor2Value"), "sensor2_value");
+            assert_eq!(to_snake_case("already_snake"), "already_snake");
+        }
+
+        #[test]
+        fn test_stable_hash_is_fnv1a() {
+            assert_eq!(stable_hash(b""), 0xcbf29ce484222325);
+            assert_eq!(stable_hash(b"a"), 0xaf63dc4c8601ec8c);
+        }
+    }
+
+    mod topic_matches_filter {
+        use crate::utils::{is_wildcard_filter, topic_matches_filter};
+
+        #[test]
+        fn test_exact_topic() {
+            assert!(topic_matches_filter("sensors/temp", "sensors/temp"));
+            assert!(!topic_matches_filter("sensors/temp", "sensors/temp/1"));
+            assert!(!topic_matches_filter("sensors/temp", "sensors"));
+        }
+
+        #[test]
+        fn test_single_level() {
+            assert!(topic_matches_filter("sensors/+/temp", "sensors/a/temp"));
+            assert!(topic_matches_filter("sensors/+", "sensors/"));
+            assert!(!topic_matches_filter("sensors/+", "sensors"));
+            assert!(!topic_matches_filter("sensors/+", "sensors/a/b"));
+            assert!(topic_matches_filter("+/+", "/a"));
+        }
+
+        #[test]
+        fn test_multi_level() {
+            assert!(topic_matches_filter("sensors/#", "sensors/a/b/c"));
+            assert!(topic_matches_filter("sensors/#", "sensors"));
+            assert!(!topic_matches_filter("sensors/#", "sensorsx/a"));
+            assert!(topic_matches_filter("#", "a/b"));
+            assert!(topic_matches_filter("+/a/#", "x/a"));
+        }
+
+        #[test]
+        fn test_dollar_topics() {
+            assert!(!topic_matches_filter("#", "$SYS/uptime"));
+            assert!(!topic_matches_filter("+/uptime", "$SYS/uptime"));
+            assert!(topic_matches_filter("$SYS/#", "$SYS/uptime"));
+        }
+
+        #[test]
+        fn test_is_wildcard_filter() {
+            assert!(is_wildcard_filter("sensors/#"));
+            assert!(is_wildcard_filter("+"));
+            assert!(is_wildcard_filter("a/+/b"));
+            assert!(!is_wildcard_filter("sensors/temp"));
+        }
+    }
+
     mod get_wildcard_string {
         use crate::utils::get_wildcard_string;
 
@@ -88,6 +315,38 @@ mod tests {
         }
     }
 
+    mod get_rows_per_statement {
+        use crate::utils::get_rows_per_statement;
+
+        #[test]
+        fn test_exact_fit() {
+            assert_eq!(get_rows_per_statement(5, 65535), 13107);
+        }
+
+        #[test]
+        fn test_rounds_down() {
+            assert_eq!(get_rows_per_statement(4, 65535), 16383);
+        }
+
+        #[test]
+        fn test_wider_than_limit() {
+            assert_eq!(get_rows_per_statement(10, 5), 1);
+        }
+
+        #[test]
+        fn test_zero_columns() {
+            assert_eq!(get_rows_per_statement(0, 100), 100);
+        }
+
+        #[test]
+        fn test_chunks_stay_under_limit() {
+            let columns = 37;
+            let rows = get_rows_per_statement(columns, 65535);
+            assert!(rows * columns <= 65535);
+            assert!((rows + 1) * columns > 65535);
+        }
+    }
+
     mod pre_defined_column_to_string {
         use crate::utils::PreDefinedColumn;
 
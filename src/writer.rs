@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    batcher::Batcher,
+    db::{AnyDriver, DataRow, MQTable},
+    dead_letter::{to_data_row, DeadLetter, MessageError},
+    manager::Manager,
+    mapper::MapperConfig,
+    mqtt::{MessagePayload, PendingAck},
+    router::Router,
+    supervisor::is_transient,
+};
+
+/// The message a row came from, to ack it once the row is stored or to dead-letter it
+struct Source {
+    topic: String,
+    payload: Bytes,
+    timestamp: DateTime<Utc>,
+    ack: Option<PendingAck>,
+}
+
+/// Takes batches off the queue and stores them, see [crate::supervisor::supervise] for what
+/// happens when that fails.
+///
+/// A batch is kept until it is stored so it can be retried after a restart, tables whose rows
+/// were already committed are not written again.
+/// A table whose rows the database rejects is written row by row, the rows that still fail
+/// are dead-lettered.
+pub struct Writer {
+    batcher: Batcher<MessagePayload>,
+    manager: Manager<AnyDriver>,
+    router: Router,
+    mapper_config: MapperConfig,
+    /// acks of stored messages, the poll loop sends them in the order the messages arrived
+    ack_tx: UnboundedSender<PendingAck>,
+    /// a batch that is not mapped to rows yet
+    messages: Vec<MessagePayload>,
+    /// rows of each table and the messages they came from
+    tables: HashMap<MQTable, (Vec<DataRow>, Vec<Source>)>,
+    dead_letters: Vec<DeadLetter>,
+    dead_acks: Vec<PendingAck>,
+}
+
+impl Writer {
+    pub fn new(
+        batcher: Batcher<MessagePayload>,
+        manager: Manager<AnyDriver>,
+        router: Router,
+        mapper_config: MapperConfig,
+        ack_tx: UnboundedSender<PendingAck>,
+    ) -> Self {
+        Self {
+            batcher,
+            manager,
+            router,
+            mapper_config,
+            ack_tx,
+            messages: vec![],
+            tables: HashMap::new(),
+            dead_letters: vec![],
+            dead_acks: vec![],
+        }
+    }
+
+    /// Runs until the queue is closed and drained or a write fails, the writer is handed back
+    /// either way
+    pub async fn run(mut self) -> (Self, anyhow::Result<()>) {
+        let out = self.write_all().await;
+        if let Err(e) = &out {
+            println!("Writer failed: {:?}", e);
+            // a failed statement may have left the cache ahead of the database
+            self.manager.clear_cache();
+        }
+        (self, out)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.messages.is_empty() && self.tables.is_empty() && self.dead_letters.is_empty()
+    }
+
+    async fn write_all(&mut self) -> anyhow::Result<()> {
+        loop {
+            if self.is_idle() {
+                match self.batcher.next_batch().await {
+                    Some(batch) => self.messages = batch,
+                    // closed and drained
+                    None => return Ok(()),
+                }
+            }
+            self.map_messages().await?;
+            self.write_tables().await?;
+            self.write_dead_letters().await?;
+            println!("Inserted into DB");
+        }
+    }
+
+    /// All or nothing, the messages stay if a table can't be resolved
+    async fn map_messages(&mut self) -> anyhow::Result<()> {
+        if self.messages.is_empty() {
+            return Ok(());
+        }
+        let mut tables: HashMap<MQTable, (Vec<DataRow>, Vec<Source>)> = HashMap::new();
+        let mut dead_letters = vec![];
+        let mut dead_acks = vec![];
+
+        for msg in self.messages.iter() {
+            let ack = msg.to_ack();
+            let options = self.mapper_config.options_for(&msg.topic);
+            let mut obj = match to_data_row(&msg.payload, msg.timestamp, options) {
+                Ok(obj) => obj,
+                Err(e) => {
+                    println!("Dead-lettering message on topic {}: {}", msg.topic, e);
+                    dead_letters.push(DeadLetter::new(
+                        msg.topic.clone(),
+                        msg.payload.to_vec(),
+                        msg.timestamp,
+                        &e,
+                    ));
+                    dead_acks.extend(ack);
+                    continue;
+                }
+            };
+            let route = self.router.route(&msg.topic);
+            let table = match route.table {
+                Some(name) => self.manager.resolve_routed_table(name).await?,
+                None => self.manager.resolve_table(&msg.topic).await?,
+            };
+            println!(
+                "Received on topic {} - {} at {}: {:?}",
+                msg.topic, table.name, msg.timestamp, msg.payload
+            );
+            if options.mqtt_properties {
+                msg.properties.merge_into(&mut obj);
+            }
+            route.merge_into(&mut obj);
+
+            let (rows, sources) = tables.entry(table).or_default();
+            rows.push(obj);
+            sources.push(Source {
+                topic: msg.topic.clone(),
+                payload: msg.payload.clone(),
+                timestamp: msg.timestamp,
+                ack,
+            });
+        }
+
+        self.messages.clear();
+        self.tables = tables;
+        self.dead_letters = dead_letters;
+        self.dead_acks = dead_acks;
+        Ok(())
+    }
+
+    async fn write_tables(&mut self) -> anyhow::Result<()> {
+        while let Some(table) = self.tables.keys().next().cloned() {
+            let (rows, sources) = &self.tables[&table];
+            match self.manager.insert_many(&table, rows).await {
+                Ok(()) => {
+                    // unacked messages are redelivered, so nothing is acked before its commit
+                    for ack in sources.iter().filter_map(|s| s.ack) {
+                        self.ack_tx.send(ack)?;
+                    }
+                    self.tables.remove(&table);
+                }
+                Err(e) if is_transient(&e) => return Err(e),
+                Err(e) => {
+                    println!(
+                        "Writing {} failed, retrying row by row: {:#}",
+                        table.name, e
+                    );
+                    self.manager.clear_cache();
+                    self.write_rows_one_by_one(&table).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the rows of a rejected batch that are at fault and dead-letters them, the others
+    /// are written and acked. Each row leaves the batch once it is handled, so a transient
+    /// error only retries the rest.
+    async fn write_rows_one_by_one(&mut self, table: &MQTable) -> anyhow::Result<()> {
+        let (rows, sources) = self.tables.get_mut(table).unwrap();
+        while let (Some(row), Some(source)) = (rows.first(), sources.first()) {
+            match self
+                .manager
+                .insert_many(table, std::slice::from_ref(row))
+                .await
+            {
+                Ok(()) => {
+                    if let Some(ack) = source.ack {
+                        self.ack_tx.send(ack)?;
+                    }
+                }
+                Err(e) if is_transient(&e) => return Err(e),
+                Err(e) => {
+                    println!("Dead-lettering rejected row of {}: {:#}", table.name, e);
+                    self.manager.clear_cache();
+                    self.dead_letters.push(DeadLetter::new(
+                        source.topic.clone(),
+                        source.payload.to_vec(),
+                        source.timestamp,
+                        &MessageError::Rejected(e),
+                    ));
+                    self.dead_acks.extend(source.ack);
+                }
+            }
+            rows.remove(0);
+            sources.remove(0);
+        }
+        self.tables.remove(table);
+        Ok(())
+    }
+
+    async fn write_dead_letters(&mut self) -> anyhow::Result<()> {
+        self.manager.dead_letter(&self.dead_letters).await?;
+        self.dead_letters.clear();
+        for ack in self.dead_acks.drain(..) {
+            self.ack_tx.send(ack)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod run {
+        use std::time::Duration;
+
+        use bytes::Bytes;
+        use chrono::Utc;
+        use rumqttc::QoS;
+        use tokio::sync::mpsc;
+
+        use crate::{
+            batcher::{BatchLimits, Batcher},
+            db::{AnyDriver, DBDriver, SqliteDriver},
+            manager::Manager,
+            mapper::MapperConfig,
+            mqtt::{MessagePayload, MessageProperties, PendingAck},
+            queue::{channel, OverflowPolicy},
+            router::Router,
+            writer::Writer,
+        };
+
+        fn message(topic: &str, payload: &'static [u8], pkid: u16) -> MessagePayload {
+            MessagePayload {
+                topic: topic.to_string(),
+                payload: Bytes::from_static(payload),
+                timestamp: Utc::now(),
+                pkid,
+                qos: QoS::AtLeastOnce,
+                generation: 0,
+                seq: pkid.into(),
+                properties: MessageProperties::default(),
+            }
+        }
+
+        #[tokio::test]
+        async fn test_stores_dead_letters_and_acks_everything() {
+            let dir = tempfile::tempdir().unwrap();
+            let url = format!("sqlite://{}", dir.path().join("test.db").display());
+            let driver = AnyDriver::Sqlite(SqliteDriver::connect(&url).await.unwrap());
+
+            let (tx, rx) = channel(10, OverflowPolicy::Block);
+            let limits = BatchLimits {
+                max_count: 10,
+                max_bytes: 1024,
+                flush_interval: Duration::ZERO,
+            };
+            let (ack_tx, mut ack_rx) = mpsc::unbounded_channel();
+            let writer = Writer::new(
+                Batcher::new(rx, limits, MessagePayload::size),
+                Manager::new(driver),
+                Router::default(),
+                MapperConfig::default(),
+                ack_tx,
+            );
+            for msg in [
+                message("a", br#"{"x": 1}"#, 1),
+                message("a", b"\xff", 2),
+                message("b", br#"{"y": 2}"#, 3),
+            ] {
+                tx.send(msg).await.unwrap();
+            }
+            drop(tx);
+
+            let (mut writer, out) = writer.run().await;
+            out.unwrap();
+            assert!(writer.is_idle());
+
+            let mut acked = vec![];
+            while let Ok(PendingAck { pkid, .. }) = ack_rx.try_recv() {
+                acked.push(pkid);
+            }
+            acked.sort();
+            assert_eq!(acked, [1, 2, 3]);
+
+            let letters = writer.manager.dead_letters().await.unwrap();
+            assert_eq!(letters.len(), 1);
+            assert_eq!(
+                (letters[0].topic.as_str(), letters[0].payload.as_slice()),
+                ("a", &b"\xff"[..])
+            );
+        }
+
+        #[tokio::test]
+        async fn test_rejected_rows_are_dead_lettered() {
+            let dir = tempfile::tempdir().unwrap();
+            let url = format!("sqlite://{}", dir.path().join("test.db").display());
+            let driver = AnyDriver::Sqlite(SqliteDriver::connect(&url).await.unwrap());
+            let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+            for statement in [
+                "CREATE TABLE a (pkey INTEGER PRIMARY KEY, raw TEXT, insert_ts DATETIME, \
+                 received_ts DATETIME, x INTEGER)",
+                "CREATE TRIGGER reject BEFORE INSERT ON a WHEN NEW.x = 2 \
+                 BEGIN SELECT RAISE(ABORT, 'x must not be 2'); END",
+            ] {
+                sqlx::query(statement).execute(&pool).await.unwrap();
+            }
+
+            let (tx, rx) = channel(10, OverflowPolicy::Block);
+            let limits = BatchLimits {
+                max_count: 10,
+                max_bytes: 1024,
+                flush_interval: Duration::ZERO,
+            };
+            let (ack_tx, mut ack_rx) = mpsc::unbounded_channel();
+            let writer = Writer::new(
+                Batcher::new(rx, limits, MessagePayload::size),
+                Manager::new(driver),
+                Router::default(),
+                MapperConfig::default(),
+                ack_tx,
+            );
+            for msg in [
+                message("a", br#"{"x": 1}"#, 1),
+                message("a", br#"{"x": 2}"#, 2),
+                message("a", br#"{"x": 3}"#, 3),
+            ] {
+                tx.send(msg).await.unwrap();
+            }
+            drop(tx);
+
+            let (mut writer, out) = writer.run().await;
+            out.unwrap();
+
+            let mut acked = vec![];
+            while let Ok(PendingAck { pkid, .. }) = ack_rx.try_recv() {
+                acked.push(pkid);
+            }
+            acked.sort();
+            assert_eq!(acked, [1, 2, 3]);
+
+            let stored: Vec<i64> = sqlx::query_scalar("SELECT x FROM a ORDER BY pkey")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            assert_eq!(stored, [1, 3]);
+
+            let letters = writer.manager.dead_letters().await.unwrap();
+            assert_eq!(letters.len(), 1);
+            assert_eq!(letters[0].payload, br#"{"x": 2}"#);
+            assert_eq!(letters[0].error_kind, "rejected");
+            assert!(letters[0].error_message.contains("x must not be 2"));
+        }
+    }
+}